@@ -1,14 +1,18 @@
+use super::expand;
+use super::integer::{self, Operation};
 use super::{
-    builtin_instr, compile_finalized_expr, CustomFunc, EvaluationError, EvaluationResult,
-    FinalizationContext, Instr, LispExpr, LispFunc, LispValue, StackOffset, State, TopExpr,
+    builtin_instr, compile_finalized_expr, ArgType, Comparison, CustomFunc, EvaluationError,
+    EvaluationResult, FinalizationContext, FinalizedExpr, Instr, LispExpr, LispFunc, LispValue,
+    ListPath, PartTest, StackOffset, State, TopExpr,
 };
+use std::convert::TryFrom;
 use std::default::Default;
 use std::iter;
-use std::mem::{replace, transmute};
+use std::mem::{replace, take, transmute};
 use std::ops::Index;
 
 fn unitary_list<F: Fn(&mut Vec<LispValue>) -> EvaluationResult<LispValue>>(
-    stack: &mut Vec<LispValue>,
+    stack: &mut [LispValue],
     f: F,
 ) -> EvaluationResult<()> {
     let reference = stack.last_mut().unwrap();
@@ -22,10 +26,223 @@ fn unitary_list<F: Fn(&mut Vec<LispValue>) -> EvaluationResult<LispValue>>(
     Ok(())
 }
 
+/// Replaces the given number of integers at the top of the stack by their
+/// left fold. When no initial value is given, the first integer is used.
+fn fold_integers(
+    stack: &mut Vec<LispValue>,
+    arg_count: usize,
+    init: Option<i64>,
+    op: Operation,
+) -> EvaluationResult<()> {
+    let start = stack.len() - arg_count;
+    let result = {
+        let mut integers = stack.drain(start..);
+        let mut acc = match init {
+            Some(i) => LispValue::Integer(i),
+            None => integers.next().unwrap(),
+        };
+
+        for i in integers {
+            acc = integer::apply(op, acc, i)?;
+        }
+
+        acc
+    };
+
+    stack.push(result);
+    Ok(())
+}
+
+/// Pushes the default values of the optional arguments that were not
+/// supplied and collects any remaining arguments in a list, so that the
+/// stack holds a value for every argument of the function.
+fn complete_arguments(stack: &mut Vec<LispValue>, f: &CustomFunc, arg_count: usize) {
+    let arity = f.0.arity;
+    let positional_count = arity.required + arity.optional;
+
+    if arg_count < positional_count {
+        stack.extend(f.0.defaults[arg_count - arity.required..].iter().cloned());
+    }
+
+    if arity.rest {
+        let rest = if arg_count > positional_count {
+            let start = stack.len() - (arg_count - positional_count);
+            stack.split_off(start)
+        } else {
+            Vec::new()
+        };
+
+        stack.push(LispValue::List(rest));
+    }
+}
+
+fn compare_integers(
+    stack: &mut Vec<LispValue>,
+    arg_count: usize,
+    comparison: Comparison,
+) -> EvaluationResult<()> {
+    let start = stack.len() - arg_count;
+    let mut result = true;
+
+    for pair in stack[start..].windows(2) {
+        result = result && comparison.holds(integer::compare(&pair[0], &pair[1])?);
+    }
+
+    // A single argument has nothing to compare against, but must still
+    // be an integer
+    if stack[start].get_type() != ArgType::Integer {
+        return Err(EvaluationError::ArgumentTypeMismatch);
+    }
+
+    stack.truncate(start);
+    stack.push(LispValue::Boolean(result));
+    Ok(())
+}
+
+fn into_string(value: LispValue) -> EvaluationResult<String> {
+    match value {
+        LispValue::String(s) => Ok(s),
+        _ => Err(EvaluationError::ArgumentTypeMismatch),
+    }
+}
+
+/// Replaces the character at the top of the stack by the result of `f`.
+fn unitary_char<F: Fn(char) -> EvaluationResult<LispValue>>(
+    stack: &mut [LispValue],
+    f: F,
+) -> EvaluationResult<()> {
+    let reference = stack.last_mut().unwrap();
+
+    *reference = if let LispValue::Char(c) = *reference {
+        f(c)?
+    } else {
+        return Err(EvaluationError::ArgumentTypeMismatch);
+    };
+
+    Ok(())
+}
+
+fn into_index(value: LispValue) -> EvaluationResult<usize> {
+    match value {
+        LispValue::Integer(i) if i >= 0 => Ok(i as usize),
+        LispValue::Integer(..) | LispValue::BigInteger(..) => Err(EvaluationError::IndexOutOfRange),
+        _ => Err(EvaluationError::ArgumentTypeMismatch),
+    }
+}
+
+/// Returns the characters of `s` from index `start` up to `end`.
+fn substring(s: &str, start: usize, end: usize) -> EvaluationResult<String> {
+    if start > end {
+        return Err(EvaluationError::IndexOutOfRange);
+    }
+
+    // Byte offsets of every character boundary in the string
+    let mut boundaries = s
+        .char_indices()
+        .map(|(idx, _)| idx)
+        .chain(iter::once(s.len()));
+    let start_byte = boundaries
+        .nth(start)
+        .ok_or(EvaluationError::IndexOutOfRange)?;
+    let end_byte = if end == start {
+        start_byte
+    } else {
+        boundaries
+            .nth(end - start - 1)
+            .ok_or(EvaluationError::IndexOutOfRange)?
+    };
+
+    Ok(s[start_byte..end_byte].into())
+}
+
+/// A part of a value, as found by a list path
+enum Part<'a> {
+    Value(&'a LispValue),
+    /// The elements of a list that remain after the dropped ones
+    Elements(&'a [LispValue]),
+}
+
+fn value_part<'a>(value: &'a LispValue, path: &ListPath) -> EvaluationResult<Part<'a>> {
+    let mut part = value;
+
+    for &index in &path.indices {
+        part = match *part {
+            LispValue::List(ref list) if index < list.len() => &list[list.len() - 1 - index],
+            LispValue::Record(_, ref fields) if index < fields.len() => &fields[index],
+            LispValue::List(..) | LispValue::Record(..) => {
+                return Err(EvaluationError::IndexOutOfRange)
+            }
+            _ => return Err(EvaluationError::ArgumentTypeMismatch),
+        };
+    }
+
+    match *part {
+        _ if path.dropped == 0 => Ok(Part::Value(part)),
+        LispValue::List(ref list) if path.dropped <= list.len() => {
+            Ok(Part::Elements(&list[..list.len() - path.dropped]))
+        }
+        LispValue::List(..) => Err(EvaluationError::IndexOutOfRange),
+        _ => Err(EvaluationError::ArgumentTypeMismatch),
+    }
+}
+
+/// Moves the part at the path out of a value. Like `VarSplit`, this does
+/// not copy the remaining elements of a list.
+fn take_part(value: &mut LispValue, path: &ListPath) -> EvaluationResult<LispValue> {
+    let mut part = value;
+
+    for &index in &path.indices {
+        part = match *part {
+            LispValue::List(ref mut list) if index < list.len() => {
+                let len = list.len();
+                &mut list[len - 1 - index]
+            }
+            LispValue::Record(_, ref mut fields) if index < fields.len() => &mut fields[index],
+            LispValue::List(..) | LispValue::Record(..) => {
+                return Err(EvaluationError::IndexOutOfRange)
+            }
+            _ => return Err(EvaluationError::ArgumentTypeMismatch),
+        };
+    }
+
+    match *part {
+        _ if path.dropped == 0 => Ok(replace(part, LispValue::Boolean(false))),
+        LispValue::List(ref mut list) if path.dropped <= list.len() => {
+            let mut rest = take(list);
+            let len = rest.len();
+            rest.truncate(len - path.dropped);
+            Ok(LispValue::List(rest))
+        }
+        LispValue::List(..) => Err(EvaluationError::IndexOutOfRange),
+        _ => Err(EvaluationError::ArgumentTypeMismatch),
+    }
+}
+
+fn passes_test(part: Part, test: &PartTest) -> bool {
+    let elements = match part {
+        Part::Value(LispValue::List(list)) => Some(&list[..]),
+        Part::Value(..) => None,
+        Part::Elements(elements) => Some(elements),
+    };
+
+    match *test {
+        PartTest::Length(length, open) => elements
+            .is_some_and(|elements| elements.len() == length || (open && elements.len() > length)),
+        PartTest::Equal(ref value) => match part {
+            Part::Value(part_value) => part_value == value,
+            Part::Elements(elements) => *value == LispValue::List(elements.to_vec()),
+        },
+        PartTest::Record(name) => {
+            matches!(part, Part::Value(&LispValue::Record(n, _)) if n == name)
+        }
+    }
+}
+
 fn remove_old_arguments(stack: &mut Vec<LispValue>, start: StackOffset, end: StackOffset) {
     stack.splice(start.to_usize()..end.to_usize(), iter::empty());
 }
 
+#[derive(Clone)]
 struct StackRef {
     instr_pointer: usize,
     #[allow(dead_code)]
@@ -36,24 +253,34 @@ struct StackRef {
     instr_slice: &'static [Instr],
 }
 
+/// A handler of the errors in the body of a try expression
+struct Handler {
+    /// Frame of the try expression, right after its body
+    frame: StackRef,
+    frame_depth: usize,
+    /// Size of the value stack, including the handler function at its top
+    value_depth: usize,
+}
+
 impl StackRef {
     fn new(
         func: CustomFunc,
         stack_pointer: StackOffset,
         state: &State,
     ) -> EvaluationResult<StackRef> {
-        let reference = unsafe { transmute(func.compile(state)?) };
+        let reference = unsafe { transmute::<&[Instr], &'static [Instr]>(func.compile(state)?) };
 
         Ok(StackRef {
             instr_slice: reference,
             instr_pointer: reference.len(),
-            func: func,
-            stack_pointer: stack_pointer,
+            func,
+            stack_pointer,
         })
     }
 }
 
 pub fn eval(expr: LispExpr, state: &mut State) -> EvaluationResult<LispValue> {
+    let expr = expand::expand(expr, state)?;
     let (instructions, is_define) = match expr.into_top_expr()? {
         TopExpr::Define(name, sub_expr) => {
             let finalized_definition =
@@ -64,6 +291,31 @@ pub fn eval(expr: LispExpr, state: &mut State) -> EvaluationResult<LispValue> {
                 Some(name),
             )
         }
+        TopExpr::DefineMacro(name, transformer) => {
+            let finalized_transformer =
+                transformer.finalize(&mut FinalizationContext::new(None))?;
+            let instructions = compile_finalized_expr(finalized_transformer.0, true, state)?;
+
+            match run(instructions, state)? {
+                LispValue::Function(f) => {
+                    state.set_macro(name, expand::Transformer::Procedure(f))?
+                }
+                _ => return Err(EvaluationError::MalformedDefinition),
+            }
+
+            return Ok(LispValue::List(Vec::new()));
+        }
+        TopExpr::DefineSyntax(name, rules) => {
+            let rules = expand::SyntaxRules::new(rules.into_code_datum(state), state)?;
+            state.set_macro(name, expand::Transformer::Rules(rules))?;
+
+            return Ok(LispValue::List(Vec::new()));
+        }
+        TopExpr::DefineRecord(name, fields) => {
+            state.define_record(name, &fields)?;
+
+            return Ok(LispValue::List(Vec::new()));
+        }
         TopExpr::Regular(sub_expr, _returns) => {
             let instr_vec = compile_finalized_expr(sub_expr, true, state)?;
             (instr_vec, None)
@@ -80,53 +332,216 @@ pub fn eval(expr: LispExpr, state: &mut State) -> EvaluationResult<LispValue> {
     }
 }
 
+/// Applies a function to the given arguments.
+pub fn apply(f: LispFunc, args: Vec<LispValue>, state: &State) -> EvaluationResult<LispValue> {
+    let call = FinalizedExpr::FunctionCall(
+        Box::new(FinalizedExpr::Value(LispValue::Function(f))),
+        args.into_iter().map(FinalizedExpr::Value).collect(),
+        false,
+        false,
+    );
+
+    run(compile_finalized_expr(call, true, state)?, state)
+}
+
+/// Evaluation error of a complete program. Contains the index of the
+/// top-level form that failed.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ProgramError {
+    pub form_index: usize,
+    pub error: EvaluationError,
+}
+
+/// Evaluates a sequence of top-level forms in order, stopping at the first
+/// one that fails. Returns the value of the last form.
+pub fn eval_program<I>(exprs: I, state: &mut State) -> Result<LispValue, ProgramError>
+where
+    I: IntoIterator<Item = LispExpr>,
+{
+    let mut last_value = LispValue::List(Vec::new());
+
+    for (form_index, expr) in exprs.into_iter().enumerate() {
+        last_value = eval(expr, state).map_err(|error| ProgramError { form_index, error })?;
+    }
+
+    Ok(last_value)
+}
+
 fn run(instructions: Vec<Instr>, state: &State) -> EvaluationResult<LispValue> {
     let mut value_stack: Vec<LispValue> = Vec::new();
     let mut frame_stack = vec![];
+    let mut handlers = vec![];
     let mut frame = StackRef::new(
         CustomFunc::from_byte_code(0, instructions),
         StackOffset::default(),
         state,
     )?;
 
+    // Errors unwind the stacks to the innermost handler, which is applied
+    // to the raised value. Other errors are passed to it as symbols. Its
+    // result is the value of the try expression.
+    while let Err(error) = execute(
+        &mut frame,
+        &mut frame_stack,
+        &mut value_stack,
+        &mut handlers,
+        state,
+    ) {
+        let handler: Handler = match handlers.pop() {
+            Some(handler) => handler,
+            None => return Err(error),
+        };
+        let raised = state.error_value(error);
+
+        frame_stack.truncate(handler.frame_depth);
+        value_stack.truncate(handler.value_depth);
+        let handler_func = value_stack.pop().unwrap();
+        let stack_pointer = StackOffset::from(value_stack.len());
+        value_stack.push(raised);
+        value_stack.push(handler_func);
+
+        let application =
+            CustomFunc::from_byte_code(1, vec![Instr::Return, Instr::EvalFunction(1, None)]);
+        frame_stack.push(handler.frame);
+        frame = StackRef::new(application, stack_pointer, state)?;
+    }
+
+    assert!(frame_stack.is_empty());
+    assert_eq!(value_stack.len(), 1);
+    Ok(value_stack.pop().unwrap())
+}
+
+/// Evaluates `f` with as many of the arguments at the top of the stack as
+/// it takes, and then applies its result to the others.
+fn apply_to_remainder(
+    f: CustomFunc,
+    arg_count: usize,
+    is_tail_call: bool,
+    frame: &mut StackRef,
+    frame_stack: &mut Vec<StackRef>,
+    value_stack: &mut [LispValue],
+    state: &State,
+) -> EvaluationResult<()> {
+    let taken_count = f.0.arity.slots();
+    let rest_count = arg_count - taken_count;
+    let start = value_stack.len() - arg_count;
+    value_stack[start..].rotate_left(taken_count);
+
+    let remainder = CustomFunc::from_byte_code(
+        rest_count,
+        vec![Instr::Return, Instr::EvalFunction(rest_count, None)],
+    );
+    let remainder_frame = StackRef::new(remainder, StackOffset::from(start), state)?;
+
+    if is_tail_call {
+        *frame = remainder_frame;
+    } else {
+        frame_stack.push(replace(frame, remainder_frame));
+    }
+
+    let stack_pointer = StackOffset::from(start + rest_count);
+    let next_frame = StackRef::new(f, stack_pointer, state)?;
+    frame_stack.push(replace(frame, next_frame));
+    Ok(())
+}
+
+fn execute(
+    frame: &mut StackRef,
+    frame_stack: &mut Vec<StackRef>,
+    value_stack: &mut Vec<LispValue>,
+    handlers: &mut Vec<Handler>,
+    state: &State,
+) -> EvaluationResult<()> {
     'l: loop {
         frame.instr_pointer -= 1;
 
         match frame.instr_slice[frame.instr_pointer] {
+            Instr::DropLocals(count) => {
+                let top_index = StackOffset::from(value_stack.len() - 1);
+                remove_old_arguments(value_stack, top_index - StackOffset::from(count), top_index);
+            }
+            Instr::Pop => {
+                value_stack.pop();
+            }
             Instr::Return => {
                 // Remove all values except for the last, which is the return value of
                 // called function
                 let top_index = StackOffset::from(value_stack.len() - 1);
-                remove_old_arguments(&mut value_stack, frame.stack_pointer, top_index);
+                remove_old_arguments(value_stack, frame.stack_pointer, top_index);
 
                 if let Some(new_frame) = frame_stack.pop() {
-                    frame = new_frame;
+                    *frame = new_frame;
                 } else {
                     break 'l;
                 }
             }
             Instr::VarAddOne(offset) => {
-                if let LispValue::Integer(ref mut i) = *value_stack
-                    .get_mut((frame.stack_pointer + offset).to_usize())
-                    .unwrap()
-                {
-                    *i += 1;
-                } else {
-                    return Err(EvaluationError::ArgumentTypeMismatch);
-                }
+                integer::increment(
+                    value_stack
+                        .get_mut((frame.stack_pointer + offset).to_usize())
+                        .unwrap(),
+                    1,
+                )?;
+            }
+            Instr::VarTest(offset, ref path_test) => {
+                let (ref path, ref test) = **path_test;
+                let part = value_part(
+                    &value_stack[(frame.stack_pointer + offset).to_usize()],
+                    path,
+                )?;
+                let holds = passes_test(part, test);
+                value_stack.push(LispValue::Boolean(holds));
+            }
+            Instr::VarPart(offset, ref path) => {
+                let value = match value_part(
+                    &value_stack[(frame.stack_pointer + offset).to_usize()],
+                    path,
+                )? {
+                    Part::Value(value) => value.clone(),
+                    Part::Elements(elements) => LispValue::List(elements.to_vec()),
+                };
+                value_stack.push(value);
+            }
+            Instr::VarTakePart(offset, ref path) => {
+                let value = take_part(
+                    &mut value_stack[(frame.stack_pointer + offset).to_usize()],
+                    path,
+                )?;
+                value_stack.push(value);
+            }
+            Instr::NoMatch => return Err(EvaluationError::NoMatch),
+            Instr::UnknownVariable(n) => {
+                return Err(EvaluationError::UnknownVariable(
+                    state.resolve_intern(n).into(),
+                ))
+            }
+            Instr::Try(catch_offset) => {
+                let mut catch_frame = frame.clone();
+                catch_frame.instr_pointer -= catch_offset;
+
+                handlers.push(Handler {
+                    frame: catch_frame,
+                    frame_depth: frame_stack.len(),
+                    value_depth: value_stack.len(),
+                });
+            }
+            Instr::EndTry => {
+                handlers.pop();
+                let top_index = StackOffset::from(value_stack.len() - 1);
+                remove_old_arguments(value_stack, top_index - StackOffset::from(1), top_index);
+            }
+            Instr::Raise => {
+                return Err(EvaluationError::Raised(value_stack.pop().unwrap()));
             }
             Instr::CondZeroJumpDecr(offset, jump_size) => {
-                if let LispValue::Integer(ref mut i) = *value_stack
+                let value = value_stack
                     .get_mut((frame.stack_pointer + offset).to_usize())
-                    .unwrap()
-                {
-                    if *i == 0 {
-                        frame.instr_pointer -= jump_size;
-                    } else {
-                        *i -= 1;
-                    }
+                    .unwrap();
+
+                if integer::is_zero(value)? {
+                    frame.instr_pointer -= jump_size;
                 } else {
-                    return Err(EvaluationError::ArgumentTypeMismatch);
+                    integer::increment(value, -1)?;
                 }
             }
             Instr::VarCheckNull(offset) => {
@@ -142,16 +557,13 @@ fn run(instructions: Vec<Instr>, state: &State) -> EvaluationResult<LispValue> {
                 value_stack.push(head);
             }
             Instr::VarCheckZero(offset) => {
-                let head = if let LispValue::Integer(i) = *value_stack
-                    .get((frame.stack_pointer + offset).to_usize())
-                    .unwrap()
-                {
-                    LispValue::Boolean(i == 0)
-                } else {
-                    return Err(EvaluationError::ArgumentTypeMismatch);
-                };
+                let is_zero = integer::is_zero(
+                    value_stack
+                        .get((frame.stack_pointer + offset).to_usize())
+                        .unwrap(),
+                )?;
 
-                value_stack.push(head);
+                value_stack.push(LispValue::Boolean(is_zero));
             }
             Instr::VarSplit(offset) => {
                 let head = if let LispValue::List(ref mut list) = *value_stack
@@ -208,21 +620,25 @@ fn run(instructions: Vec<Instr>, state: &State) -> EvaluationResult<LispValue> {
                 value_stack.push(head);
             }
             Instr::Recurse(arg_count) => {
-                if arg_count > 0 {
-                    let top_index = frame.stack_pointer + StackOffset::from(frame.func.0.arg_count);
-                    let bottom_index = top_index - StackOffset::from(arg_count);
-                    remove_old_arguments(&mut value_stack, bottom_index, top_index);
+                // Remove the arguments that are replaced, along with any local
+                // variables that were pushed after them
+                let bottom_index =
+                    frame.stack_pointer + StackOffset::from(frame.func.0.arity.slots() - arg_count);
+                let top_index = StackOffset::from(value_stack.len() - arg_count);
+                if bottom_index < top_index {
+                    remove_old_arguments(value_stack, bottom_index, top_index);
                 }
                 frame.instr_pointer = frame.instr_slice.len();
             }
-            Instr::CreateLambda(scope, arg_count, ref body, returns) => {
+            Instr::CreateLambda(scope, arity, ref body, returns) => {
+                let defaults = value_stack.split_off(value_stack.len() - arity.optional);
                 // If there are any references to function arguments in
                 // the lambda body, we should resolve them before
                 // creating the lambda.
                 // This enables us to do closures.
                 let walked_body =
                     body.replace_args(scope, &mut value_stack[From::from(frame.stack_pointer)..]);
-                let f = LispFunc::new_custom(arg_count, walked_body, returns);
+                let f = LispFunc::new_custom(arity, defaults, walked_body, returns);
 
                 value_stack.push(LispValue::Function(f));
             }
@@ -230,12 +646,9 @@ fn run(instructions: Vec<Instr>, state: &State) -> EvaluationResult<LispValue> {
                 frame.instr_pointer -= n;
             }
             Instr::CondJump(n) => {
-                if let LispValue::Boolean(b) = value_stack.pop().unwrap() {
-                    if b {
-                        frame.instr_pointer -= n;
-                    }
-                } else {
-                    return Err(EvaluationError::ArgumentTypeMismatch);
+                // Any value but false is true
+                if value_stack.pop().unwrap() != LispValue::Boolean(false) {
+                    frame.instr_pointer -= n;
                 }
             }
             Instr::PushValue(ref v) => {
@@ -243,7 +656,7 @@ fn run(instructions: Vec<Instr>, state: &State) -> EvaluationResult<LispValue> {
             }
             Instr::CloneArgument(offset) => {
                 let idx = (frame.stack_pointer + offset).to_usize();
-                let value = (&value_stack[..]).index(idx).clone();
+                let value = value_stack[..].index(idx).clone();
                 value_stack.push(value);
             }
             Instr::MoveArgument(offset) => {
@@ -260,85 +673,126 @@ fn run(instructions: Vec<Instr>, state: &State) -> EvaluationResult<LispValue> {
             Instr::EvalFunction(arg_count, tail_call_args) => {
                 let top_stack = value_stack.pop().unwrap();
                 if let LispValue::Function(funk) = top_stack {
+                    // Built-in functions that take all the arguments and
+                    // partial applications return to the current frame,
+                    // which drops its own locals. Otherwise, the call
+                    // replaces the current frame.
+                    let replaces_frame = match funk {
+                        LispFunc::BuiltIn(b) => b.arity().is_some_and(|n| n < arg_count),
+                        LispFunc::Custom(ref f) => arg_count >= f.0.arity.required,
+                    };
+
+                    if let Some(arg_reuse_count) = tail_call_args {
+                        if replaces_frame {
+                            // Everything between the reused arguments and the new
+                            // ones is no longer needed. After removing it, the
+                            // arguments are at the top of the stack.
+                            let remove_count =
+                                value_stack.len() - frame.stack_pointer.to_usize() - arg_count;
+                            if remove_count > 0 {
+                                // Remove old arguments from the stack.
+                                let bottom_index =
+                                    frame.stack_pointer + StackOffset::from(arg_reuse_count);
+                                let top_index = bottom_index + StackOffset::from(remove_count);
+                                remove_old_arguments(value_stack, bottom_index, top_index);
+                            }
+                        } else if arg_reuse_count > 0 {
+                            // The current frame is kept, so the reused arguments
+                            // are copied below the new ones instead.
+                            let bottom = frame.stack_pointer.to_usize();
+                            let reused = value_stack[bottom..bottom + arg_reuse_count].to_vec();
+                            let top = value_stack.len() - (arg_count - arg_reuse_count);
+                            value_stack.splice(top..top, reused);
+                        }
+                    }
+
                     let (next_func, push_stack) = match funk {
                         LispFunc::BuiltIn(b) => {
+                            // Built-in functions with a fixed number of arguments
+                            // apply their result to any further arguments
+                            let taken_count = b.arity().filter(|&n| n < arg_count);
                             // The performance of this solution is basically horrendous,
                             // but all the performant solutions are super messy.
                             // This shouldn't occur too often, though.
                             let func = CustomFunc::from_byte_code(
-                                arg_count,
-                                vec![Instr::Return, builtin_instr(b, arg_count)?],
+                                taken_count.unwrap_or(arg_count),
+                                vec![
+                                    Instr::Return,
+                                    builtin_instr(b, taken_count.unwrap_or(arg_count))?,
+                                ],
                             );
 
+                            if taken_count.is_some() {
+                                let is_tail_call = tail_call_args.is_some();
+                                apply_to_remainder(
+                                    func,
+                                    arg_count,
+                                    is_tail_call,
+                                    frame,
+                                    frame_stack,
+                                    value_stack,
+                                    state,
+                                )?;
+                                continue;
+                            }
+
                             (func, true)
                         }
                         LispFunc::Custom(f) => {
-                            let func_arg_count = f.0.arg_count;
-
-                            // Exactly right number of arguments. Let's evaluate.
-                            if func_arg_count == arg_count {
-                                if let Some(arg_reuse_count) = tail_call_args {
-                                    let remove_count = value_stack.len()
-                                        - frame.stack_pointer.to_usize()
-                                        - func_arg_count;
-                                    if remove_count > 0 {
-                                        // Remove old arguments from the stack.
-                                        let bottom_index = frame.stack_pointer
-                                            + StackOffset::from(arg_reuse_count);
-                                        let top_index =
-                                            bottom_index + StackOffset::from(remove_count);
-                                        remove_old_arguments(
-                                            &mut value_stack,
-                                            bottom_index,
-                                            top_index,
-                                        );
-                                    }
-
-                                    (f, false)
-                                } else {
-                                    // No need to add this frame to the frame stack when
-                                    // we're just immediately going to return next
-                                    (
-                                        f,
-                                        frame.instr_slice[frame.instr_pointer - 1] != Instr::Return,
-                                    )
-                                }
+                            let arity = f.0.arity;
+
+                            // Right number of arguments. Let's evaluate.
+                            if arity.accepts(arg_count) {
+                                // No need to add this frame to the frame stack when
+                                // we're just immediately going to return next
+                                let push_stack = tail_call_args.is_none()
+                                    && frame.instr_slice[frame.instr_pointer - 1] != Instr::Return;
+
+                                complete_arguments(value_stack, &f, arg_count);
+                                (f, push_stack)
                             }
                             // Not enough arguments, let's create a lambda that takes
                             // the remainder.
-                            else if arg_count < func_arg_count {
+                            else if arg_count < arity.required {
                                 let temp_stack = value_stack.len() - arg_count;
-                                let continuation = LispFunc::curry(
-                                    f,
-                                    func_arg_count,
-                                    arg_count,
-                                    value_stack.drain(temp_stack..),
-                                );
+                                let continuation =
+                                    LispFunc::curry(f, arg_count, value_stack.drain(temp_stack..));
 
                                 value_stack.push(LispValue::Function(continuation));
                                 continue;
                             }
-                            // Too many arguments.
+                            // Too many arguments. The function is evaluated with
+                            // the arguments it takes and its result is applied to
+                            // the rest.
                             else {
-                                return Err(EvaluationError::ArgumentCountMismatch);
+                                let is_tail_call = tail_call_args.is_some();
+                                apply_to_remainder(
+                                    f,
+                                    arg_count,
+                                    is_tail_call,
+                                    frame,
+                                    frame_stack,
+                                    value_stack,
+                                    state,
+                                )?;
+                                continue;
                             }
                         }
                     };
 
                     // Create a new stack frame and replace the current one with it
                     let stack_pointer =
-                        StackOffset::from(value_stack.len() - next_func.0.arg_count);
+                        StackOffset::from(value_stack.len() - next_func.0.arity.slots());
                     let next_frame = StackRef::new(next_func, stack_pointer, state)?;
 
                     // If the called function is not a tail call and there are instructions
                     // left in the calling function, push the old stack frame to the stack.
                     if push_stack {
-                        frame_stack.push(replace(&mut frame, next_frame));
+                        frame_stack.push(replace(frame, next_frame));
                     } else {
-                        frame = next_frame;
+                        *frame = next_frame;
                     }
                 } else {
-                    println!("Tried to apply {:?}", top_stack);
                     return Err(EvaluationError::NonFunctionApplication);
                 }
             }
@@ -347,7 +801,7 @@ fn run(instructions: Vec<Instr>, state: &State) -> EvaluationResult<LispValue> {
                 let new_vec = value_stack.split_off(len - arg_count);
                 value_stack.push(LispValue::List(new_vec));
             }
-            Instr::Car => unitary_list(&mut value_stack, |vec| match vec.pop() {
+            Instr::Car => unitary_list(value_stack, |vec| match vec.pop() {
                 Some(car) => Ok(car),
                 None => Err(EvaluationError::EmptyList),
             })?,
@@ -360,26 +814,14 @@ fn run(instructions: Vec<Instr>, state: &State) -> EvaluationResult<LispValue> {
                     return Err(EvaluationError::ArgumentTypeMismatch);
                 };
             }
-            Instr::CheckNull => unitary_list(&mut value_stack, |vec| {
-                Ok(LispValue::Boolean(vec.is_empty()))
-            })?,
+            Instr::CheckNull => {
+                unitary_list(value_stack, |vec| Ok(LispValue::Boolean(vec.is_empty())))?
+            }
             Instr::AddOne => {
-                if let LispValue::Integer(ref mut i) = *value_stack.last_mut().unwrap() {
-                    *i += 1;
-                } else {
-                    return Err(EvaluationError::ArgumentTypeMismatch);
-                }
+                integer::increment(value_stack.last_mut().unwrap(), 1)?;
             }
             Instr::SubOne => {
-                if let LispValue::Integer(ref mut i) = *value_stack.last_mut().unwrap() {
-                    if *i > 0 {
-                        *i -= 1;
-                    } else {
-                        return Err(EvaluationError::SubZero);
-                    }
-                } else {
-                    return Err(EvaluationError::ArgumentTypeMismatch);
-                }
+                integer::increment(value_stack.last_mut().unwrap(), -1)?;
             }
             Instr::Cons => {
                 let len = value_stack.len();
@@ -393,21 +835,149 @@ fn run(instructions: Vec<Instr>, state: &State) -> EvaluationResult<LispValue> {
             }
             Instr::CheckZero => {
                 let reference = value_stack.last_mut().unwrap();
-                let is_zero = if let LispValue::Integer(i) = *reference {
-                    i == 0
-                } else {
-                    return Err(EvaluationError::ArgumentTypeMismatch);
-                };
+                let is_zero = integer::is_zero(reference)?;
                 *reference = LispValue::Boolean(is_zero);
             }
             Instr::CheckType(arg_type) => {
                 let same_type = arg_type == value_stack.pop().unwrap().get_type();
                 value_stack.push(LispValue::Boolean(same_type));
             }
+            Instr::Add(arg_count) => {
+                fold_integers(value_stack, arg_count, Some(0), Operation::Add)?
+            }
+            Instr::Sub(arg_count) => {
+                let init = if arg_count == 1 { Some(0) } else { None };
+                fold_integers(value_stack, arg_count, init, Operation::Sub)?
+            }
+            Instr::Mul(arg_count) => {
+                fold_integers(value_stack, arg_count, Some(1), Operation::Mul)?
+            }
+            Instr::Quotient => fold_integers(value_stack, 2, None, Operation::Quotient)?,
+            Instr::Remainder => fold_integers(value_stack, 2, None, Operation::Remainder)?,
+            Instr::Compare(comparison, arg_count) => {
+                compare_integers(value_stack, arg_count, comparison)?
+            }
+            Instr::StringLength => {
+                let reference = value_stack.last_mut().unwrap();
+                let length = if let LispValue::String(ref s) = *reference {
+                    s.chars().count()
+                } else {
+                    return Err(EvaluationError::ArgumentTypeMismatch);
+                };
+                *reference = LispValue::Integer(length as i64);
+            }
+            Instr::StringAppend(arg_count) => {
+                let start = value_stack.len() - arg_count;
+                let mut result = String::new();
+
+                for value in value_stack.drain(start..) {
+                    result.push_str(&into_string(value)?);
+                }
+
+                value_stack.push(LispValue::String(result));
+            }
+            Instr::Substring => {
+                let end = into_index(value_stack.pop().unwrap())?;
+                let start = into_index(value_stack.pop().unwrap())?;
+                let s = into_string(value_stack.pop().unwrap())?;
+                value_stack.push(LispValue::String(substring(&s, start, end)?));
+            }
+            Instr::StringEqual(arg_count) => {
+                let start = value_stack.len() - arg_count;
+                let strings = value_stack
+                    .drain(start..)
+                    .map(into_string)
+                    .collect::<EvaluationResult<Vec<_>>>()?;
+                let all_equal = strings.windows(2).all(|pair| pair[0] == pair[1]);
+                value_stack.push(LispValue::Boolean(all_equal));
+            }
+            Instr::StringToList => {
+                let s = into_string(value_stack.pop().unwrap())?;
+                let chars = s.chars().map(LispValue::Char);
+                value_stack.push(LispValue::List(chars.collect()));
+            }
+            Instr::NumberToString => {
+                let reference = value_stack.last_mut().unwrap();
+                let s = match *reference {
+                    LispValue::Integer(i) => i.to_string(),
+                    LispValue::BigInteger(ref i) => i.to_string(),
+                    _ => return Err(EvaluationError::ArgumentTypeMismatch),
+                };
+                *reference = LispValue::String(s);
+            }
+            Instr::CharToInteger => unitary_char(value_stack, |c| {
+                Ok(LispValue::Integer(i64::from(u32::from(c))))
+            })?,
+            Instr::IntegerToChar => {
+                let reference = value_stack.last_mut().unwrap();
+                let c = match *reference {
+                    LispValue::Integer(i) => u32::try_from(i)
+                        .ok()
+                        .and_then(::std::char::from_u32)
+                        .ok_or(EvaluationError::InvalidCodePoint)?,
+                    LispValue::BigInteger(..) => return Err(EvaluationError::InvalidCodePoint),
+                    _ => return Err(EvaluationError::ArgumentTypeMismatch),
+                };
+                *reference = LispValue::Char(c);
+            }
+            Instr::CharUpcase => unitary_char(value_stack, |c| {
+                // Characters without a single character upper case, like
+                // the german sharp s, are left as is.
+                let mut upper = c.to_uppercase();
+                Ok(LispValue::Char(match (upper.next(), upper.next()) {
+                    (Some(u), None) => u,
+                    _ => c,
+                }))
+            })?,
+            Instr::CheckAlphabetic => {
+                unitary_char(value_stack, |c| Ok(LispValue::Boolean(c.is_alphabetic())))?
+            }
+            Instr::CheckEq => {
+                let rhs = value_stack.pop().unwrap();
+                let lhs = value_stack.pop().unwrap();
+                // Values without an identity are never the same
+                let same = lhs.is_atom() && lhs == rhs;
+                value_stack.push(LispValue::Boolean(same));
+            }
+            Instr::CheckEqual => {
+                let rhs = value_stack.pop().unwrap();
+                let lhs = value_stack.pop().unwrap();
+                value_stack.push(LispValue::Boolean(lhs == rhs));
+            }
+            Instr::MacroExpand => {
+                let form = value_stack.pop().unwrap();
+                value_stack.push(expand::macroexpand(form, state)?);
+            }
+            Instr::MakeRecord(name, field_count) => {
+                let fields = value_stack.split_off(value_stack.len() - field_count);
+                value_stack.push(LispValue::Record(name, fields));
+            }
+            Instr::CheckRecord(name) => {
+                let is_record =
+                    matches!(value_stack.pop().unwrap(), LispValue::Record(n, _) if n == name);
+                value_stack.push(LispValue::Boolean(is_record));
+            }
+            Instr::RecordField(name, index) => match value_stack.pop().unwrap() {
+                LispValue::Record(n, mut fields) if n == name => {
+                    value_stack.push(fields.swap_remove(index))
+                }
+                _ => return Err(EvaluationError::ArgumentTypeMismatch),
+            },
+            Instr::Append(arg_count) => {
+                let start = value_stack.len() - arg_count;
+                let mut result = Vec::new();
+
+                for value in value_stack.drain(start..) {
+                    match value {
+                        LispValue::List(mut v) => result.append(&mut v),
+                        _ => return Err(EvaluationError::ArgumentTypeMismatch),
+                    }
+                }
+
+                value_stack.push(LispValue::List(result));
+            }
         }
     }
 
-    assert!(frame_stack.is_empty());
-    assert_eq!(value_stack.len(), 1);
-    Ok(value_stack.pop().unwrap())
+    Ok(())
 }
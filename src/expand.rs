@@ -0,0 +1,972 @@
+use super::evaluator;
+use super::parse::read_datum;
+use super::{
+    BuiltIn, EvaluationError, EvaluationResult, InternedString, LispExpr, LispFunc, LispMacro,
+    LispValue, State,
+};
+use std::collections::HashMap;
+use std::slice;
+use std::sync::Arc;
+
+/// The maximum number of expansions that a macro call may go through,
+/// including those of the calls it is nested in, so that macros which
+/// never stop expanding fail instead of looping forever or overflowing
+/// the stack.
+const EXPANSION_LIMIT: usize = 256;
+
+/// The function that computes the expansion of a macro.
+#[derive(Debug, Clone)]
+pub enum Transformer {
+    /// Defined by `defmacro`
+    Procedure(LispFunc),
+    /// Defined by `define-syntax`
+    Rules(Arc<SyntaxRules>),
+}
+
+#[derive(Debug)]
+struct Rule {
+    pattern: LispValue,
+    template: LispValue,
+    /// Names bound by the template itself, which are renamed on expansion
+    binders: Vec<InternedString>,
+    /// Names used by the template without binding them, which refer to
+    /// global definitions
+    free: Vec<InternedString>,
+}
+
+/// A name in a template that is not a pattern variable.
+#[derive(Debug, Clone, Copy)]
+pub enum TemplateName {
+    /// Bound by the template itself
+    Binder(InternedString),
+    /// Used by the template without binding it
+    Free(InternedString),
+}
+
+/// Whether the part of a template being transcribed is code or data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Quoting {
+    Code,
+    Quoted,
+    Quasiquoted,
+}
+
+/// A pattern macro. Its uses are matched against the pattern of every rule
+/// in order and expand to the template of the first match.
+#[derive(Debug)]
+pub struct SyntaxRules {
+    literals: Vec<InternedString>,
+    rules: Vec<Rule>,
+    ellipsis: InternedString,
+    wildcard: InternedString,
+    quote: InternedString,
+    quasiquote: InternedString,
+    unquotes: [InternedString; 2],
+}
+
+/// The values matched by a pattern variable. Variables followed by an
+/// ellipsis match any number of values.
+#[derive(Debug, Clone)]
+enum Match {
+    One(LispValue),
+    Many(Vec<Match>),
+}
+
+type Bindings = HashMap<InternedString, Match>;
+
+fn is_symbol(value: &LispValue, name: InternedString) -> bool {
+    *value == LispValue::Symbol(name)
+}
+
+impl SyntaxRules {
+    /// Creates a macro from its specification, which has the form
+    /// `(syntax-rules (literal ...) (pattern template) ...)`.
+    pub fn new(spec: LispValue, state: &mut State) -> EvaluationResult<Arc<SyntaxRules>> {
+        let ellipsis = state.intern("...");
+        let wildcard = state.intern("_");
+        let keyword = state.intern(LispMacro::SyntaxRules.to_string());
+        let quote = state.intern(LispMacro::Quote.to_string());
+        let quasiquote = state.intern(LispMacro::Quasiquote.to_string());
+        let unquotes = [
+            state.intern(LispMacro::Unquote.to_string()),
+            state.intern(LispMacro::UnquoteSplicing.to_string()),
+        ];
+        let mut spec_iter = match spec {
+            LispValue::List(values) => values.into_iter(),
+            _ => return Err(EvaluationError::MalformedDefinition),
+        };
+
+        match (spec_iter.next(), spec_iter.next()) {
+            (Some(LispValue::Symbol(n)), Some(LispValue::List(literal_list))) if n == keyword => {
+                let mut syntax_rules = SyntaxRules {
+                    literals: Vec::with_capacity(literal_list.len()),
+                    rules: Vec::with_capacity(spec_iter.len()),
+                    ellipsis,
+                    wildcard,
+                    quote,
+                    quasiquote,
+                    unquotes,
+                };
+
+                for literal in literal_list {
+                    match literal {
+                        LispValue::Symbol(n) => syntax_rules.literals.push(n),
+                        _ => return Err(EvaluationError::MalformedDefinition),
+                    }
+                }
+
+                for rule in spec_iter {
+                    let mut parts = match rule {
+                        LispValue::List(parts) => parts.into_iter(),
+                        _ => return Err(EvaluationError::MalformedDefinition),
+                    };
+
+                    // Patterns are lists that start with the macro keyword
+                    match (parts.next(), parts.next(), parts.next()) {
+                        (Some(pattern), Some(template), None) if matches!(pattern, LispValue::List(ref p) if !p.is_empty()) =>
+                        {
+                            let mut variables = Vec::new();
+                            syntax_rules.pattern_variables(&pattern, true, &mut variables);
+                            let mut binders = Vec::new();
+                            template_binders(&template, state, &mut binders);
+                            binders.retain(|n| !variables.contains(n));
+                            let mut free = Vec::new();
+                            template_free_names(&template, state, &mut free);
+                            free.retain(|n| {
+                                !variables.contains(n) && !binders.contains(n) && *n != ellipsis
+                            });
+
+                            syntax_rules.rules.push(Rule {
+                                pattern,
+                                template,
+                                binders,
+                                free,
+                            });
+                        }
+                        _ => return Err(EvaluationError::MalformedDefinition),
+                    }
+                }
+
+                Ok(Arc::new(syntax_rules))
+            }
+            _ => Err(EvaluationError::MalformedDefinition),
+        }
+    }
+
+    /// Collects the variables of a pattern. The head of the outermost
+    /// pattern is the macro keyword, which is not a variable.
+    fn pattern_variables(
+        &self,
+        pattern: &LispValue,
+        skip_head: bool,
+        vars: &mut Vec<InternedString>,
+    ) {
+        match *pattern {
+            LispValue::Symbol(n)
+                if n != self.ellipsis && n != self.wildcard && !self.literals.contains(&n) =>
+            {
+                vars.push(n)
+            }
+            LispValue::List(ref values) => {
+                let skip = if skip_head { 1 } else { 0 };
+
+                for value in values.iter().skip(skip) {
+                    self.pattern_variables(value, false, vars);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Expands a use of the macro, given its arguments. The names that the
+    /// template binds or uses freely are replaced by the result of
+    /// `rename`, except in quoted data.
+    pub fn expand<F>(&self, args: Vec<LispValue>, mut rename: F) -> EvaluationResult<LispValue>
+    where
+        F: FnMut(TemplateName) -> LispValue,
+    {
+        for rule in &self.rules {
+            let mut bindings = HashMap::new();
+
+            let matches = match rule.pattern {
+                LispValue::List(ref patterns) => {
+                    self.match_list(&patterns[1..], &args, &mut bindings)
+                }
+                _ => unreachable!(),
+            };
+
+            if matches {
+                let binders = rule.binders.iter().map(|&n| (n, TemplateName::Binder(n)));
+                let free = rule.free.iter().map(|&n| (n, TemplateName::Free(n)));
+                let renames = binders
+                    .chain(free)
+                    .map(|(n, name)| (n, rename(name)))
+                    .collect();
+                return self.transcribe(&rule.template, &bindings, &renames, Quoting::Code);
+            }
+        }
+
+        Err(EvaluationError::NoMatchingRule)
+    }
+
+    fn match_pattern(
+        &self,
+        pattern: &LispValue,
+        value: &LispValue,
+        bindings: &mut Bindings,
+    ) -> bool {
+        match *pattern {
+            LispValue::Symbol(n) if n == self.wildcard => true,
+            LispValue::Symbol(n) if self.literals.contains(&n) => is_symbol(value, n),
+            LispValue::Symbol(n) => {
+                bindings.insert(n, Match::One(value.clone()));
+                true
+            }
+            LispValue::List(ref patterns) => match *value {
+                LispValue::List(ref values) => self.match_list(patterns, values, bindings),
+                _ => false,
+            },
+            ref literal => literal == value,
+        }
+    }
+
+    fn match_list(
+        &self,
+        patterns: &[LispValue],
+        values: &[LispValue],
+        bindings: &mut Bindings,
+    ) -> bool {
+        let ellipsis_index = patterns.iter().position(|p| is_symbol(p, self.ellipsis));
+
+        let repeat_index = match ellipsis_index {
+            Some(index) if index > 0 => index - 1,
+            // An ellipsis must follow a pattern
+            Some(_) => return false,
+            None => {
+                return patterns.len() == values.len()
+                    && patterns
+                        .iter()
+                        .zip(values)
+                        .all(|(p, v)| self.match_pattern(p, v, bindings))
+            }
+        };
+        let after = &patterns[repeat_index + 2..];
+
+        if values.len() < repeat_index + after.len() {
+            return false;
+        }
+
+        let repeat_end = values.len() - after.len();
+        let mut matches = Vec::with_capacity(repeat_end - repeat_index);
+
+        for value in &values[repeat_index..repeat_end] {
+            let mut sub_bindings = HashMap::new();
+
+            if !self.match_pattern(&patterns[repeat_index], value, &mut sub_bindings) {
+                return false;
+            }
+
+            matches.push(sub_bindings);
+        }
+
+        let mut vars = Vec::new();
+        self.pattern_variables(&patterns[repeat_index], false, &mut vars);
+
+        for var in vars {
+            let var_matches = matches
+                .iter_mut()
+                .map(|sub_bindings| sub_bindings.remove(&var).unwrap())
+                .collect();
+            bindings.insert(var, Match::Many(var_matches));
+        }
+
+        self.match_list(&patterns[..repeat_index], &values[..repeat_index], bindings)
+            && self.match_list(after, &values[repeat_end..], bindings)
+    }
+
+    fn transcribe(
+        &self,
+        template: &LispValue,
+        bindings: &Bindings,
+        renames: &HashMap<InternedString, LispValue>,
+        quoting: Quoting,
+    ) -> EvaluationResult<LispValue> {
+        match *template {
+            LispValue::Symbol(n) => match bindings.get(&n) {
+                Some(Match::One(value)) => Ok(value.clone()),
+                // Variables must be followed by as many ellipses as in the pattern
+                Some(&Match::Many(..)) => Err(EvaluationError::MalformedDefinition),
+                None if quoting == Quoting::Code => {
+                    Ok(renames.get(&n).cloned().unwrap_or(LispValue::Symbol(n)))
+                }
+                None => Ok(LispValue::Symbol(n)),
+            },
+            LispValue::List(ref templates) => {
+                let quoting = match templates.first() {
+                    Some(&LispValue::Symbol(n)) => self.argument_quoting(n, quoting),
+                    _ => quoting,
+                };
+                let mut result = Vec::with_capacity(templates.len());
+                let mut template_iter = templates.iter().peekable();
+
+                while let Some(sub_template) = template_iter.next() {
+                    if template_iter
+                        .peek()
+                        .is_some_and(|t| is_symbol(t, self.ellipsis))
+                    {
+                        template_iter.next();
+                        self.transcribe_repeated(
+                            sub_template,
+                            bindings,
+                            renames,
+                            quoting,
+                            &mut result,
+                        )?;
+                    } else {
+                        result.push(self.transcribe(sub_template, bindings, renames, quoting)?);
+                    }
+                }
+
+                Ok(LispValue::List(result))
+            }
+            ref v => Ok(v.clone()),
+        }
+    }
+
+    /// Returns whether the arguments of a list starting with `head` are
+    /// code or data, in the same way as the parser.
+    fn argument_quoting(&self, head: InternedString, quoting: Quoting) -> Quoting {
+        match quoting {
+            Quoting::Code if head == self.quote => Quoting::Quoted,
+            Quoting::Code if head == self.quasiquote => Quoting::Quasiquoted,
+            Quoting::Quasiquoted if self.unquotes.contains(&head) => Quoting::Code,
+            _ => quoting,
+        }
+    }
+
+    /// Transcribes a template followed by an ellipsis once for every value
+    /// matched by its variables.
+    fn transcribe_repeated(
+        &self,
+        template: &LispValue,
+        bindings: &Bindings,
+        renames: &HashMap<InternedString, LispValue>,
+        quoting: Quoting,
+        result: &mut Vec<LispValue>,
+    ) -> EvaluationResult<()> {
+        let mut vars = Vec::new();
+        self.pattern_variables(template, false, &mut vars);
+        let repeated: Vec<_> = vars
+            .into_iter()
+            .filter_map(|n| match bindings.get(&n) {
+                Some(Match::Many(matches)) => Some((n, matches)),
+                _ => None,
+            })
+            .collect();
+
+        let count = match repeated.first() {
+            Some(&(_, matches)) => matches.len(),
+            None => return Err(EvaluationError::MalformedDefinition),
+        };
+
+        if repeated.iter().any(|&(_, matches)| matches.len() != count) {
+            return Err(EvaluationError::NoMatchingRule);
+        }
+
+        for i in 0..count {
+            let mut sub_bindings = bindings.clone();
+
+            for &(n, matches) in &repeated {
+                sub_bindings.insert(n, matches[i].clone());
+            }
+
+            result.push(self.transcribe(template, &sub_bindings, renames, quoting)?);
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the name defined by a definition in a template.
+fn definition_name(value: &LispValue, state: &State) -> Option<InternedString> {
+    match *value {
+        LispValue::List(ref values) => match (values.first(), values.get(1)) {
+            (Some(&LispValue::Symbol(head)), Some(&LispValue::Symbol(n)))
+                if LispMacro::from_str(state.resolve_intern(head)) == Some(LispMacro::Define) =>
+            {
+                Some(n)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Calls `visit` on every part of a template that is code rather than
+/// quoted data.
+fn visit_code<F>(template: &LispValue, state: &State, quoting: Quoting, visit: &mut F)
+where
+    F: FnMut(&LispValue),
+{
+    if quoting == Quoting::Code {
+        visit(template);
+    }
+
+    if let LispValue::List(ref values) = *template {
+        let mac = match values.first() {
+            Some(&LispValue::Symbol(n)) => LispMacro::from_str(state.resolve_intern(n)),
+            _ => None,
+        };
+        let quoting = match (quoting, mac) {
+            (Quoting::Code, Some(LispMacro::Quote)) => return,
+            (Quoting::Code, Some(LispMacro::Quasiquote)) => Quoting::Quasiquoted,
+            (Quoting::Quasiquoted, Some(LispMacro::Unquote))
+            | (Quoting::Quasiquoted, Some(LispMacro::UnquoteSplicing)) => Quoting::Code,
+            _ => quoting,
+        };
+
+        for value in values {
+            visit_code(value, state, quoting, visit);
+        }
+    }
+}
+
+/// Collects the names that a template binds as arguments of its lambdas,
+/// as local variables, as definitions inside lambda bodies or in match
+/// patterns.
+fn template_binders(template: &LispValue, state: &State, binders: &mut Vec<InternedString>) {
+    visit_code(template, state, Quoting::Code, &mut |value| {
+        if let LispValue::List(ref values) = *value {
+            form_binders(values, state, binders);
+        }
+    });
+}
+
+/// Collects the names that a form in a template binds.
+fn form_binders(values: &[LispValue], state: &State, binders: &mut Vec<InternedString>) {
+    let mac = match values.first() {
+        Some(&LispValue::Symbol(n)) => LispMacro::from_str(state.resolve_intern(n)),
+        _ => None,
+    };
+    let mut bind = |n: InternedString| {
+        if !binders.contains(&n) {
+            binders.push(n);
+        }
+    };
+
+    match (mac, values.get(1)) {
+        (Some(LispMacro::Lambda), Some(args)) => {
+            let args = match *args {
+                LispValue::List(ref args) => &args[..],
+                ref rest => slice::from_ref(rest),
+            };
+
+            for arg in args {
+                match *arg {
+                    // Markers like `.` and `#!optional` are not names
+                    LispValue::Symbol(n)
+                        if LispMacro::from_str(state.resolve_intern(n)).is_none() =>
+                    {
+                        bind(n)
+                    }
+                    LispValue::List(ref pair) => {
+                        if let Some(&LispValue::Symbol(n)) = pair.first() {
+                            bind(n);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            for n in values[2..].iter().filter_map(|e| definition_name(e, state)) {
+                bind(n);
+            }
+        }
+        (Some(LispMacro::Let), Some(LispValue::List(bindings)))
+        | (Some(LispMacro::LetStar), Some(LispValue::List(bindings)))
+        | (Some(LispMacro::Letrec), Some(LispValue::List(bindings))) => {
+            for binding in bindings {
+                if let LispValue::List(ref binding) = *binding {
+                    if let Some(&LispValue::Symbol(n)) = binding.first() {
+                        bind(n);
+                    }
+                }
+            }
+        }
+        (Some(LispMacro::Match), Some(..)) => {
+            for clause in &values[2..] {
+                if let LispValue::List(ref clause) = *clause {
+                    if let Some(pattern) = clause.first() {
+                        pattern_binders(pattern, state, binders);
+                    }
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Collects the names that a template uses as code, other than keywords
+/// and built-in functions.
+fn template_free_names(template: &LispValue, state: &State, names: &mut Vec<InternedString>) {
+    visit_code(template, state, Quoting::Code, &mut |value| {
+        if let LispValue::Symbol(n) = *value {
+            let name = state.resolve_intern(n);
+
+            if LispMacro::from_str(name).is_none()
+                && BuiltIn::from_str(name).is_none()
+                && !names.contains(&n)
+            {
+                names.push(n);
+            }
+        }
+    });
+}
+
+/// Collects the names that a match pattern binds.
+fn pattern_names(pattern: &LispExpr, names: &mut Vec<InternedString>) {
+    match *pattern {
+        LispExpr::OpVar(n) => names.push(n),
+        LispExpr::Call(ref exprs) if exprs.first() != Some(&LispExpr::Macro(LispMacro::Quote)) => {
+            for expr in exprs {
+                pattern_names(expr, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Replaces the names of record constructors at the start of list patterns
+/// by the constructors themselves, so that these patterns match records.
+/// Only constructors that have been defined before are recognized, and
+/// names bound in an enclosing scope are left alone, so that a local
+/// binding shadowing a constructor makes the pattern a plain list pattern.
+fn resolve_constructors(pattern: &mut LispExpr, state: &State, bound: &[InternedString]) {
+    if let LispExpr::Call(ref mut exprs) = *pattern {
+        if exprs.first() == Some(&LispExpr::Macro(LispMacro::Quote)) {
+            return;
+        }
+
+        if let Some(&LispExpr::OpVar(n)) = exprs.first() {
+            if !bound.contains(&n) && is_record_constructor(n, state) {
+                exprs[0] = LispExpr::Value(state.get(n).unwrap().clone());
+            }
+        }
+
+        for expr in exprs {
+            resolve_constructors(expr, state, bound);
+        }
+    }
+}
+
+fn is_record_constructor(name: InternedString, state: &State) -> bool {
+    matches!(
+        state.get(name),
+        Some(&LispValue::Function(LispFunc::BuiltIn(
+            BuiltIn::MakeRecord(..)
+        )))
+    )
+}
+
+/// Collects the names that a match pattern binds, when it is data in a
+/// template.
+fn pattern_binders(pattern: &LispValue, state: &State, binders: &mut Vec<InternedString>) {
+    match *pattern {
+        LispValue::Symbol(n)
+            if LispMacro::from_str(state.resolve_intern(n)).is_none() && !binders.contains(&n) =>
+        {
+            binders.push(n)
+        }
+        LispValue::List(ref values) => {
+            let is_quote = match values.first() {
+                Some(&LispValue::Symbol(n)) => {
+                    LispMacro::from_str(state.resolve_intern(n)) == Some(LispMacro::Quote)
+                }
+                _ => false,
+            };
+
+            let is_record = match values.first() {
+                Some(&LispValue::Symbol(n)) => is_record_constructor(n, state),
+                _ => false,
+            };
+
+            if !is_quote {
+                // Record constructors are not bound
+                for value in &values[is_record as usize..] {
+                    pattern_binders(value, state, binders);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Returns the code for a value, which is quoted unless it evaluates to
+/// itself.
+fn quote_value(value: LispValue, state: &mut State) -> LispValue {
+    match value {
+        LispValue::Symbol(..) | LispValue::List(..) => {
+            let quote = state.intern(LispMacro::Quote.to_string());
+            LispValue::List(vec![LispValue::Symbol(quote), value])
+        }
+        value => value,
+    }
+}
+
+/// Applies a macro transformer to the data of its arguments. Names bound
+/// by syntax-rules templates are not renamed.
+fn apply_transformer(
+    transformer: &Transformer,
+    args: Vec<LispValue>,
+    state: &State,
+) -> EvaluationResult<LispValue> {
+    match *transformer {
+        Transformer::Procedure(ref f) => {
+            // Transformers are never curried
+            if let LispFunc::Custom(ref custom) = *f {
+                if !custom.0.arity.accepts(args.len()) {
+                    return Err(EvaluationError::ArgumentCountMismatch);
+                }
+            }
+
+            evaluator::apply(f.clone(), args, state)
+        }
+        Transformer::Rules(ref rules) => rules.expand(args, |name| match name {
+            TemplateName::Binder(n) | TemplateName::Free(n) => LispValue::Symbol(n),
+        }),
+    }
+}
+
+/// Expands a macro call until its head is no longer a macro. Subforms are
+/// left untouched.
+pub fn macroexpand(mut form: LispValue, state: &State) -> EvaluationResult<LispValue> {
+    for _ in 0..EXPANSION_LIMIT {
+        let transformer = match form {
+            LispValue::List(ref values) => match values.first() {
+                Some(&LispValue::Symbol(n)) => state.get_macro(n).cloned(),
+                _ => None,
+            },
+            _ => None,
+        };
+
+        match (transformer, form) {
+            (Some(transformer), LispValue::List(mut values)) => {
+                values.remove(0);
+                form = apply_transformer(&transformer, values, state)?;
+            }
+            (_, form) => return Ok(form),
+        }
+    }
+
+    Err(EvaluationError::ExpansionLimit)
+}
+
+/// Expands all macro calls in an expression. Macros are expanded until
+/// none are left, so they may expand to other macro calls. Quoted data is
+/// never expanded.
+pub fn expand(expr: LispExpr, state: &mut State) -> EvaluationResult<LispExpr> {
+    expand_expr(expr, state, &mut Vec::new(), 0)
+}
+
+/// The depth is the number of macro expansions that the expression is
+/// nested in. Successive expansions of the same call are done in a loop,
+/// only expansions nested in arguments use the stack.
+fn expand_expr(
+    mut expr: LispExpr,
+    state: &mut State,
+    bound: &mut Vec<InternedString>,
+    mut depth: usize,
+) -> EvaluationResult<LispExpr> {
+    loop {
+        let exprs = match expr {
+            LispExpr::Call(exprs) => exprs,
+            other => return Ok(other),
+        };
+
+        let transformer = match exprs.first() {
+            Some(&LispExpr::OpVar(n)) if !bound.contains(&n) => state.get_macro(n).cloned(),
+            _ => None,
+        };
+
+        let transformer = match transformer {
+            Some(transformer) => transformer,
+            None => return expand_form(exprs, state, bound, depth),
+        };
+
+        if depth >= EXPANSION_LIMIT {
+            return Err(EvaluationError::ExpansionLimit);
+        }
+
+        let args = exprs
+            .into_iter()
+            .skip(1)
+            .map(|e| e.into_code_datum(state))
+            .collect();
+        let expansion = match transformer {
+            Transformer::Rules(ref rules) => rules.expand(args, |name| match name {
+                // Names bound by the template get fresh names, so that
+                // they cannot capture names in the arguments
+                TemplateName::Binder(n) => LispValue::Symbol(state.gensym(n)),
+                // Free names refer to global definitions, even where the
+                // use of the macro binds them
+                TemplateName::Free(n) if bound.contains(&n) => match state.get(n).cloned() {
+                    Some(value) => quote_value(value, state),
+                    None => LispValue::Symbol(n),
+                },
+                TemplateName::Free(n) => LispValue::Symbol(n),
+            })?,
+            _ => apply_transformer(&transformer, args, state)?,
+        };
+
+        expr = read_datum(expansion, state);
+        depth += 1;
+    }
+}
+
+/// Expands the macro calls inside a form that is not a macro call.
+fn expand_form(
+    exprs: Vec<LispExpr>,
+    state: &mut State,
+    bound: &mut Vec<InternedString>,
+    depth: usize,
+) -> EvaluationResult<LispExpr> {
+    match exprs.first() {
+        Some(&LispExpr::Macro(LispMacro::Quote))
+        | Some(&LispExpr::Macro(LispMacro::DefineSyntax)) => Ok(LispExpr::Call(exprs)),
+        Some(&LispExpr::Macro(LispMacro::Quasiquote)) => exprs
+            .into_iter()
+            .map(|e| expand_template(e, state, bound, depth))
+            .collect::<EvaluationResult<_>>()
+            .map(LispExpr::Call),
+        Some(&LispExpr::Macro(mac @ LispMacro::Lambda))
+        | Some(&LispExpr::Macro(mac @ LispMacro::Defmacro)) => {
+            expand_lambda(exprs, mac, state, bound, depth)
+        }
+        Some(&LispExpr::Macro(LispMacro::Match)) => expand_match(exprs, state, bound, depth),
+        Some(&LispExpr::Macro(mac @ LispMacro::Let))
+        | Some(&LispExpr::Macro(mac @ LispMacro::LetStar))
+        | Some(&LispExpr::Macro(mac @ LispMacro::Letrec)) => {
+            expand_let(exprs, mac, state, bound, depth)
+        }
+        _ => exprs
+            .into_iter()
+            .map(|e| expand_expr(e, state, bound, depth))
+            .collect::<EvaluationResult<_>>()
+            .map(LispExpr::Call),
+    }
+}
+
+/// Expands a lambda or macro definition. Arguments shadow macros within
+/// the body.
+fn expand_lambda(
+    mut exprs: Vec<LispExpr>,
+    mac: LispMacro,
+    state: &mut State,
+    bound: &mut Vec<InternedString>,
+    depth: usize,
+) -> EvaluationResult<LispExpr> {
+    // The name of a macro precedes its argument list
+    let arg_index = if mac == LispMacro::Lambda { 1 } else { 2 };
+
+    if exprs.len() <= arg_index {
+        return Ok(LispExpr::Call(exprs));
+    }
+
+    // Default values of optional arguments are evaluated outside
+    // the body
+    if let Some(&mut LispExpr::Call(ref mut args)) = exprs.get_mut(arg_index) {
+        for arg in args.iter_mut() {
+            if let LispExpr::Call(ref mut pair) = *arg {
+                if pair.len() == 2 {
+                    let default = pair.pop().unwrap();
+                    pair.push(expand_expr(default, state, bound, depth)?);
+                }
+            }
+        }
+    }
+
+    let bound_len = bound.len();
+
+    match exprs.get(arg_index) {
+        Some(LispExpr::Call(args)) => bound.extend(args.iter().filter_map(|arg| match *arg {
+            LispExpr::OpVar(n) => Some(n),
+            LispExpr::Call(ref pair) => match pair.first() {
+                Some(&LispExpr::OpVar(n)) => Some(n),
+                _ => None,
+            },
+            _ => None,
+        })),
+        Some(&LispExpr::OpVar(n)) => bound.push(n),
+        _ => {}
+    }
+
+    // So do definitions inside the body
+    bound.extend(
+        exprs[arg_index + 1..]
+            .iter()
+            .filter_map(|expr| match *expr {
+                LispExpr::Call(ref def)
+                    if def.first() == Some(&LispExpr::Macro(LispMacro::Define)) =>
+                {
+                    match def.get(1) {
+                        Some(&LispExpr::OpVar(n)) => Some(n),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            }),
+    );
+
+    let body = exprs.split_off(arg_index + 1);
+
+    for expr in body {
+        let expanded = expand_expr(expr, state, bound, depth)?;
+        exprs.push(expanded);
+    }
+
+    bound.truncate(bound_len);
+    Ok(LispExpr::Call(exprs))
+}
+
+/// Expands a match expression. Names in patterns shadow macros within
+/// their clause.
+fn expand_match(
+    mut exprs: Vec<LispExpr>,
+    state: &mut State,
+    bound: &mut Vec<InternedString>,
+    depth: usize,
+) -> EvaluationResult<LispExpr> {
+    if exprs.len() < 2 {
+        return Ok(LispExpr::Call(exprs));
+    }
+
+    let clauses = exprs.split_off(2);
+    let value = exprs.pop().unwrap();
+    exprs.push(expand_expr(value, state, bound, depth)?);
+
+    for clause in clauses {
+        exprs.push(match clause {
+            LispExpr::Call(mut clause) if !clause.is_empty() => {
+                let bound_len = bound.len();
+                let body = clause.split_off(1);
+                resolve_constructors(&mut clause[0], state, bound);
+                pattern_names(&clause[0], bound);
+
+                for expr in body {
+                    let expanded = expand_expr(expr, state, bound, depth)?;
+                    clause.push(expanded);
+                }
+
+                bound.truncate(bound_len);
+                LispExpr::Call(clause)
+            }
+            other => other,
+        });
+    }
+
+    Ok(LispExpr::Call(exprs))
+}
+
+/// Expands a let, let* or letrec expression. Local variables shadow
+/// macros within the body. The variables of let* are also bound in later
+/// values and those of letrec in all values.
+fn expand_let(
+    mut exprs: Vec<LispExpr>,
+    mac: LispMacro,
+    state: &mut State,
+    bound: &mut Vec<InternedString>,
+    depth: usize,
+) -> EvaluationResult<LispExpr> {
+    if exprs.len() < 2 {
+        return Ok(LispExpr::Call(exprs));
+    }
+
+    let bound_len = bound.len();
+    let body = exprs.split_off(2);
+
+    match exprs.pop() {
+        Some(LispExpr::Call(bindings)) => {
+            let names: Vec<_> = bindings
+                .iter()
+                .filter_map(|binding| match *binding {
+                    LispExpr::Call(ref binding) => match binding.first() {
+                        Some(&LispExpr::OpVar(n)) => Some(n),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect();
+
+            if mac == LispMacro::Letrec {
+                bound.extend(names.iter().cloned());
+            }
+
+            let mut expanded_bindings = Vec::with_capacity(bindings.len());
+
+            for binding in bindings {
+                expanded_bindings.push(match binding {
+                    LispExpr::Call(mut binding) if binding.len() == 2 => {
+                        let value = binding.pop().unwrap();
+                        binding.push(expand_expr(value, state, bound, depth)?);
+
+                        if let (LispMacro::LetStar, Some(&LispExpr::OpVar(n))) =
+                            (mac, binding.first())
+                        {
+                            bound.push(n);
+                        }
+
+                        LispExpr::Call(binding)
+                    }
+                    other => other,
+                });
+            }
+
+            if mac == LispMacro::Let {
+                bound.extend(names);
+            }
+
+            exprs.push(LispExpr::Call(expanded_bindings));
+        }
+        Some(other) => exprs.push(other),
+        None => unreachable!(),
+    }
+
+    for expr in body {
+        let expanded = expand_expr(expr, state, bound, depth)?;
+        exprs.push(expanded);
+    }
+
+    bound.truncate(bound_len);
+    Ok(LispExpr::Call(exprs))
+}
+
+/// Expands the unquoted expressions in a quasiquote template.
+fn expand_template(
+    expr: LispExpr,
+    state: &mut State,
+    bound: &mut Vec<InternedString>,
+    depth: usize,
+) -> EvaluationResult<LispExpr> {
+    match expr {
+        LispExpr::Call(exprs) => {
+            let is_unquote = matches!(
+                exprs.first(),
+                Some(&LispExpr::Macro(LispMacro::Unquote))
+                    | Some(&LispExpr::Macro(LispMacro::UnquoteSplicing))
+            );
+
+            exprs
+                .into_iter()
+                .map(|e| {
+                    if is_unquote {
+                        expand_expr(e, state, bound, depth)
+                    } else {
+                        expand_template(e, state, bound, depth)
+                    }
+                })
+                .collect::<EvaluationResult<_>>()
+                .map(LispExpr::Call)
+        }
+        other => Ok(other),
+    }
+}
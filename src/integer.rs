@@ -0,0 +1,99 @@
+use super::{EvaluationError, EvaluationResult, LispValue};
+use num_bigint::BigInt;
+use num_traits::{ToPrimitive, Zero};
+use std::cmp::Ordering;
+use std::mem::replace;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Operation {
+    Add,
+    Sub,
+    Mul,
+    Quotient,
+    Remainder,
+}
+
+/// Converts a big integer to a lisp value. Integers that fit in 64 bits are
+/// always represented by `LispValue::Integer`, so that there is only a
+/// single representation for every number.
+pub fn normalize(i: BigInt) -> LispValue {
+    match i.to_i64() {
+        Some(small) => LispValue::Integer(small),
+        None => LispValue::BigInteger(i),
+    }
+}
+
+fn into_big(value: LispValue) -> EvaluationResult<BigInt> {
+    match value {
+        LispValue::Integer(i) => Ok(BigInt::from(i)),
+        LispValue::BigInteger(i) => Ok(i),
+        _ => Err(EvaluationError::ArgumentTypeMismatch),
+    }
+}
+
+fn apply_big(op: Operation, x: BigInt, y: BigInt) -> EvaluationResult<LispValue> {
+    if y.is_zero() && (op == Operation::Quotient || op == Operation::Remainder) {
+        return Err(EvaluationError::DivisionByZero);
+    }
+
+    Ok(normalize(match op {
+        Operation::Add => x + y,
+        Operation::Sub => x - y,
+        Operation::Mul => x * y,
+        Operation::Quotient => x / y,
+        Operation::Remainder => x % y,
+    }))
+}
+
+/// Applies a binary operation to two integers. Operations are done on
+/// 64-bit integers whenever possible and only fall back to big integers
+/// when the result does not fit.
+pub fn apply(op: Operation, x: LispValue, y: LispValue) -> EvaluationResult<LispValue> {
+    if let (&LispValue::Integer(a), &LispValue::Integer(b)) = (&x, &y) {
+        let result = match op {
+            Operation::Add => a.checked_add(b),
+            Operation::Sub => a.checked_sub(b),
+            Operation::Mul => a.checked_mul(b),
+            Operation::Quotient if b == 0 => return Err(EvaluationError::DivisionByZero),
+            Operation::Quotient => a.checked_div(b),
+            Operation::Remainder if b == 0 => return Err(EvaluationError::DivisionByZero),
+            Operation::Remainder => a.checked_rem(b),
+        };
+
+        if let Some(i) = result {
+            return Ok(LispValue::Integer(i));
+        }
+    }
+
+    apply_big(op, into_big(x)?, into_big(y)?)
+}
+
+/// Adds `delta` to an integer value in place.
+pub fn increment(value: &mut LispValue, delta: i64) -> EvaluationResult<()> {
+    if let LispValue::Integer(ref mut i) = *value {
+        if let Some(result) = i.checked_add(delta) {
+            *i = result;
+            return Ok(());
+        }
+    }
+
+    let old = replace(value, LispValue::Boolean(false));
+    *value = apply(Operation::Add, old, LispValue::Integer(delta))?;
+    Ok(())
+}
+
+pub fn is_zero(value: &LispValue) -> EvaluationResult<bool> {
+    match *value {
+        LispValue::Integer(i) => Ok(i == 0),
+        // Big integers are never small enough to be zero
+        LispValue::BigInteger(..) => Ok(false),
+        _ => Err(EvaluationError::ArgumentTypeMismatch),
+    }
+}
+
+pub fn compare(x: &LispValue, y: &LispValue) -> EvaluationResult<Ordering> {
+    match (x, y) {
+        (&LispValue::Integer(a), &LispValue::Integer(b)) => Ok(a.cmp(&b)),
+        _ => Ok(into_big(x.clone())?.cmp(&into_big(y.clone())?)),
+    }
+}
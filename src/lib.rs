@@ -6,22 +6,29 @@ extern crate quickcheck;
 #[macro_use(quickcheck)]
 extern crate quickcheck_macros;
 
+extern crate num_bigint;
+extern crate num_traits;
 extern crate string_interner;
 #[cfg(test)]
 extern crate test;
 
 pub mod evaluator;
+mod expand;
+mod integer;
 pub mod parse;
 pub mod print;
 
+use num_bigint::BigInt;
 use std::cell::UnsafeCell;
+use std::cmp::Ordering;
 use std::collections::hash_map;
 use std::collections::HashMap;
 use std::convert::From;
 use std::fmt;
-use std::mem::{replace, transmute_copy};
+use std::mem::{replace, take, transmute_copy};
 use std::ops::{Add, Index, Sub};
 use std::sync::Arc;
+use std::vec;
 use string_interner::StringInterner;
 
 macro_rules! destructure {
@@ -40,7 +47,7 @@ macro_rules! destructure {
     };
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug, PartialOrd, Ord)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, PartialOrd, Ord, Default)]
 struct StackOffset(u32);
 
 impl StackOffset {
@@ -61,12 +68,6 @@ impl From<usize> for StackOffset {
     }
 }
 
-impl Default for StackOffset {
-    fn default() -> Self {
-        StackOffset(0)
-    }
-}
-
 impl Add for StackOffset {
     type Output = Self;
 
@@ -85,9 +86,45 @@ impl Sub for StackOffset {
 
 type EvaluationResult<T> = Result<T, EvaluationError>;
 
+/// The number of arguments a function takes. Optional arguments follow the
+/// required ones, and a rest argument collects any remaining arguments in
+/// a list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Arity {
+    required: usize,
+    optional: usize,
+    rest: bool,
+}
+
+impl Arity {
+    fn fixed(count: usize) -> Self {
+        Arity {
+            required: count,
+            optional: 0,
+            rest: false,
+        }
+    }
+
+    fn is_fixed(self) -> bool {
+        self.optional == 0 && !self.rest
+    }
+
+    /// The number of values on the stack for the arguments once the function
+    /// is evaluated
+    fn slots(self) -> usize {
+        self.required + self.optional + self.rest as usize
+    }
+
+    fn accepts(self, arg_count: usize) -> bool {
+        arg_count >= self.required && (self.rest || arg_count <= self.required + self.optional)
+    }
+}
+
 #[derive(Debug)]
 struct InnerCustomFunc {
-    arg_count: usize,
+    arity: Arity,
+    /// Values of the optional arguments when they are not supplied
+    defaults: Vec<LispValue>,
     body: FinalizedExpr,
     returns: bool,
     byte_code: UnsafeCell<Vec<Instr>>,
@@ -101,13 +138,23 @@ unsafe impl Sync for InnerCustomFunc {}
 pub struct State {
     interns: StringInterner<InternedString>,
     store: HashMap<InternedString, LispValue>,
+    macros: HashMap<InternedString, expand::Transformer>,
 }
 
 impl Default for State {
     fn default() -> Self {
+        let mut interns = StringInterner::new();
+
+        // Interned up front, so that errors can be turned into symbols
+        // while evaluating
+        for &kind in ERROR_KINDS.iter() {
+            interns.get_or_intern(kind);
+        }
+
         Self {
-            interns: StringInterner::new(),
+            interns,
             store: HashMap::new(),
+            macros: HashMap::new(),
         }
     }
 }
@@ -146,6 +193,99 @@ impl State {
         }
     }
 
+    /// Returns a fresh symbol based on the given name, which differs from
+    /// every symbol seen so far.
+    fn gensym(&mut self, name: InternedString) -> InternedString {
+        let base = self.resolve_intern(name).to_owned();
+        let mut suffix = self.interns.len();
+
+        loop {
+            let candidate = format!("{}%{}", base, suffix);
+
+            if self.interns.get(candidate.as_str()).is_none() {
+                return self.intern(candidate);
+            }
+
+            suffix += 1;
+        }
+    }
+
+    /// Returns the value passed to the handler of a try expression for
+    /// the given error: the raised value itself, or the symbol naming the
+    /// error for errors of built-in functions.
+    fn error_value(&self, error: EvaluationError) -> LispValue {
+        match error {
+            EvaluationError::Raised(value) => value,
+            other => LispValue::Symbol(self.interns.get(other.kind()).unwrap()),
+        }
+    }
+
+    fn get_macro(&self, name: InternedString) -> Option<&expand::Transformer> {
+        self.macros.get(&name)
+    }
+
+    /// Registers a macro. Like variables, macros cannot be redefined.
+    fn set_macro(
+        &mut self,
+        name: InternedString,
+        transformer: expand::Transformer,
+    ) -> EvaluationResult<()> {
+        match self.macros.entry(name) {
+            hash_map::Entry::Occupied(..) => Err(EvaluationError::BadDefine),
+            hash_map::Entry::Vacant(vac_entry) => {
+                vac_entry.insert(transformer);
+                Ok(())
+            }
+        }
+    }
+
+    /// Defines the constructor, predicate and field accessors of a record
+    /// type. Like variables, none of them may have been defined before.
+    fn define_record(
+        &mut self,
+        name: InternedString,
+        fields: &[InternedString],
+    ) -> EvaluationResult<()> {
+        let type_name = self.resolve_intern(name).to_owned();
+        let mut definitions = vec![
+            (
+                format!("make-{}", type_name),
+                BuiltIn::MakeRecord(name, fields.len()),
+            ),
+            (format!("{}?", type_name), BuiltIn::CheckRecord(name)),
+        ];
+
+        for (index, &field) in fields.iter().enumerate() {
+            definitions.push((
+                format!("{}-{}", type_name, self.resolve_intern(field)),
+                BuiltIn::RecordField(name, field, index),
+            ));
+        }
+
+        // Built-in functions and keywords cannot be redefined either
+        if definitions.iter().any(|(function_name, _)| {
+            BuiltIn::from_str(function_name).is_some()
+                || LispMacro::from_str(function_name).is_some()
+        }) {
+            return Err(EvaluationError::BadDefine);
+        }
+
+        let definitions: Vec<_> = definitions
+            .into_iter()
+            .map(|(function_name, f)| (self.intern(function_name), f))
+            .collect();
+
+        if definitions.iter().any(|&(n, _)| self.get(n).is_some()) {
+            return Err(EvaluationError::BadDefine);
+        }
+
+        for (n, f) in definitions {
+            self.set_variable(n, LispValue::Function(LispFunc::BuiltIn(f)), false)?;
+        }
+
+        Ok(())
+    }
+
     pub fn get_variable_keys(&self) -> Vec<String> {
         self.store
             .keys()
@@ -193,7 +333,8 @@ impl CustomFunc {
 
     fn from_byte_code(arg_count: usize, bytecode: Vec<Instr>) -> Self {
         CustomFunc(Arc::new(InnerCustomFunc {
-            arg_count: arg_count,
+            arity: Arity::fixed(arg_count),
+            defaults: Vec::new(),
             // dummy value
             body: FinalizedExpr::Value(LispValue::Boolean(false)),
             returns: true,
@@ -202,7 +343,7 @@ impl CustomFunc {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
 struct Scope(u32);
 
 impl fmt::Display for Scope {
@@ -211,12 +352,6 @@ impl fmt::Display for Scope {
     }
 }
 
-impl Default for Scope {
-    fn default() -> Self {
-        Scope(0)
-    }
-}
-
 impl Scope {
     fn next(self) -> Self {
         Scope(self.0 + 1)
@@ -234,6 +369,38 @@ pub enum BuiltIn {
     CheckZero,
     CheckNull,
     CheckType(ArgType),
+    Add,
+    Sub,
+    Mul,
+    Quotient,
+    Remainder,
+    Compare(Comparison),
+    StringLength,
+    StringAppend,
+    Substring,
+    StringEqual,
+    StringToList,
+    NumberToString,
+    CharToInteger,
+    IntegerToChar,
+    CharUpcase,
+    CheckAlphabetic,
+    /// Compares atoms only, as lists and records have no identity
+    CheckEq,
+    CheckEqual,
+    /// Concatenates lists. It has no name, so that it can only be used
+    /// by quasiquote templates, and is printed as `#%append`, which
+    /// cannot be read back or clash with a user definition.
+    Append,
+    MacroExpand,
+    /// Creates a record of the given type from the given number of fields
+    MakeRecord(InternedString, usize),
+    /// Checks whether a value is a record of the given type
+    CheckRecord(InternedString),
+    /// Gets a field of a record of the given type. Holds the name and the
+    /// index of the field.
+    RecordField(InternedString, InternedString, usize),
+    Raise,
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
@@ -242,9 +409,74 @@ pub enum ArgType {
     Boolean,
     Function,
     List,
+    String,
+    Char,
+    Symbol,
+    Record,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Comparison {
+    Equal,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl Comparison {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Equal => ordering == Ordering::Equal,
+            Comparison::Less => ordering == Ordering::Less,
+            Comparison::LessEqual => ordering != Ordering::Greater,
+            Comparison::Greater => ordering == Ordering::Greater,
+            Comparison::GreaterEqual => ordering != Ordering::Less,
+        }
+    }
 }
 
 impl BuiltIn {
+    /// The number of arguments of functions that do not take a variable
+    /// number of them
+    fn arity(self) -> Option<usize> {
+        match self {
+            BuiltIn::List
+            | BuiltIn::Add
+            | BuiltIn::Sub
+            | BuiltIn::Mul
+            | BuiltIn::Compare(..)
+            | BuiltIn::StringAppend
+            | BuiltIn::StringEqual
+            | BuiltIn::Append => None,
+            BuiltIn::Cons
+            | BuiltIn::Quotient
+            | BuiltIn::Remainder
+            | BuiltIn::CheckEq
+            | BuiltIn::CheckEqual => Some(2),
+            BuiltIn::Substring => Some(3),
+            BuiltIn::MakeRecord(_, count) => Some(count),
+            BuiltIn::AddOne
+            | BuiltIn::SubOne
+            | BuiltIn::Cdr
+            | BuiltIn::Car
+            | BuiltIn::CheckZero
+            | BuiltIn::CheckNull
+            | BuiltIn::CheckType(..)
+            | BuiltIn::StringLength
+            | BuiltIn::StringToList
+            | BuiltIn::NumberToString
+            | BuiltIn::CharToInteger
+            | BuiltIn::IntegerToChar
+            | BuiltIn::CharUpcase
+            | BuiltIn::CheckAlphabetic
+            | BuiltIn::MacroExpand
+            | BuiltIn::CheckRecord(..)
+            | BuiltIn::RecordField(..)
+            | BuiltIn::Raise => Some(1),
+        }
+    }
+
     fn from_str(s: &str) -> Option<BuiltIn> {
         match s {
             "add1" => Some(BuiltIn::AddOne),
@@ -259,6 +491,34 @@ impl BuiltIn {
             "bool?" => Some(BuiltIn::CheckType(ArgType::Boolean)),
             "list?" => Some(BuiltIn::CheckType(ArgType::List)),
             "fun?" => Some(BuiltIn::CheckType(ArgType::Function)),
+            "string?" => Some(BuiltIn::CheckType(ArgType::String)),
+            "char?" => Some(BuiltIn::CheckType(ArgType::Char)),
+            "symbol?" => Some(BuiltIn::CheckType(ArgType::Symbol)),
+            "record?" => Some(BuiltIn::CheckType(ArgType::Record)),
+            "+" => Some(BuiltIn::Add),
+            "-" => Some(BuiltIn::Sub),
+            "*" => Some(BuiltIn::Mul),
+            "quotient" => Some(BuiltIn::Quotient),
+            "remainder" => Some(BuiltIn::Remainder),
+            "=" => Some(BuiltIn::Compare(Comparison::Equal)),
+            "<" => Some(BuiltIn::Compare(Comparison::Less)),
+            "<=" => Some(BuiltIn::Compare(Comparison::LessEqual)),
+            ">" => Some(BuiltIn::Compare(Comparison::Greater)),
+            ">=" => Some(BuiltIn::Compare(Comparison::GreaterEqual)),
+            "string-length" => Some(BuiltIn::StringLength),
+            "string-append" => Some(BuiltIn::StringAppend),
+            "substring" => Some(BuiltIn::Substring),
+            "string=?" => Some(BuiltIn::StringEqual),
+            "string->list" => Some(BuiltIn::StringToList),
+            "number->string" => Some(BuiltIn::NumberToString),
+            "char->integer" => Some(BuiltIn::CharToInteger),
+            "integer->char" => Some(BuiltIn::IntegerToChar),
+            "char-upcase" => Some(BuiltIn::CharUpcase),
+            "char-alphabetic?" => Some(BuiltIn::CheckAlphabetic),
+            "eq?" => Some(BuiltIn::CheckEq),
+            "equal?" => Some(BuiltIn::CheckEqual),
+            "macroexpand" => Some(BuiltIn::MacroExpand),
+            "raise" => Some(BuiltIn::Raise),
             _ => None,
         }
     }
@@ -281,6 +541,40 @@ impl fmt::Display for BuiltIn {
             BuiltIn::CheckType(ArgType::Boolean) => "bool?",
             BuiltIn::CheckType(ArgType::Integer) => "int?",
             BuiltIn::CheckType(ArgType::List) => "list?",
+            BuiltIn::CheckType(ArgType::String) => "string?",
+            BuiltIn::CheckType(ArgType::Char) => "char?",
+            BuiltIn::CheckType(ArgType::Symbol) => "symbol?",
+            BuiltIn::CheckType(ArgType::Record) => "record?",
+            BuiltIn::Add => "+",
+            BuiltIn::Sub => "-",
+            BuiltIn::Mul => "*",
+            BuiltIn::Quotient => "quotient",
+            BuiltIn::Remainder => "remainder",
+            BuiltIn::Compare(Comparison::Equal) => "=",
+            BuiltIn::Compare(Comparison::Less) => "<",
+            BuiltIn::Compare(Comparison::LessEqual) => "<=",
+            BuiltIn::Compare(Comparison::Greater) => ">",
+            BuiltIn::Compare(Comparison::GreaterEqual) => ">=",
+            BuiltIn::StringLength => "string-length",
+            BuiltIn::StringAppend => "string-append",
+            BuiltIn::Substring => "substring",
+            BuiltIn::StringEqual => "string=?",
+            BuiltIn::StringToList => "string->list",
+            BuiltIn::NumberToString => "number->string",
+            BuiltIn::CharToInteger => "char->integer",
+            BuiltIn::IntegerToChar => "integer->char",
+            BuiltIn::CharUpcase => "char-upcase",
+            BuiltIn::CheckAlphabetic => "char-alphabetic?",
+            BuiltIn::CheckEq => "eq?",
+            BuiltIn::CheckEqual => "equal?",
+            BuiltIn::Append => "#%append",
+            BuiltIn::MacroExpand => "macroexpand",
+            BuiltIn::Raise => "raise",
+            // The functions of record types are named after their type,
+            // which is printed instead
+            BuiltIn::MakeRecord(..) => "make-record",
+            BuiltIn::CheckRecord(..) => "record-type?",
+            BuiltIn::RecordField(..) => "record-field",
         };
 
         write!(f, "{}", str)
@@ -294,34 +588,61 @@ pub enum LispFunc {
 }
 
 impl LispFunc {
-    fn new_custom(arg_count: usize, body: FinalizedExpr, returns: bool) -> LispFunc {
+    fn new_custom(
+        arity: Arity,
+        defaults: Vec<LispValue>,
+        body: FinalizedExpr,
+        returns: bool,
+    ) -> LispFunc {
         LispFunc::Custom(CustomFunc(Arc::new(InnerCustomFunc {
-            arg_count,
+            arity,
+            defaults,
             body,
             returns,
             byte_code: UnsafeCell::new(Vec::new()),
         })))
     }
 
+    /// Creates a function that takes the remaining arguments of `f` and
+    /// then evaluates it. It has the same optional and rest arguments as `f`.
     fn curry<I: Iterator<Item = LispValue>>(
         f: CustomFunc,
-        total_args: usize,
         supplied_args: usize,
         args: I,
     ) -> LispFunc {
-        let arg_count = total_args - supplied_args;
+        let arity = f.0.arity;
+        let remaining = Arity {
+            required: arity.required - supplied_args,
+            ..arity
+        };
+        // The new function completes the optional and rest arguments, so it
+        // calls a copy of `f` that takes their values as they are
+        let (f, defaults) = if arity.is_fixed() {
+            (f, Vec::new())
+        } else {
+            let completed = CustomFunc(Arc::new(InnerCustomFunc {
+                arity: Arity::fixed(arity.slots()),
+                defaults: Vec::new(),
+                body: f.0.body.clone(),
+                returns: f.0.returns,
+                byte_code: UnsafeCell::new(Vec::new()),
+            }));
+
+            (completed, f.0.defaults.clone())
+        };
         let funk = Box::new(FinalizedExpr::Value(LispValue::Function(LispFunc::Custom(
             f,
         ))));
         let arg_vec = args
             .map(FinalizedExpr::Value)
-            .chain((0..total_args - supplied_args).map(From::from).map(|o| {
+            .chain((0..remaining.slots()).map(From::from).map(|o| {
                 FinalizedExpr::Argument(o, Scope::default(), VariableConstraint::Unconstrained)
             }))
             .collect();
 
         Self::new_custom(
-            arg_count,
+            remaining,
+            defaults,
             FinalizedExpr::FunctionCall(funk, arg_vec, true, false),
             false,
         )
@@ -333,6 +654,30 @@ pub enum LispMacro {
     Define,
     Cond,
     Lambda,
+    Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
+    Defmacro,
+    DefineSyntax,
+    DefineRecord,
+    SyntaxRules,
+    Let,
+    LetStar,
+    Letrec,
+    Begin,
+    If,
+    When,
+    Unless,
+    Else,
+    Arrow,
+    And,
+    Or,
+    Dot,
+    Optional,
+    Match,
+    Wildcard,
+    Try,
 }
 
 impl LispMacro {
@@ -341,17 +686,83 @@ impl LispMacro {
             "define" => Some(LispMacro::Define),
             "cond" => Some(LispMacro::Cond),
             "lambda" => Some(LispMacro::Lambda),
+            "quote" => Some(LispMacro::Quote),
+            "quasiquote" => Some(LispMacro::Quasiquote),
+            "unquote" => Some(LispMacro::Unquote),
+            "unquote-splicing" => Some(LispMacro::UnquoteSplicing),
+            "defmacro" => Some(LispMacro::Defmacro),
+            "define-syntax" => Some(LispMacro::DefineSyntax),
+            "define-record" => Some(LispMacro::DefineRecord),
+            "syntax-rules" => Some(LispMacro::SyntaxRules),
+            "let" => Some(LispMacro::Let),
+            "let*" => Some(LispMacro::LetStar),
+            "letrec" => Some(LispMacro::Letrec),
+            "begin" => Some(LispMacro::Begin),
+            "if" => Some(LispMacro::If),
+            "when" => Some(LispMacro::When),
+            "unless" => Some(LispMacro::Unless),
+            "else" => Some(LispMacro::Else),
+            "=>" => Some(LispMacro::Arrow),
+            "and" => Some(LispMacro::And),
+            "or" => Some(LispMacro::Or),
+            "." => Some(LispMacro::Dot),
+            "#!optional" => Some(LispMacro::Optional),
+            "match" => Some(LispMacro::Match),
+            "_" => Some(LispMacro::Wildcard),
+            "try" => Some(LispMacro::Try),
             _ => None,
         }
     }
 }
 
+impl fmt::Display for LispMacro {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let str = match *self {
+            LispMacro::Define => "define",
+            LispMacro::Cond => "cond",
+            LispMacro::Lambda => "lambda",
+            LispMacro::Quote => "quote",
+            LispMacro::Quasiquote => "quasiquote",
+            LispMacro::Unquote => "unquote",
+            LispMacro::UnquoteSplicing => "unquote-splicing",
+            LispMacro::Defmacro => "defmacro",
+            LispMacro::DefineSyntax => "define-syntax",
+            LispMacro::DefineRecord => "define-record",
+            LispMacro::SyntaxRules => "syntax-rules",
+            LispMacro::Let => "let",
+            LispMacro::LetStar => "let*",
+            LispMacro::Letrec => "letrec",
+            LispMacro::Begin => "begin",
+            LispMacro::If => "if",
+            LispMacro::When => "when",
+            LispMacro::Unless => "unless",
+            LispMacro::Else => "else",
+            LispMacro::Arrow => "=>",
+            LispMacro::And => "and",
+            LispMacro::Or => "or",
+            LispMacro::Dot => ".",
+            LispMacro::Optional => "#!optional",
+            LispMacro::Match => "match",
+            LispMacro::Wildcard => "_",
+            LispMacro::Try => "try",
+        };
+
+        write!(f, "{}", str)
+    }
+}
+
 /// In our implementation, defines can only happen at the top level of
 /// an expression. To enforce in the types, `FinalizedExpr` does not contain
 /// a Define variant.
 #[derive(Debug, Clone)]
 enum TopExpr {
     Define(InternedString, LispExpr),
+    /// Name of the macro and the lambda expression of its transformer
+    DefineMacro(InternedString, LispExpr),
+    /// Name of the macro and its syntax-rules specification
+    DefineSyntax(InternedString, LispExpr),
+    /// Name of the record type and the names of its fields
+    DefineRecord(InternedString, Vec<InternedString>),
     /// Bool states whether or not the expression always returns
     Regular(FinalizedExpr, bool),
 }
@@ -359,8 +770,9 @@ enum TopExpr {
 #[derive(Debug, PartialEq, Eq, Clone)]
 /// TODO: explain how `FinalizedExpression` is different from `LispExpr`
 enum FinalizedExpr {
-    // Arg count, scope level, body, returns
-    Lambda(usize, Scope, Box<FinalizedExpr>, bool),
+    // Arity, scope level, body, returns, default values of the optional
+    // arguments
+    Lambda(Arity, Scope, Box<FinalizedExpr>, bool, Vec<FinalizedExpr>),
     // test expr, true branch, false branch.
     // true branch returns, tail call status of cond
     // TODO: clean up this variant.
@@ -374,6 +786,22 @@ enum FinalizedExpr {
     Argument(StackOffset, Scope, VariableConstraint),
     // function, arguments, tail-call, self-call
     FunctionCall(Box<FinalizedExpr>, Vec<FinalizedExpr>, bool, bool),
+    // scope and offset of the first local variable, values of the local
+    // variables, body
+    Let(Scope, StackOffset, Vec<FinalizedExpr>, Box<FinalizedExpr>),
+    // expressions that are evaluated in order. Only the value of the last
+    // is kept
+    Begin(Vec<FinalizedExpr>),
+    // offset of a local variable, the path to a part of it and the test on
+    // that part
+    TestPart(StackOffset, ListPath, PartTest),
+    // offset of a local variable, the path to a part of it and whether the
+    // part is moved out of the variable instead of cloned
+    Part(StackOffset, ListPath, bool),
+    // fails because no clause of a match expression applies
+    NoMatch,
+    // handler, body. The handler is applied to the values raised by the body
+    Try(Box<FinalizedExpr>, Box<FinalizedExpr>),
 }
 
 impl FinalizedExpr {
@@ -386,7 +814,7 @@ impl FinalizedExpr {
                     BuiltIn::SubOne,
                 ))) = *f
                 {
-                    if let Some(&FinalizedExpr::Argument(e_offset, e_scope, _)) = args.get(0) {
+                    if let Some(&FinalizedExpr::Argument(e_offset, e_scope, _)) = args.first() {
                         if args.len() == 1 && offset == e_offset && e_scope == scope {
                             return args.into_iter().next().unwrap();
                         }
@@ -416,9 +844,35 @@ impl FinalizedExpr {
                     tail_call_status,
                 )
             }
-            FinalizedExpr::Lambda(a, b, body, returns) => {
-                FinalizedExpr::Lambda(a, b, Box::new(body.remove_subs_of(offset, scope)), returns)
-            }
+            FinalizedExpr::Lambda(a, b, body, returns, defaults) => FinalizedExpr::Lambda(
+                a,
+                b,
+                Box::new(body.remove_subs_of(offset, scope)),
+                returns,
+                defaults
+                    .into_iter()
+                    .map(|d| d.remove_subs_of(offset, scope))
+                    .collect(),
+            ),
+            FinalizedExpr::Let(let_scope, let_offset, values, body) => FinalizedExpr::Let(
+                let_scope,
+                let_offset,
+                values
+                    .into_iter()
+                    .map(|v| v.remove_subs_of(offset, scope))
+                    .collect(),
+                Box::new(body.remove_subs_of(offset, scope)),
+            ),
+            FinalizedExpr::Begin(exprs) => FinalizedExpr::Begin(
+                exprs
+                    .into_iter()
+                    .map(|e| e.remove_subs_of(offset, scope))
+                    .collect(),
+            ),
+            FinalizedExpr::Try(handler, body) => FinalizedExpr::Try(
+                Box::new(handler.remove_subs_of(offset, scope)),
+                Box::new(body.remove_subs_of(offset, scope)),
+            ),
             x => x,
         }
     }
@@ -436,9 +890,16 @@ impl FinalizedExpr {
                     && true_expr.only_use_after_sub(offset, scope, false)
                     && false_expr.only_use_after_sub(offset, scope, false)
             }
-            FinalizedExpr::Variable(..) | FinalizedExpr::Value(..) => true,
-            FinalizedExpr::Lambda(_, _, ref body, _) => {
+            FinalizedExpr::Variable(..)
+            | FinalizedExpr::Value(..)
+            | FinalizedExpr::TestPart(..)
+            | FinalizedExpr::Part(..)
+            | FinalizedExpr::NoMatch => true,
+            FinalizedExpr::Lambda(_, _, ref body, _, ref defaults) => {
                 body.only_use_after_sub(offset, scope, false)
+                    && defaults
+                        .iter()
+                        .all(|d| d.only_use_after_sub(offset, scope, false))
             }
             FinalizedExpr::FunctionCall(ref f, ref args, _, _) => {
                 let is_sub =
@@ -450,6 +911,45 @@ impl FinalizedExpr {
                         .iter()
                         .all(|a| a.only_use_after_sub(offset, scope, is_sub))
             }
+            FinalizedExpr::Let(_, _, ref values, ref body) => {
+                values
+                    .iter()
+                    .all(|v| v.only_use_after_sub(offset, scope, false))
+                    && body.only_use_after_sub(offset, scope, false)
+            }
+            FinalizedExpr::Begin(ref exprs) => exprs
+                .iter()
+                .all(|e| e.only_use_after_sub(offset, scope, false)),
+            FinalizedExpr::Try(ref handler, ref body) => {
+                handler.only_use_after_sub(offset, scope, false)
+                    && body.only_use_after_sub(offset, scope, false)
+            }
+        }
+    }
+
+    /// Checks whether evaluating this expression pushes local variables
+    /// to the stack of the current function.
+    fn has_locals(&self) -> bool {
+        match *self {
+            // The handler of a try expression is kept on the stack
+            FinalizedExpr::Let(..) | FinalizedExpr::Try(..) => true,
+            FinalizedExpr::Cond(ref boks, ..) => {
+                let (ref test, ref true_expr, ref false_expr) = **boks;
+                test.has_locals() || true_expr.has_locals() || false_expr.has_locals()
+            }
+            FinalizedExpr::FunctionCall(ref f, ref args, _, _) => {
+                f.has_locals() || args.iter().any(FinalizedExpr::has_locals)
+            }
+            FinalizedExpr::Begin(ref exprs) => exprs.iter().any(FinalizedExpr::has_locals),
+            FinalizedExpr::Lambda(_, _, _, _, ref defaults) => {
+                defaults.iter().any(FinalizedExpr::has_locals)
+            }
+            FinalizedExpr::Variable(..)
+            | FinalizedExpr::Value(..)
+            | FinalizedExpr::Argument(..)
+            | FinalizedExpr::TestPart(..)
+            | FinalizedExpr::Part(..)
+            | FinalizedExpr::NoMatch => false,
         }
     }
 
@@ -488,11 +988,36 @@ impl FinalizedExpr {
                     tail_call_status,
                 )
             }
-            FinalizedExpr::Lambda(arg_c, scope, ref body, returns) => FinalizedExpr::Lambda(
-                arg_c,
+            FinalizedExpr::Lambda(arity, scope, ref body, returns, ref defaults) => {
+                FinalizedExpr::Lambda(
+                    arity,
+                    scope,
+                    Box::new(body.replace_args(scope_level, stack)),
+                    returns,
+                    defaults
+                        .iter()
+                        .map(|d| d.replace_args(scope_level, stack))
+                        .collect(),
+                )
+            }
+            FinalizedExpr::Let(scope, offset, ref values, ref body) => FinalizedExpr::Let(
                 scope,
+                offset,
+                values
+                    .iter()
+                    .map(|v| v.replace_args(scope_level, stack))
+                    .collect(),
+                Box::new(body.replace_args(scope_level, stack)),
+            ),
+            FinalizedExpr::Begin(ref exprs) => FinalizedExpr::Begin(
+                exprs
+                    .iter()
+                    .map(|e| e.replace_args(scope_level, stack))
+                    .collect(),
+            ),
+            FinalizedExpr::Try(ref handler, ref body) => FinalizedExpr::Try(
+                Box::new(handler.replace_args(scope_level, stack)),
                 Box::new(body.replace_args(scope_level, stack)),
-                returns,
             ),
             ref x => x.clone(),
         }
@@ -515,16 +1040,22 @@ enum Instr {
     /// The second parameter indicates whether this is a tail call, and if so, whether
     /// we can skip reuse the arguments
     EvalFunction(usize, Option<usize>),
-    /// Creates a custom function with given (scope level, argument count, function body, returns)
-    /// and pushes the result to the stack
-    CreateLambda(Scope, usize, Box<FinalizedExpr>, bool),
+    /// Creates a custom function with given (scope level, arity, function body, returns)
+    /// and pushes the result to the stack. The default values of its optional
+    /// arguments are popped from the stack
+    CreateLambda(Scope, Arity, Box<FinalizedExpr>, bool),
     /// Pops the stack reference and removes everything from the stack pointer
     /// upwards from the value stack except for the top value
     Return,
+    /// Removes the given number of local variables below the top value
+    DropLocals(usize),
+    /// Removes the top value from the stack
+    Pop,
 
     /// Skips the given number of instructions
     Jump(usize),
-    /// Pops boolean value from stack and conditionally jumps a number of instructions
+    /// Pops a value from stack and jumps a number of instructions unless it
+    /// is false
     CondJump(usize),
     /// Pushes a value to the stack
     PushValue(LispValue),
@@ -544,6 +1075,50 @@ enum Instr {
     CheckZero,
     CheckNull,
     CheckType(ArgType),
+    /// Replaces the given number of integers at the top of the stack by their sum
+    Add(usize),
+    /// Subtracts all but the first of the given number of integers at the top of
+    /// the stack from the first. A single integer is subtracted from zero.
+    Sub(usize),
+    /// Replaces the given number of integers at the top of the stack by their product
+    Mul(usize),
+    Quotient,
+    Remainder,
+    /// Checks whether the comparison holds for every consecutive pair of the
+    /// given number of integers at the top of the stack
+    Compare(Comparison, usize),
+    StringLength,
+    /// Concatenates the given number of strings at the top of the stack
+    StringAppend(usize),
+    /// Takes a string, a start index and an end index from the top of the
+    /// stack and pushes the characters in between
+    Substring,
+    /// Checks whether the given number of strings at the top of the stack
+    /// are all equal
+    StringEqual(usize),
+    StringToList,
+    NumberToString,
+    CharToInteger,
+    IntegerToChar,
+    CharUpcase,
+    CheckAlphabetic,
+    /// Checks whether the two atoms at the top of the stack are the same
+    CheckEq,
+    /// Checks whether the two values at the top of the stack are equal
+    CheckEqual,
+    /// Concatenates the given number of lists at the top of the stack
+    Append(usize),
+    /// Expands the macro call at the top of the stack
+    MacroExpand,
+    /// Replaces the given number of values at the top of the stack by a
+    /// record of the given type with these fields
+    MakeRecord(InternedString, usize),
+    /// Checks whether the value at the top of the stack is a record of the
+    /// given type
+    CheckRecord(InternedString),
+    /// Replaces the record of the given type at the top of the stack by its
+    /// field with the given index
+    RecordField(InternedString, usize),
 
     /// Pushes the car of the variable with given offset to the stack.
     /// This is functionally equivalent to [CloneArgument(offset), Car]
@@ -562,6 +1137,28 @@ enum Instr {
     VarCheckNull(StackOffset),
     /// Increments variable at given offset
     VarAddOne(StackOffset),
+    /// Checks whether the part of the variable with given offset at the
+    /// path passes the test and pushes the result to the stack
+    VarTest(StackOffset, Box<(ListPath, PartTest)>),
+    /// Pushes a clone of the part of the variable with given offset at the
+    /// path to the stack
+    VarPart(StackOffset, Box<ListPath>),
+    /// Identical to VarPart, except that it moves the part out of the
+    /// variable
+    VarTakePart(StackOffset, Box<ListPath>),
+    /// Fails because no clause of a match expression applies
+    NoMatch,
+    /// Fails because the variable with the given name is not defined
+    UnknownVariable(InternedString),
+    /// Installs the function at the top of the stack as the handler of
+    /// errors in the following instructions. When an error occurs, the
+    /// stacks are unwound to this point, the handler is applied and
+    /// execution continues the given number of instructions ahead.
+    Try(usize),
+    /// Removes the innermost handler and its function below the top value
+    EndTry,
+    /// Raises the value at the top of the stack
+    Raise,
 
     /// The most optimized instruction of all. Checks if the variable with
     /// given offset is zero. Jumps if it is, decrements it otherwise.
@@ -596,30 +1193,85 @@ struct FinalizationContext {
     scope_level: Scope,
     arguments: Vec<(InternedString, (Scope, StackOffset, VariableConstraint))>,
     tail_call_status: TailCallStatus,
-    own_name: Option<InternedString>,
+    /// Name and argument count of the function whose body is being finalized
+    own_name: Option<(InternedString, usize)>,
+    /// Name of the definition being finalized. It is given to the first
+    /// lambda, so that only its body recognizes recursive calls.
+    definition_name: Option<InternedString>,
+    /// Number of values on the stack of the current function when the
+    /// expression is evaluated. Local variables live at this offset.
+    stack_depth: usize,
+    /// Groups of mutually recursive functions defined by letrec. Every
+    /// function in a group takes all functions of the group as its first
+    /// arguments. Holds the scope and offset of the first of these arguments
+    /// and the argument count of every function.
+    groups: Vec<(Scope, StackOffset, Vec<usize>)>,
 }
 
 impl FinalizationContext {
-    fn new(own_name: Option<InternedString>) -> FinalizationContext {
+    fn new(definition_name: Option<InternedString>) -> FinalizationContext {
         FinalizationContext {
-            scope_level: Scope::default(),
+            // Local variables of top-level expressions live in scope zero
+            scope_level: Scope(1),
             arguments: Vec::new(),
             tail_call_status: TailCallStatus::CanTailCall,
-            own_name: own_name,
+            own_name: None,
+            definition_name,
+            stack_depth: 0,
+            groups: Vec::new(),
         }
     }
+
+    /// Scope of the local variables of the current function
+    fn local_scope(&self) -> Scope {
+        Scope(self.scope_level.0 - 1)
+    }
+
+    /// Finds the letrec function with the given name. Returns its group
+    /// and its position in that group.
+    fn group_member(&self, name: InternedString) -> Option<(Scope, StackOffset, &[usize], usize)> {
+        let (scope, offset) = self
+            .arguments
+            .iter()
+            .rev()
+            .find(|&&(n, _)| n == name)
+            .map(|&(_, (scope, offset, _))| (scope, offset))?;
+
+        self.groups
+            .iter()
+            .rev()
+            .find(|&&(group_scope, base, ref arg_counts)| {
+                group_scope == scope
+                    && base <= offset
+                    && offset.to_usize() < base.to_usize() + arg_counts.len()
+            })
+            .map(|&(_, base, ref arg_counts)| {
+                (
+                    scope,
+                    base,
+                    &arg_counts[..],
+                    offset.to_usize() - base.to_usize(),
+                )
+            })
+    }
 }
 
 impl LispExpr {
     fn into_top_expr(self) -> EvaluationResult<TopExpr> {
-        let is_define = if let LispExpr::Call(ref expr_list) = self {
-            Some(&LispExpr::Macro(LispMacro::Define)) == expr_list.get(0)
+        let definition = if let LispExpr::Call(ref expr_list) = self {
+            match expr_list.first() {
+                Some(&LispExpr::Macro(LispMacro::Define)) => Some(LispMacro::Define),
+                Some(&LispExpr::Macro(LispMacro::Defmacro)) => Some(LispMacro::Defmacro),
+                Some(&LispExpr::Macro(LispMacro::DefineSyntax)) => Some(LispMacro::DefineSyntax),
+                Some(&LispExpr::Macro(LispMacro::DefineRecord)) => Some(LispMacro::DefineRecord),
+                _ => None,
+            }
         } else {
-            false
+            None
         };
 
         // This feels kind of clumsy
-        if is_define {
+        if let Some(LispMacro::Define) = definition {
             match self {
                 LispExpr::Call(expr_list) => {
                     let mut call_iter = expr_list.into_iter();
@@ -633,12 +1285,191 @@ impl LispExpr {
                 }
                 _ => unreachable!(),
             }
+        } else if let Some(LispMacro::DefineSyntax) = definition {
+            match self {
+                LispExpr::Call(expr_list) => {
+                    let mut call_iter = expr_list.into_iter();
+                    destructure!(call_iter, [_mac, opvar, rules], {
+                        if let LispExpr::OpVar(n) = opvar {
+                            Ok(TopExpr::DefineSyntax(n, rules))
+                        } else {
+                            Err(EvaluationError::BadDefine)
+                        }
+                    })
+                }
+                _ => unreachable!(),
+            }
+        } else if let Some(LispMacro::DefineRecord) = definition {
+            match self {
+                LispExpr::Call(expr_list) => {
+                    let mut call_iter = expr_list.into_iter().skip(1);
+                    let name = match call_iter.next() {
+                        Some(LispExpr::OpVar(n)) => n,
+                        Some(..) => return Err(EvaluationError::BadDefine),
+                        None => return Err(EvaluationError::ArgumentCountMismatch),
+                    };
+                    let mut fields = Vec::new();
+
+                    for expr in call_iter {
+                        match expr {
+                            LispExpr::OpVar(n) if !fields.contains(&n) => fields.push(n),
+                            _ => return Err(EvaluationError::BadDefine),
+                        }
+                    }
+
+                    Ok(TopExpr::DefineRecord(name, fields))
+                }
+                _ => unreachable!(),
+            }
+        } else if definition.is_some() {
+            match self {
+                LispExpr::Call(expr_list) => {
+                    let mut call_iter = expr_list.into_iter();
+                    destructure!(call_iter, [_mac, opvar, arg_list, body], {
+                        if let LispExpr::OpVar(n) = opvar {
+                            let transformer = LispExpr::Call(vec![
+                                LispExpr::Macro(LispMacro::Lambda),
+                                arg_list,
+                                body,
+                            ]);
+                            Ok(TopExpr::DefineMacro(n, transformer))
+                        } else {
+                            Err(EvaluationError::BadDefine)
+                        }
+                    })
+                }
+                _ => unreachable!(),
+            }
         } else {
             let finalized = self.finalize(&mut FinalizationContext::new(None))?;
             Ok(TopExpr::Regular(finalized.0, finalized.1))
         }
     }
 
+    /// Converts quoted data to the value it represents. Names become
+    /// symbols and calls become lists.
+    fn into_datum(self) -> EvaluationResult<LispValue> {
+        match self {
+            LispExpr::Value(v) => Ok(v),
+            LispExpr::OpVar(n) => Ok(LispValue::Symbol(n)),
+            LispExpr::Call(exprs) => exprs
+                .into_iter()
+                .map(LispExpr::into_datum)
+                .collect::<EvaluationResult<_>>()
+                .map(LispValue::List),
+            LispExpr::Macro(..) => Err(EvaluationError::UnexpectedOperator),
+        }
+    }
+
+    /// Converts code to the data that represents it, which is what macros
+    /// operate on. Unlike `into_datum`, this turns special forms and
+    /// built-in functions back into symbols.
+    fn into_code_datum(self, state: &mut State) -> LispValue {
+        match self {
+            LispExpr::Value(LispValue::Function(LispFunc::BuiltIn(f))) => {
+                LispValue::Symbol(state.intern(f.to_string()))
+            }
+            LispExpr::Value(v) => v,
+            LispExpr::OpVar(n) => LispValue::Symbol(n),
+            LispExpr::Macro(mac) => LispValue::Symbol(state.intern(mac.to_string())),
+            LispExpr::Call(exprs) => LispValue::List(
+                exprs
+                    .into_iter()
+                    .map(|e| e.into_code_datum(state))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Returns whether this quasiquote template contains unquoted
+    /// expressions.
+    fn has_unquotes(&self) -> bool {
+        match *self {
+            LispExpr::Call(ref exprs) => match exprs.first() {
+                Some(&LispExpr::Macro(LispMacro::Unquote))
+                | Some(&LispExpr::Macro(LispMacro::UnquoteSplicing)) => true,
+                _ => exprs.iter().any(LispExpr::has_unquotes),
+            },
+            _ => false,
+        }
+    }
+
+    /// Lowers a quasiquote template to an expression that builds it. Parts
+    /// of the template without unquotes are quoted, unquoted expressions
+    /// are evaluated and lists are built using `list`, `cons` and `append`.
+    fn unquote_template(self) -> EvaluationResult<LispExpr> {
+        fn builtin(f: BuiltIn, mut args: Vec<LispExpr>) -> LispExpr {
+            args.insert(
+                0,
+                LispExpr::Value(LispValue::Function(LispFunc::BuiltIn(f))),
+            );
+            LispExpr::Call(args)
+        }
+
+        fn unquote_argument(exprs: Vec<LispExpr>) -> EvaluationResult<LispExpr> {
+            let mut expr_iter = exprs.into_iter().skip(1);
+            Ok(destructure!(expr_iter, [expr], expr))
+        }
+
+        if !self.has_unquotes() {
+            return Ok(LispExpr::Call(vec![
+                LispExpr::Macro(LispMacro::Quote),
+                self,
+            ]));
+        }
+
+        let exprs = match self {
+            LispExpr::Call(exprs) => exprs,
+            _ => unreachable!(),
+        };
+
+        match exprs[0] {
+            LispExpr::Macro(LispMacro::Unquote) => return unquote_argument(exprs),
+            // Splices must be part of a list
+            LispExpr::Macro(LispMacro::UnquoteSplicing) => {
+                return Err(EvaluationError::UnexpectedOperator)
+            }
+            _ => {}
+        }
+
+        // Since cons adds elements to the end of a list, elements that
+        // follow a splice can be consed onto the list built so far.
+        let mut list = None;
+        let mut prefix = Vec::new();
+
+        for expr in exprs {
+            let is_splice = match expr {
+                LispExpr::Call(ref v) => {
+                    v.first() == Some(&LispExpr::Macro(LispMacro::UnquoteSplicing))
+                }
+                _ => false,
+            };
+
+            list = match (list, is_splice) {
+                (None, false) => {
+                    prefix.push(expr.unquote_template()?);
+                    None
+                }
+                (Some(l), false) => Some(builtin(BuiltIn::Cons, vec![expr.unquote_template()?, l])),
+                (l, true) => {
+                    let spliced = match expr {
+                        LispExpr::Call(v) => unquote_argument(v)?,
+                        _ => unreachable!(),
+                    };
+                    let mut lists = match l {
+                        Some(l) => vec![l],
+                        None if prefix.is_empty() => vec![],
+                        None => vec![builtin(BuiltIn::List, take(&mut prefix))],
+                    };
+                    lists.push(spliced);
+                    Some(builtin(BuiltIn::Append, lists))
+                }
+            };
+        }
+
+        Ok(list.unwrap_or_else(|| builtin(BuiltIn::List, prefix)))
+    }
+
     /// Bool indicates whether the expression returns
     /// Tail calls and recursions do not return, for example
     fn finalize(self, ctx: &mut FinalizationContext) -> EvaluationResult<(FinalizedExpr, bool)> {
@@ -649,6 +1480,38 @@ impl LispExpr {
             caller: Option<BuiltIn>,
         ) -> EvaluationResult<(FinalizedExpr, bool)> {
             if let LispExpr::OpVar(n) = expr {
+                if let Some((scope, base, arg_counts, index)) = ctx.group_member(n) {
+                    // Functions defined by letrec are partially applied to
+                    // their group
+                    let call = FinalizedExpr::FunctionCall(
+                        Box::new(FinalizedExpr::Argument(
+                            base + StackOffset::from(index),
+                            scope,
+                            VariableConstraint::NeedFull,
+                        )),
+                        group_arguments(scope, base, arg_counts.len()).collect(),
+                        arg_counts[index] == 0,
+                        false,
+                    );
+
+                    // Functions without arguments of their own would be called
+                    // straight away, so they are wrapped in a new lambda
+                    return Ok((
+                        if arg_counts[index] == 0 {
+                            FinalizedExpr::Lambda(
+                                Arity::fixed(0),
+                                ctx.scope_level,
+                                Box::new(call),
+                                false,
+                                Vec::new(),
+                            )
+                        } else {
+                            call
+                        },
+                        true,
+                    ));
+                }
+
                 // So if we encounter a symbol, it could be two things:
                 // a function argument, in which case it should be in the arguments map
                 // a reference to something in our state.
@@ -683,6 +1546,10 @@ impl LispExpr {
             }
         }
 
+        // Only the expression that is defined can take the name of the
+        // definition
+        let definition_name = ctx.definition_name.take();
+
         Ok(match self {
             LispExpr::Value(v) => (FinalizedExpr::Value(v), true),
             LispExpr::OpVar(..) => return deal_with_opvar(self, ctx, None),
@@ -697,113 +1564,163 @@ impl LispExpr {
                 };
 
                 match head_expr {
-                    LispExpr::Macro(LispMacro::Cond) => {
+                    LispExpr::Macro(LispMacro::If) => {
                         destructure!(expr_iter, [test_expr, true_expr, false_expr], {
-                            let could_tail_call = ctx.tail_call_status;
-                            let false_expr_args = ctx.arguments.clone();
-                            let mut false_expr_ctx = FinalizationContext {
-                                arguments: false_expr_args,
-                                ..*ctx
-                            };
-                            let (finalized_false_expr, _false_returns) =
-                                false_expr.finalize(&mut false_expr_ctx)?;
-                            let (finalized_true_expr, true_returns) = true_expr.finalize(ctx)?;
-
-                            // Move analysis: a function argument is still moveable
-                            // when it has been moved in neither the true branch or
-                            // the false branch.
-                            for (&mut (_, (_, _, ref mut arg_true)), &(_, (_, _, arg_false))) in ctx
-                                .arguments
-                                .iter_mut()
-                                .zip(false_expr_ctx.arguments.iter())
-                            {
-                                *arg_true = arg_false.combine(*arg_true);
-                            }
+                            finalize_if(
+                                |ctx| Ok(test_expr.finalize(ctx)?.0),
+                                |ctx| true_expr.finalize(ctx),
+                                |ctx| false_expr.finalize(ctx),
+                                ctx,
+                            )?
+                        })
+                    }
+                    LispExpr::Macro(mac @ LispMacro::When)
+                    | LispExpr::Macro(mac @ LispMacro::Unless) => {
+                        let test_expr = match expr_iter.next() {
+                            Some(test_expr) => test_expr,
+                            None => return Err(EvaluationError::ArgumentCountMismatch),
+                        };
+                        let finalize_test =
+                            |ctx: &mut FinalizationContext| Ok(test_expr.finalize(ctx)?.0);
+                        let finalize_body = |ctx: &mut FinalizationContext| {
+                            finalize_sequence(expr_iter.collect(), ctx)
+                        };
+                        let finalize_empty = |_: &mut FinalizationContext| {
+                            Ok((FinalizedExpr::Value(LispValue::List(Vec::new())), true))
+                        };
 
-                            // The test expression cannot ever tail call!
-                            // TODO: add test for this!
-                            ctx.tail_call_status = TailCallStatus::CannotTailCall;
-
-                            (
-                                FinalizedExpr::Cond(
-                                    Box::new((
-                                        test_expr.finalize(ctx)?.0,
-                                        finalized_true_expr,
-                                        finalized_false_expr,
-                                    )),
-                                    true_returns,
-                                    could_tail_call,
-                                ),
-                                could_tail_call == TailCallStatus::CannotTailCall,
-                            )
+                        if mac == LispMacro::When {
+                            finalize_if(finalize_test, finalize_body, finalize_empty, ctx)?
+                        } else {
+                            finalize_if(finalize_test, finalize_empty, finalize_body, ctx)?
+                        }
+                    }
+                    LispExpr::Macro(LispMacro::Cond) => finalize_clauses(expr_iter, ctx)?,
+                    LispExpr::Macro(LispMacro::Try) => {
+                        destructure!(expr_iter, [body, handler], {
+                            finalize_try(body, handler, ctx)?
                         })
                     }
+                    LispExpr::Macro(LispMacro::Match) => match expr_iter.next() {
+                        Some(value) => finalize_match(value, expr_iter, ctx)?,
+                        None => return Err(EvaluationError::ArgumentCountMismatch),
+                    },
+                    LispExpr::Macro(mac @ LispMacro::And)
+                    | LispExpr::Macro(mac @ LispMacro::Or) => {
+                        finalize_connective(mac == LispMacro::And, expr_iter, ctx)?
+                    }
                     LispExpr::Macro(LispMacro::Lambda) => {
-                        destructure!(expr_iter, [arg_list, body], {
-                            if let LispExpr::Call(ref arg_vec) = arg_list {
-                                // Append arguments to the arguments map. Since we're doing
-                                // symbol lookup in reverse orders, this guarantees that
-                                // variables with the same symbol will use the highest
-                                // scope.
-                                let num_args = arg_vec.len();
-                                let arguments_len = ctx.arguments.len();
-                                ctx.arguments.reserve(num_args);
-
-                                for (offset, expr) in arg_vec.into_iter().enumerate() {
-                                    let symbol = match *expr {
-                                        LispExpr::OpVar(intern) => Ok(intern),
-                                        _ => Err(EvaluationError::MalformedDefinition),
-                                    }?;
-
-                                    ctx.arguments.push((
-                                        symbol,
-                                        (
-                                            ctx.scope_level,
-                                            StackOffset::from(offset),
-                                            VariableConstraint::Unconstrained,
-                                        ),
-                                    ));
-                                }
-
-                                // Update context for lambda
-                                let orig_scope_level = ctx.scope_level;
-                                let current_tail_status = ctx.tail_call_status;
-                                ctx.scope_level = ctx.scope_level.next();
-                                ctx.tail_call_status = TailCallStatus::CanTailCall;
-
-                                let (finalized_body, returns) = body.finalize(ctx)?;
-
-                                // TODO: here we can check whether this is not a tail call
-                                // but all arguments were moved!
-
-                                let result = FinalizedExpr::Lambda(
-                                    num_args,
-                                    orig_scope_level,
-                                    Box::new(finalized_body),
-                                    returns,
-                                );
-
-                                // Reset context to original state
-                                ctx.scope_level = orig_scope_level;
-                                ctx.tail_call_status = current_tail_status;
-                                ctx.arguments.truncate(arguments_len);
-
-                                (result, true)
-                            } else {
-                                return Err(EvaluationError::ArgumentTypeMismatch);
+                        let (params, body) = lambda_parts(expr_iter.collect())?;
+                        (
+                            finalize_lambda(params, None, definition_name, body, ctx)?,
+                            true,
+                        )
+                    }
+                    LispExpr::Macro(LispMacro::Begin) => {
+                        finalize_sequence(expr_iter.collect(), ctx)?
+                    }
+                    LispExpr::Macro(LispMacro::Let) => {
+                        destructure!(expr_iter, [bindings, body], {
+                            let (names, values) = bindings.let_bindings()?;
+                            finalize_let(
+                                names,
+                                values,
+                                None,
+                                |value, _, ctx| Ok(value.finalize(ctx)?.0),
+                                |ctx| body.finalize(ctx),
+                                ctx,
+                            )?
+                        })
+                    }
+                    LispExpr::Macro(LispMacro::LetStar) => {
+                        destructure!(expr_iter, [bindings, body], {
+                            let (names, values) = bindings.let_bindings()?;
+                            finalize_let_star(names, values, body, ctx)?
+                        })
+                    }
+                    LispExpr::Macro(LispMacro::Letrec) => {
+                        destructure!(expr_iter, [bindings, body], {
+                            let (names, values) = bindings.let_bindings()?;
+                            // Other values are bound before the functions
+                            // that refer to them, like internal definitions
+                            if !values.iter().all(LispExpr::is_lambda) {
+                                return bind_in_dependency_order(names, values, body)?
+                                    .finalize(ctx);
                             }
+                            let lambdas = values
+                                .into_iter()
+                                .map(LispExpr::into_lambda)
+                                .collect::<EvaluationResult<Vec<_>>>()?;
+                            // Partial applications to the group only take
+                            // required arguments
+                            if lambdas.iter().any(|(params, _)| !params.arity.is_fixed()) {
+                                return Err(EvaluationError::MalformedDefinition);
+                            }
+                            let arg_counts: Vec<_> = lambdas
+                                .iter()
+                                .map(|(params, _)| params.arity.required)
+                                .collect();
+                            let group_names = names.clone();
+
+                            finalize_let(
+                                names,
+                                lambdas,
+                                Some(arg_counts.clone()),
+                                |(mut params, lambda_body), index, ctx| {
+                                    let mut arguments = group_names.clone();
+                                    arguments.append(&mut params.names);
+                                    params.names = arguments;
+                                    params.arity.required += group_names.len();
+                                    finalize_lambda(
+                                        params,
+                                        Some(arg_counts.clone()),
+                                        Some(group_names[index]),
+                                        lambda_body,
+                                        ctx,
+                                    )
+                                },
+                                |ctx| body.finalize(ctx),
+                                ctx,
+                            )?
+                        })
+                    }
+                    LispExpr::Macro(LispMacro::Quote) => {
+                        destructure!(expr_iter, [datum], {
+                            (FinalizedExpr::Value(datum.into_datum()?), true)
+                        })
+                    }
+                    LispExpr::Macro(LispMacro::Quasiquote) => {
+                        destructure!(expr_iter, [template], {
+                            template.unquote_template()?.finalize(ctx)?
                         })
                     }
                     // Defines should be caught by into_top_expr
-                    LispExpr::Macro(LispMacro::Define) => {
+                    LispExpr::Macro(LispMacro::Define)
+                    | LispExpr::Macro(LispMacro::Defmacro)
+                    | LispExpr::Macro(LispMacro::DefineSyntax)
+                    | LispExpr::Macro(LispMacro::DefineRecord) => {
                         return Err(EvaluationError::MalformedDefinition)
                     }
                     // Function evaluation
                     _ => {
+                        // Functions defined by letrec are called with their group
+                        // as the first arguments
+                        let group = if let LispExpr::OpVar(intern) = head_expr {
+                            ctx.group_member(intern)
+                                .map(|(scope, base, arg_counts, index)| {
+                                    (scope, base, arg_counts.len(), index)
+                                })
+                        } else {
+                            None
+                        };
+                        let group_size = group.map_or(0, |(_, _, size, _)| size);
+                        // Calls with a different number of arguments are curried
+                        // or over-applied, so they cannot recurse in place
                         let is_self_call = if let LispExpr::OpVar(intern) = head_expr {
-                            ctx.own_name
-                                .map(|self_name| intern == self_name)
-                                .unwrap_or(false)
+                            ctx.own_name.is_some_and(|(self_name, arg_count)| {
+                                intern == self_name
+                                    && arg_count == group_size + expr_iter.as_slice().len()
+                            })
                         } else {
                             false
                         };
@@ -824,13 +1741,28 @@ impl LispExpr {
                             None
                         };
 
-                        let funk = head_expr.finalize(ctx)?.0;
+                        let depth = ctx.stack_depth;
+                        ctx.stack_depth = depth + group_size + expr_iter.len();
+
+                        let funk = match group {
+                            Some((scope, base, _, index)) => FinalizedExpr::Argument(
+                                base + StackOffset::from(index),
+                                scope,
+                                VariableConstraint::NeedFull,
+                            ),
+                            None => head_expr.finalize(ctx)?.0,
+                        };
 
-                        for e in expr_iter.rev() {
+                        for (idx, e) in expr_iter.enumerate().rev() {
+                            ctx.stack_depth = depth + group_size + idx;
                             let arg = deal_with_opvar(e, ctx, caller)?.0;
                             arg_finalized_expr.push(arg);
                         }
+                        if let Some((scope, base, size, _)) = group {
+                            arg_finalized_expr.extend(group_arguments(scope, base, size).rev());
+                        }
                         arg_finalized_expr.reverse();
+                        ctx.stack_depth = depth;
 
                         (
                             FinalizedExpr::FunctionCall(
@@ -846,507 +1778,1950 @@ impl LispExpr {
             }
         })
     }
-}
-
-#[derive(PartialEq, Eq, Clone, Copy, Debug)]
-enum VariableConstraint {
-    NeedFull,
-    Unconstrained,
-    RemovedTail,
-    RemovedHead,
-}
 
-impl VariableConstraint {
-    fn combine(self, other: Self) -> Self {
-        if self == other {
-            self
-        } else {
-            VariableConstraint::NeedFull
+    /// Reads the argument list of a lambda. Arguments after `#!optional` are
+    /// either a name, which defaults to the empty list, or a list of a name
+    /// and its default value. The name after `.` collects the remaining
+    /// arguments, as does a single name instead of an argument list.
+    fn lambda_arguments(self) -> EvaluationResult<Parameters> {
+        let mut params = Parameters {
+            names: Vec::new(),
+            arity: Arity::fixed(0),
+            defaults: Vec::new(),
+        };
+        let arg_vec = match self {
+            LispExpr::Call(arg_vec) => arg_vec,
+            LispExpr::OpVar(name) => {
+                params.names.push(name);
+                params.arity.rest = true;
+                return Ok(params);
+            }
+            _ => return Err(EvaluationError::ArgumentTypeMismatch),
+        };
+        let mut optional = false;
+        let mut arg_iter = arg_vec.into_iter();
+
+        while let Some(expr) = arg_iter.next() {
+            match expr {
+                LispExpr::OpVar(name) if optional => {
+                    params.names.push(name);
+                    params.arity.optional += 1;
+                    params
+                        .defaults
+                        .push(LispExpr::Value(LispValue::List(Vec::new())));
+                }
+                LispExpr::OpVar(name) => {
+                    params.names.push(name);
+                    params.arity.required += 1;
+                }
+                LispExpr::Call(pair) if optional => {
+                    let mut pair_iter = pair.into_iter();
+
+                    match (pair_iter.next(), pair_iter.next(), pair_iter.next()) {
+                        (Some(LispExpr::OpVar(name)), Some(default), None) => {
+                            params.names.push(name);
+                            params.arity.optional += 1;
+                            params.defaults.push(default);
+                        }
+                        _ => return Err(EvaluationError::MalformedDefinition),
+                    }
+                }
+                LispExpr::Macro(LispMacro::Optional) if !optional => optional = true,
+                LispExpr::Macro(LispMacro::Dot) => match (arg_iter.next(), arg_iter.next()) {
+                    (Some(LispExpr::OpVar(name)), None) => {
+                        params.names.push(name);
+                        params.arity.rest = true;
+                    }
+                    _ => return Err(EvaluationError::MalformedDefinition),
+                },
+                _ => return Err(EvaluationError::MalformedDefinition),
+            }
         }
+
+        Ok(params)
     }
-}
-
-#[derive(Debug, PartialEq, Eq)]
-pub enum EvaluationError {
-    UnexpectedOperator,
-    ArgumentCountMismatch,
-    ArgumentTypeMismatch,
-    EmptyListEvaluation,
-    NonFunctionApplication,
-    SubZero,
-    EmptyList,
-    UnknownVariable(String),
-    MalformedDefinition,
-    BadDefine,
-}
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum LispValue {
-    Boolean(bool),
-    Integer(u64),
-    Function(LispFunc),
-    List(Vec<LispValue>),
-}
 
-impl LispValue {
-    fn get_type(&self) -> ArgType {
-        match *self {
-            LispValue::Boolean(..) => ArgType::Boolean,
-            LispValue::Integer(..) => ArgType::Integer,
-            LispValue::Function(..) => ArgType::Function,
-            LispValue::List(..) => ArgType::List,
+    /// Splits a lambda expression into its parameters and body.
+    fn into_lambda(self) -> EvaluationResult<(Parameters, LispExpr)> {
+        match self {
+            LispExpr::Call(mut expr_list) => {
+                if expr_list.first() == Some(&LispExpr::Macro(LispMacro::Lambda)) {
+                    expr_list.remove(0);
+                    lambda_parts(expr_list)
+                } else {
+                    Err(EvaluationError::MalformedDefinition)
+                }
+            }
+            _ => Err(EvaluationError::MalformedDefinition),
         }
     }
-}
 
-fn builtin_instr(f: BuiltIn, arg_count: usize) -> EvaluationResult<Instr> {
-    Ok(match (f, arg_count) {
-        (BuiltIn::AddOne, 1) => Instr::AddOne,
-        (BuiltIn::SubOne, 1) => Instr::SubOne,
-        (BuiltIn::CheckZero, 1) => Instr::CheckZero,
-        (BuiltIn::CheckNull, 1) => Instr::CheckNull,
-        (BuiltIn::List, _) => Instr::List(arg_count),
-        (BuiltIn::Cons, 2) => Instr::Cons,
-        (BuiltIn::Car, 1) => Instr::Car,
-        (BuiltIn::Cdr, 1) => Instr::Cdr,
-        (BuiltIn::CheckType(t), 1) => Instr::CheckType(t),
-        (_, _) => return Err(EvaluationError::ArgumentCountMismatch),
-    })
-}
-
-#[derive(PartialEq, Eq, Clone, Copy)]
-enum VarStatus {
-    RemovedHead,
-    RemovedTail,
-}
-
-// Compiles a finalized expression into instructions and writes them to the
-// given buffer *in reverse order*
-fn inner_compile(
-    expr: FinalizedExpr,
-    state: &State,
-    instructions: &mut Vec<Instr>,
-    var_stats: &mut Vec<(StackOffset, Scope, VarStatus)>,
-) -> EvaluationResult<()> {
-    match expr {
-        FinalizedExpr::Argument(offset, _scope, VariableConstraint::Unconstrained) => {
-            instructions.push(Instr::MoveArgument(offset));
-        }
-        FinalizedExpr::Argument(offset, _scope, _move_status) => {
-            instructions.push(Instr::CloneArgument(offset));
-        }
-        FinalizedExpr::Value(v) => {
-            instructions.push(Instr::PushValue(v));
-        }
-        FinalizedExpr::Variable(n) => {
-            if let Some(v) = state.get(n) {
-                instructions.push(Instr::PushValue(v.clone()));
-            } else {
-                return Err(EvaluationError::UnknownVariable(
-                    state.resolve_intern(n).into(),
-                ));
+    /// Checks whether this is a definition.
+    fn is_definition(&self) -> bool {
+        match *self {
+            LispExpr::Call(ref expr_list) => {
+                expr_list.first() == Some(&LispExpr::Macro(LispMacro::Define))
             }
+            _ => false,
         }
-        FinalizedExpr::Cond(triple, true_expr_returns, tail_call_status) => {
-            let unpacked = *triple;
-            let (test, true_expr, false_expr) = unpacked;
-
-            // Test must be done before to ensure that the true/ false branches
-            // get the right var_stats.
-            let mut test_expr_buf = Vec::new();
-            inner_compile(test.clone(), state, &mut test_expr_buf, var_stats)?;
-            let mut false_expr_var_stats = var_stats.clone();
-            let before_len = instructions.len();
+    }
 
-            if true_expr_returns && tail_call_status == TailCallStatus::CanTailCall {
-                instructions.push(Instr::Return);
+    /// Checks whether this is a lambda expression.
+    fn is_lambda(&self) -> bool {
+        match *self {
+            LispExpr::Call(ref expr_list) => {
+                expr_list.first() == Some(&LispExpr::Macro(LispMacro::Lambda))
             }
+            _ => false,
+        }
+    }
 
-            inner_compile(true_expr, state, instructions, var_stats)?;
-            let jump_size = instructions.len() - before_len;
-            let before_len = instructions.len();
+    /// Checks whether the argument list of a lambda expression only has
+    /// required arguments.
+    fn has_fixed_arity(&self) -> bool {
+        match *self {
+            LispExpr::Call(ref expr_list) => match expr_list.get(1) {
+                Some(LispExpr::Call(args)) => {
+                    args.iter().all(|arg| matches!(arg, LispExpr::OpVar(..)))
+                }
+                _ => false,
+            },
+            _ => false,
+        }
+    }
 
-            // We should probably always place either one of these. Even if we knew
-            // that the false expression *could* tail call, we are not sure if it
-            // will, for example when it is a curried result
-            if tail_call_status == TailCallStatus::CanTailCall {
-                instructions.push(Instr::Return);
-            } else {
-                instructions.push(Instr::Jump(jump_size));
+    /// Collects the variables that the expression refers to, other than
+    /// those that it binds itself. Names bound by match patterns are not
+    /// recognized, so they may be included.
+    fn free_variables(&self, bound: &mut Vec<InternedString>, names: &mut Vec<InternedString>) {
+        let exprs = match *self {
+            LispExpr::OpVar(n) => {
+                if !bound.contains(&n) && !names.contains(&n) {
+                    names.push(n);
+                }
+                return;
             }
+            LispExpr::Call(ref exprs) => exprs,
+            _ => return,
+        };
+        let bound_len = bound.len();
+        let mac = match exprs.first() {
+            Some(&LispExpr::Macro(mac)) if exprs.len() > 1 => Some(mac),
+            _ => None,
+        };
 
-            if let FinalizedExpr::FunctionCall(ref f_box, ref args, ..) = test {
-                if let FinalizedExpr::Value(LispValue::Function(LispFunc::BuiltIn(
-                    BuiltIn::CheckZero,
-                ))) = **f_box
-                {
-                    if let Some(&FinalizedExpr::Argument(offset, scope, _)) = args.get(0) {
-                        // OK, so at this point we know we are jumping conditionally
-                        // on whether a function arg is zero.
-                        // Next: make sure that every use of this argument in the false branch
-                        // is within a sub1 call.
-                        // If this is the case, replace all these sub1 calls by uses
-                        // of the argument itself (maintaining its move status!).
-                        // Then, encode the conditional jump, zero check and decrement using
-                        // a single, superspecialized instruction.
-                        if args.len() == 1 && false_expr.only_use_after_sub(offset, scope, false) {
-                            let new_false_expr = false_expr.remove_subs_of(offset, scope);
-                            inner_compile(
-                                new_false_expr,
-                                state,
-                                instructions,
-                                &mut false_expr_var_stats,
-                            )?;
-                            let jump_size = instructions.len() - before_len;
-                            instructions.push(Instr::CondZeroJumpDecr(offset, jump_size));
+        match mac {
+            Some(LispMacro::Quote) => return,
+            Some(LispMacro::Lambda) => {
+                let args = match exprs[1] {
+                    LispExpr::Call(ref args) => &args[..],
+                    ref rest => std::slice::from_ref(rest),
+                };
 
-                            return Ok(());
+                // Default values are evaluated outside the body
+                for arg in args {
+                    if let LispExpr::Call(ref pair) = *arg {
+                        for default in &pair[1..] {
+                            default.free_variables(bound, names);
                         }
                     }
                 }
-            }
 
-            inner_compile(false_expr, state, instructions, &mut false_expr_var_stats)?;
-            let jump_size = instructions.len() - before_len;
-            instructions.push(Instr::CondJump(jump_size));
-            instructions.extend(test_expr_buf.into_iter());
-        }
-        FinalizedExpr::Lambda(arg_count, scope, body, returns) => {
-            instructions.push(Instr::CreateLambda(scope, arg_count, body, returns));
-        }
-        FinalizedExpr::FunctionCall(funk, args, is_tail_call, is_self_call) => {
-            // Here we check for special patterns of builtin functions on single
-            // arguments and try to generate specialized instructions for them.
-            if let FinalizedExpr::Value(LispValue::Function(LispFunc::BuiltIn(bf))) = *funk {
-                if let Some(&FinalizedExpr::Argument(offset, scope, move_status)) = args.get(0) {
-                    match (bf, offset, scope, move_status) {
-                        (BuiltIn::Car, offset, scope, VariableConstraint::RemovedTail) => {
-                            instructions.push(Instr::VarSplit(offset));
-                            var_stats.push((offset, scope, VarStatus::RemovedHead));
-                            return Ok(());
-                        }
-                        (BuiltIn::Cdr, offset, scope, VariableConstraint::Unconstrained) => {
-                            if var_stats.iter().any(|&(o, s, v)| {
-                                o == offset && s == scope && v == VarStatus::RemovedHead
-                            }) {
-                                // Head was previously removed. We can just move the remainder
-                                instructions.push(Instr::MoveArgument(offset));
-                                return Ok(());
-                            }
-                        }
-                        (BuiltIn::Cdr, offset, scope, VariableConstraint::RemovedHead) => {
-                            instructions.push(Instr::VarReverseSplit(offset));
-                            var_stats.push((offset, scope, VarStatus::RemovedTail));
-                            return Ok(());
-                        }
-                        (BuiltIn::Car, offset, scope, VariableConstraint::Unconstrained) => {
-                            if var_stats.iter().any(|&(o, s, v)| {
-                                o == offset && s == scope && v == VarStatus::RemovedTail
-                            }) {
-                                // Tail was previously removed. We can just move the head
-                                instructions.push(Instr::MoveArgument(offset));
-                            } else {
-                                // Tail is still on.
-                                instructions.push(Instr::VarCar(offset));
+                for arg in args {
+                    match *arg {
+                        LispExpr::OpVar(n) => bound.push(n),
+                        LispExpr::Call(ref pair) => {
+                            if let Some(&LispExpr::OpVar(n)) = pair.first() {
+                                bound.push(n);
                             }
-                            return Ok(());
-                        }
-                        (BuiltIn::Car, offset, ..)
-                        | (BuiltIn::CheckNull, offset, ..)
-                        | (BuiltIn::CheckZero, offset, ..) => {
-                            // Inspection mode!
-                            instructions.push(match bf {
-                                BuiltIn::CheckNull => Instr::VarCheckNull(offset),
-                                BuiltIn::CheckZero => Instr::VarCheckZero(offset),
-                                BuiltIn::Car => Instr::VarCar(offset),
-                                _ => unreachable!(),
-                            });
-                            return Ok(());
                         }
-                        (BuiltIn::AddOne, offset, _scope, VariableConstraint::Unconstrained) => {
-                            instructions.push(Instr::MoveArgument(offset));
-                            instructions.push(Instr::VarAddOne(offset));
-                            return Ok(());
+                        _ => {}
+                    }
+                }
+
+                for expr in &exprs[2..] {
+                    if let LispExpr::Call(ref def) = *expr {
+                        if let (true, Some(&LispExpr::OpVar(n))) =
+                            (expr.is_definition(), def.get(1))
+                        {
+                            bound.push(n);
                         }
-                        (..) => {}
                     }
                 }
-            }
 
-            // Here, for tail calls, we try to reuse function arguments
-            // and elide copies thereof. For strict recursions, it's
-            // possible to do a (partial) elision when some non-zero prefix
-            // of the called function arguments is an in-order move from the
-            // prefix of the calling function.
-            let args_len = args.len();
-            let init_len = instructions.len();
-            let builtin =
-                if let FinalizedExpr::Value(LispValue::Function(LispFunc::BuiltIn(bf))) = *funk {
-                    Some(bf)
-                } else {
-                    None
+                for expr in &exprs[2..] {
+                    expr.free_variables(bound, names);
+                }
+            }
+            Some(mac @ LispMacro::Let)
+            | Some(mac @ LispMacro::LetStar)
+            | Some(mac @ LispMacro::Letrec) => {
+                let bindings = match exprs[1] {
+                    LispExpr::Call(ref bindings) => &bindings[..],
+                    _ => &[],
                 };
+                let binding_names: Vec<_> = bindings
+                    .iter()
+                    .filter_map(|binding| match *binding {
+                        LispExpr::Call(ref binding) => match binding.first() {
+                            Some(&LispExpr::OpVar(n)) => Some(n),
+                            _ => None,
+                        },
+                        _ => None,
+                    })
+                    .collect();
 
-            if let Some(bf) = builtin {
-                instructions.push(builtin_instr(bf, args_len)?);
-            } else if is_self_call && is_tail_call {
-                instructions.push(Instr::Recurse(args_len));
-            } else {
-                instructions.push(Instr::EvalFunction(args_len, None));
-                inner_compile(*funk, state, instructions, var_stats)?;
-            }
+                // The variables of let* are bound in later values and those
+                // of letrec in all values
+                if mac == LispMacro::Letrec {
+                    bound.extend(binding_names.iter().cloned());
+                }
 
-            // Compiling all arguments
-            let arg_instr_vecs: Vec<_> = args
-                .into_iter()
-                .map(|expr| {
-                    let mut sub_buf = Vec::new();
-                    inner_compile(expr, state, &mut sub_buf, var_stats)?;
-                    Ok(sub_buf)
-                })
-                .collect::<Result<_, _>>()?;
+                for binding in bindings {
+                    if let LispExpr::Call(ref binding) = *binding {
+                        for value in binding.iter().skip(1) {
+                            value.free_variables(bound, names);
+                        }
 
-            let arg_skip_count = arg_instr_vecs
-                .iter()
-                .enumerate()
-                .take_while(|&(idx, buf): &(_, &Vec<_>)| {
-                    if let Instr::MoveArgument(offset) = *buf.index(0) {
-                        idx == offset.to_usize()
-                    } else {
-                        false
+                        if let (LispMacro::LetStar, Some(&LispExpr::OpVar(n))) =
+                            (mac, binding.first())
+                        {
+                            bound.push(n);
+                        }
                     }
-                })
-                .count();
+                }
 
-            for (idx, mut buf) in arg_instr_vecs.into_iter().enumerate().rev() {
-                if idx < arg_skip_count && is_tail_call {
-                    instructions.extend(buf.drain(1..));
-                } else {
-                    instructions.extend(buf.into_iter());
+                if mac == LispMacro::Let {
+                    bound.extend(binding_names);
+                }
+
+                for expr in &exprs[2..] {
+                    expr.free_variables(bound, names);
                 }
             }
+            _ => {
+                for expr in exprs {
+                    expr.free_variables(bound, names);
+                }
+            }
+        }
 
-            if is_self_call && is_tail_call {
-                // Store the number of copies that we have to be for
-                // execution time.
-                instructions[init_len] = Instr::Recurse(args_len - arg_skip_count);
-            } else if is_tail_call && !builtin.is_some() {
-                instructions[init_len] = Instr::EvalFunction(args_len, Some(arg_skip_count));
+        bound.truncate(bound_len);
+    }
+
+    /// Splits the bindings of a let expression, such as `((x 1) (y 2))`,
+    /// into names and values.
+    fn let_bindings(self) -> EvaluationResult<(Vec<InternedString>, Vec<LispExpr>)> {
+        let bindings = match self {
+            LispExpr::Call(bindings) => bindings,
+            _ => return Err(EvaluationError::MalformedDefinition),
+        };
+        let mut names = Vec::with_capacity(bindings.len());
+        let mut values = Vec::with_capacity(bindings.len());
+
+        for binding in bindings {
+            let mut binding_iter = match binding {
+                LispExpr::Call(binding) => binding.into_iter(),
+                _ => return Err(EvaluationError::MalformedDefinition),
+            };
+
+            match (
+                binding_iter.next(),
+                binding_iter.next(),
+                binding_iter.next(),
+            ) {
+                (Some(LispExpr::OpVar(name)), Some(value), None) if !names.contains(&name) => {
+                    names.push(name);
+                    values.push(value);
+                }
+                _ => return Err(EvaluationError::MalformedDefinition),
             }
         }
+
+        Ok((names, values))
     }
+}
 
-    Ok(())
+/// The parameters of a lambda
+#[derive(Debug)]
+struct Parameters {
+    /// Names of all arguments, in order. The rest argument comes last.
+    names: Vec<InternedString>,
+    arity: Arity,
+    /// Expressions for the default values of the optional arguments
+    defaults: Vec<LispExpr>,
 }
 
-fn compile_finalized_expr(
-    expr: FinalizedExpr,
-    expr_returns: bool,
-    state: &State,
-) -> EvaluationResult<Vec<Instr>> {
-    let mut instructions = Vec::with_capacity(32);
-
-    if expr_returns {
-        instructions.push(Instr::Return);
+/// Splits the argument list and body of a lambda into its parameters and a
+/// single body expression. Definitions at the start of the body become
+/// local variables and the expressions after them are evaluated in order.
+fn lambda_parts(mut exprs: Vec<LispExpr>) -> EvaluationResult<(Parameters, LispExpr)> {
+    if exprs.len() < 2 {
+        return Err(EvaluationError::ArgumentCountMismatch);
     }
 
-    inner_compile(expr, state, &mut instructions, &mut Vec::new())?;
+    let arg_list = exprs.remove(0);
+    // The last expression is never a definition
+    let definition_count = exprs[..exprs.len() - 1]
+        .iter()
+        .take_while(|e| e.is_definition())
+        .count();
+    let mut body = exprs.split_off(definition_count);
+    let body = if body.len() == 1 {
+        body.pop().unwrap()
+    } else {
+        body.insert(0, LispExpr::Macro(LispMacro::Begin));
+        LispExpr::Call(body)
+    };
 
-    Ok(instructions)
+    Ok((
+        arg_list.lambda_arguments()?,
+        internal_definitions(exprs, body)?,
+    ))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::parse::{parse_lisp_string, ParseError};
-    use super::*;
-    use quickcheck::{Arbitrary, Gen};
-    use std::convert::From;
+/// Finalizes expressions that are evaluated in order. Only the last one
+/// can tail call.
+fn finalize_sequence(
+    mut exprs: Vec<LispExpr>,
+    ctx: &mut FinalizationContext,
+) -> EvaluationResult<(FinalizedExpr, bool)> {
+    let (last, returns) = match exprs.pop() {
+        Some(last) => last.finalize(ctx)?,
+        None => return Err(EvaluationError::ArgumentCountMismatch),
+    };
 
-    #[derive(Debug, PartialEq, Eq)]
-    enum LispError {
-        Parse(ParseError),
-        Evaluation(EvaluationError),
+    if exprs.is_empty() {
+        return Ok((last, returns));
     }
 
-    impl From<EvaluationError> for LispError {
-        fn from(err: EvaluationError) -> LispError {
-            LispError::Evaluation(err)
+    ctx.tail_call_status = TailCallStatus::CannotTailCall;
+    let mut finalized_exprs = Vec::with_capacity(exprs.len() + 1);
+    finalized_exprs.push(last);
+
+    for expr in exprs.into_iter().rev() {
+        finalized_exprs.push(expr.finalize(ctx)?.0);
+    }
+    finalized_exprs.reverse();
+
+    Ok((FinalizedExpr::Begin(finalized_exprs), returns))
+}
+
+/// Lowers definitions inside a lambda body to let expressions around the
+/// final expression of the body.
+fn internal_definitions(definitions: Vec<LispExpr>, body: LispExpr) -> EvaluationResult<LispExpr> {
+    let mut names = Vec::with_capacity(definitions.len());
+    let mut values = Vec::with_capacity(definitions.len());
+
+    for definition in definitions {
+        let mut def_iter = match definition {
+            LispExpr::Call(expr_list) => expr_list.into_iter().skip(1),
+            _ => unreachable!(),
+        };
+
+        match destructure!(def_iter, [name, value], (name, value)) {
+            // Like global variables, local ones cannot be redefined
+            (LispExpr::OpVar(name), value) if !names.contains(&name) => {
+                names.push(name);
+                values.push(value);
+            }
+            _ => return Err(EvaluationError::BadDefine),
         }
     }
 
-    impl From<ParseError> for LispError {
-        fn from(err: ParseError) -> LispError {
-            LispError::Parse(err)
+    bind_in_dependency_order(names, values, body)
+}
+
+/// Binds values to names around a body. Every value is bound after the
+/// values that it refers to, so it can refer to later ones as well.
+/// Functions that refer to each other are bound by a single letrec, while
+/// other values cannot be part of such a cycle.
+fn bind_in_dependency_order(
+    names: Vec<InternedString>,
+    values: Vec<LispExpr>,
+    body: LispExpr,
+) -> EvaluationResult<LispExpr> {
+    let mut values: Vec<_> = values.into_iter().map(Some).collect();
+
+    // Whether a definition depends on another one, directly or not
+    let mut depends: Vec<Vec<bool>> = values
+        .iter()
+        .map(|value| {
+            let mut refs = Vec::new();
+            value
+                .as_ref()
+                .unwrap()
+                .free_variables(&mut Vec::new(), &mut refs);
+            names.iter().map(|n| refs.contains(n)).collect()
+        })
+        .collect();
+
+    for k in 0..names.len() {
+        let through = depends[k].clone();
+
+        for row in depends.iter_mut().filter(|row| row[k]) {
+            for (dependency, &indirect) in row.iter_mut().zip(&through) {
+                *dependency |= indirect;
+            }
         }
     }
 
-    fn check_lisp<'i, I>(state: &mut State, commands: I) -> Result<LispValue, LispError>
-    where
-        I: IntoIterator<Item = &'i str>,
+    let mut done = vec![false; names.len()];
+    let mut groups: Vec<(LispMacro, Vec<LispExpr>)> = Vec::new();
+
+    // Definitions are bound in order, unless they depend on later ones
+    while let Some(first) = (0..names.len())
+        .find(|&i| !done[i] && (0..names.len()).all(|j| done[j] || !depends[i][j] || depends[j][i]))
     {
-        let mut last_ret_val = None;
+        let cycle: Vec<_> = (0..names.len())
+            .filter(|&j| j == first || !done[j] && depends[first][j] && depends[j][first])
+            .collect();
 
-        for cmd in commands {
-            let expr = parse_lisp_string(cmd, state)?;
-            last_ret_val = Some(evaluator::eval(expr, state)?);
+        // Functions with optional or rest arguments cannot be called through
+        // their group, so they do not become part of a letrec
+        let mac = if cycle.iter().all(|&j| {
+            let value = values[j].as_ref().unwrap();
+            value.is_lambda() && value.has_fixed_arity()
+        }) {
+            LispMacro::Letrec
+        } else if cycle.len() == 1 {
+            LispMacro::LetStar
+        } else {
+            return Err(EvaluationError::BadDefine);
+        };
+
+        for j in cycle {
+            done[j] = true;
+            let binding =
+                LispExpr::Call(vec![LispExpr::OpVar(names[j]), values[j].take().unwrap()]);
+
+            match groups.last_mut() {
+                Some(&mut (group_mac, ref mut bindings)) if group_mac == mac => {
+                    bindings.push(binding)
+                }
+                _ => groups.push((mac, vec![binding])),
+            }
         }
+    }
 
-        Ok(last_ret_val.unwrap())
+    Ok(groups
+        .into_iter()
+        .rev()
+        .fold(body, |body, (mac, bindings)| {
+            LispExpr::Call(vec![LispExpr::Macro(mac), LispExpr::Call(bindings), body])
+        }))
+}
+
+/// Finalizes the body of a lambda with the given parameters. When the
+/// lambda is part of a letrec group, its first arguments are the functions
+/// of that group. Default values are evaluated when the lambda is created.
+fn finalize_lambda(
+    params: Parameters,
+    group: Option<Vec<usize>>,
+    own_name: Option<InternedString>,
+    body: LispExpr,
+    ctx: &mut FinalizationContext,
+) -> EvaluationResult<FinalizedExpr> {
+    // Append arguments to the arguments map. Since we're doing
+    // symbol lookup in reverse orders, this guarantees that
+    // variables with the same symbol will use the highest
+    // scope.
+    let Parameters {
+        names: arguments,
+        arity,
+        defaults,
+    } = params;
+    let num_args = arguments.len();
+    let arguments_len = ctx.arguments.len();
+    let group_size = group.as_ref().map_or(0, Vec::len);
+    let is_group = group.is_some();
+    ctx.arguments.reserve(num_args);
+
+    for (offset, symbol) in arguments.into_iter().enumerate() {
+        // The functions of a group are passed to every call
+        let constraint = if offset < group_size {
+            VariableConstraint::NeedFull
+        } else {
+            VariableConstraint::Unconstrained
+        };
+
+        ctx.arguments.push((
+            symbol,
+            (ctx.scope_level, StackOffset::from(offset), constraint),
+        ));
     }
 
-    fn check_lisp_ok<'i, I>(commands: I, expected_out: &str)
-    where
-        I: IntoIterator<Item = &'i str>,
-    {
-        let mut state = State::default();
-        let val = check_lisp(&mut state, commands).unwrap();
-        assert_eq!(expected_out, print::print_value(&val, &state, 0));
+    if let Some(arg_counts) = group {
+        ctx.groups
+            .push((ctx.scope_level, StackOffset::default(), arg_counts));
     }
 
-    fn check_lisp_err<'i, I>(commands: I, expected_err: LispError)
-    where
-        I: IntoIterator<Item = &'i str>,
+    // Update context for lambda
+    let orig_scope_level = ctx.scope_level;
+    let current_tail_status = ctx.tail_call_status;
+    let orig_stack_depth = ctx.stack_depth;
+    // Functions with optional or rest arguments take a different number of
+    // values than they were called with, so they cannot recurse in place
+    let orig_name = replace(
+        &mut ctx.own_name,
+        own_name
+            .filter(|_| arity.is_fixed())
+            .map(|name| (name, num_args)),
+    );
+    ctx.scope_level = ctx.scope_level.next();
+    ctx.tail_call_status = TailCallStatus::CanTailCall;
+    ctx.stack_depth = num_args;
+
+    let (finalized_body, returns) = body.finalize(ctx)?;
+
+    // TODO: here we can check whether this is not a tail call
+    // but all arguments were moved!
+
+    // Reset context to original state
+    ctx.scope_level = orig_scope_level;
+    ctx.tail_call_status = current_tail_status;
+    ctx.stack_depth = orig_stack_depth;
+    ctx.own_name = orig_name;
+    ctx.arguments.truncate(arguments_len);
+
+    if is_group {
+        ctx.groups.pop();
+    }
+
+    // The default values are pushed to the stack in order, before the lambda
+    // is created
+    ctx.tail_call_status = TailCallStatus::CannotTailCall;
+    let mut finalized_defaults = Vec::with_capacity(defaults.len());
+
+    for (index, default) in defaults.into_iter().enumerate().rev() {
+        ctx.stack_depth = orig_stack_depth + index;
+        finalized_defaults.push(default.finalize(ctx)?.0);
+    }
+    finalized_defaults.reverse();
+    ctx.tail_call_status = current_tail_status;
+    ctx.stack_depth = orig_stack_depth;
+
+    Ok(FinalizedExpr::Lambda(
+        arity,
+        orig_scope_level,
+        Box::new(finalized_body),
+        returns,
+        finalized_defaults,
+    ))
+}
+
+/// Finalizes a conditional expression. Like in function calls, the parts
+/// are finalized in the reverse order of their evaluation, so that the last
+/// uses of arguments can move them.
+fn finalize_if<C, T, F>(
+    finalize_test: C,
+    finalize_true: T,
+    finalize_false: F,
+    ctx: &mut FinalizationContext,
+) -> EvaluationResult<(FinalizedExpr, bool)>
+where
+    C: FnOnce(&mut FinalizationContext) -> EvaluationResult<FinalizedExpr>,
+    T: FnOnce(&mut FinalizationContext) -> EvaluationResult<(FinalizedExpr, bool)>,
+    F: FnOnce(&mut FinalizationContext) -> EvaluationResult<(FinalizedExpr, bool)>,
+{
+    let could_tail_call = ctx.tail_call_status;
+    let false_expr_args = ctx.arguments.clone();
+    let mut false_expr_ctx = FinalizationContext {
+        arguments: false_expr_args,
+        groups: ctx.groups.clone(),
+        ..*ctx
+    };
+    let (finalized_false_expr, _false_returns) = finalize_false(&mut false_expr_ctx)?;
+    let (finalized_true_expr, true_returns) = finalize_true(ctx)?;
+
+    // Move analysis: a function argument is still moveable
+    // when it has been moved in neither the true branch or
+    // the false branch.
+    for (&mut (_, (_, _, ref mut arg_true)), &(_, (_, _, arg_false))) in ctx
+        .arguments
+        .iter_mut()
+        .zip(false_expr_ctx.arguments.iter())
     {
-        let mut state = State::default();
-        assert_eq!(expected_err, check_lisp(&mut state, commands).unwrap_err());
+        *arg_true = arg_false.combine(*arg_true);
     }
 
-    fn get_bytecode(definition: &str, self_name: &str) -> Vec<Instr> {
-        let mut state = State::default();
-        check_lisp(
-            &mut state,
-            vec![&format!("(define {} 1337)", self_name)[..]],
-        )
-        .unwrap();
-        let intern = state.intern(self_name);
-        let expr = super::parse::parse_lisp_string(definition, &mut state).unwrap();
-        let mut finalization_ctx = super::FinalizationContext::new(Some(intern));
-        let (finalized_expr, returns) = expr.finalize(&mut finalization_ctx).unwrap();
+    // The test expression cannot ever tail call!
+    // TODO: add test for this!
+    ctx.tail_call_status = TailCallStatus::CannotTailCall;
+
+    Ok((
+        FinalizedExpr::Cond(
+            Box::new((
+                finalize_test(ctx)?,
+                finalized_true_expr,
+                finalized_false_expr,
+            )),
+            true_returns,
+            could_tail_call,
+        ),
+        could_tail_call == TailCallStatus::CannotTailCall,
+    ))
+}
 
-        if let FinalizedExpr::Lambda(.., body, returns) = finalized_expr {
-            super::compile_finalized_expr(*body, returns, &mut state).unwrap()
-        } else {
-            super::compile_finalized_expr(finalized_expr, returns, &mut state).unwrap()
+/// Finalizes the clauses of a cond expression as nested conditionals.
+/// When no clause applies, the result is the empty list.
+fn finalize_clauses(
+    mut clauses: vec::IntoIter<LispExpr>,
+    ctx: &mut FinalizationContext,
+) -> EvaluationResult<(FinalizedExpr, bool)> {
+    let mut clause = match clauses.next() {
+        Some(LispExpr::Call(clause)) => clause.into_iter(),
+        Some(_) => return Err(EvaluationError::ArgumentTypeMismatch),
+        None => return Ok((FinalizedExpr::Value(LispValue::List(Vec::new())), true)),
+    };
+
+    let test_expr = match clause.next() {
+        Some(LispExpr::Macro(LispMacro::Else)) => {
+            // The else clause must be the last
+            if clauses.len() > 0 {
+                return Err(EvaluationError::UnexpectedOperator);
+            }
+            return finalize_sequence(clause.collect(), ctx);
+        }
+        Some(test_expr) => test_expr,
+        None => return Err(EvaluationError::ArgumentCountMismatch),
+    };
+    let body: Vec<_> = clause.collect();
+
+    match body.first() {
+        // Clauses without a body return the value of their test
+        None => finalize_test_clause(test_expr, None, clauses, ctx),
+        Some(&LispExpr::Macro(LispMacro::Arrow)) => {
+            let mut body_iter = body.into_iter().skip(1);
+            let receiver = destructure!(body_iter, [receiver], receiver);
+            finalize_test_clause(test_expr, Some(receiver), clauses, ctx)
         }
+        Some(_) => finalize_if(
+            |ctx| Ok(test_expr.finalize(ctx)?.0),
+            |ctx| finalize_sequence(body, ctx),
+            |ctx| finalize_clauses(clauses, ctx),
+            ctx,
+        ),
     }
+}
 
-    impl Arbitrary for LispValue {
-        fn arbitrary(g: &mut Gen) -> LispValue {
-            enum ValueVariant {
-                List,
-                Int,
-                Bool,
-                Func,
+/// Finalizes the operands of `and` or `or` as nested conditionals, so that
+/// an operand is only evaluated when the result is not known yet. The last
+/// operand is in tail position.
+fn finalize_connective(
+    is_and: bool,
+    mut operands: vec::IntoIter<LispExpr>,
+    ctx: &mut FinalizationContext,
+) -> EvaluationResult<(FinalizedExpr, bool)> {
+    let operand = match operands.next() {
+        Some(operand) => operand,
+        // Without operands, and is true and or is false
+        None => return Ok((FinalizedExpr::Value(LispValue::Boolean(is_and)), true)),
+    };
+
+    if operands.as_slice().is_empty() {
+        return operand.finalize(ctx);
+    }
+
+    let finalize_test = |ctx: &mut FinalizationContext| Ok(operand.finalize(ctx)?.0);
+    let finalize_rest = |ctx: &mut FinalizationContext| finalize_connective(is_and, operands, ctx);
+    let finalize_result =
+        |_: &mut FinalizationContext| Ok((FinalizedExpr::Value(LispValue::Boolean(!is_and)), true));
+
+    if is_and {
+        finalize_if(finalize_test, finalize_rest, finalize_result, ctx)
+    } else {
+        finalize_if(finalize_test, finalize_result, finalize_rest, ctx)
+    }
+}
+
+/// Finalizes a cond clause that uses the value of its test. This value is
+/// kept in a local variable, so that it can be returned or passed to the
+/// receiving function. Any value but false selects the clause.
+fn finalize_test_clause(
+    test_expr: LispExpr,
+    receiver: Option<LispExpr>,
+    clauses: vec::IntoIter<LispExpr>,
+    ctx: &mut FinalizationContext,
+) -> EvaluationResult<(FinalizedExpr, bool)> {
+    let scope = ctx.local_scope();
+    let depth = ctx.stack_depth;
+    let offset = StackOffset::from(depth);
+    let test_value = FinalizedExpr::Argument(offset, scope, VariableConstraint::NeedFull);
+
+    let is_false = FinalizedExpr::FunctionCall(
+        Box::new(FinalizedExpr::Value(LispValue::Function(
+            LispFunc::BuiltIn(BuiltIn::CheckEqual),
+        ))),
+        vec![
+            test_value.clone(),
+            FinalizedExpr::Value(LispValue::Boolean(false)),
+        ],
+        false,
+        false,
+    );
+
+    ctx.stack_depth = depth + 1;
+    let (finalized_cond, returns) = finalize_if(
+        |_| Ok(is_false),
+        |ctx| finalize_clauses(clauses, ctx),
+        |ctx| match receiver {
+            Some(receiver) => {
+                let is_tail_call = ctx.tail_call_status == TailCallStatus::CanTailCall;
+                ctx.tail_call_status = TailCallStatus::CannotTailCall;
+                ctx.stack_depth = depth + 2;
+                let funk = receiver.finalize(ctx)?.0;
+                ctx.stack_depth = depth + 1;
+                let is_builtin = matches!(
+                    funk,
+                    FinalizedExpr::Value(LispValue::Function(LispFunc::BuiltIn(..)))
+                );
+
+                Ok((
+                    FinalizedExpr::FunctionCall(
+                        Box::new(funk),
+                        vec![test_value.clone()],
+                        is_tail_call,
+                        false,
+                    ),
+                    !is_tail_call || is_builtin,
+                ))
             }
+            None => Ok((test_value.clone(), true)),
+        },
+        ctx,
+    )?;
+    ctx.stack_depth = depth;
+    ctx.tail_call_status = TailCallStatus::CannotTailCall;
+
+    Ok((
+        FinalizedExpr::Let(
+            scope,
+            offset,
+            vec![test_expr.finalize(ctx)?.0],
+            Box::new(finalized_cond),
+        ),
+        returns,
+    ))
+}
 
-            let choices = [
-                ValueVariant::List,
-                ValueVariant::Int,
-                ValueVariant::Bool,
-                ValueVariant::Func,
-            ];
-            let variant = g.choose(&choices).unwrap();
+/// The position of a part of a list. Used for pattern matching.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+struct ListPath {
+    /// Indices of the nested elements that lead to the part. The car of a
+    /// list and the first field of a record have index zero.
+    indices: Vec<usize>,
+    /// Number of cdrs taken of the list at the indices. When non-zero, the
+    /// part is the list of the remaining elements.
+    dropped: usize,
+}
 
-            match *variant {
-                ValueVariant::Int => LispValue::Integer(u64::arbitrary(g)),
-                ValueVariant::Bool => LispValue::Boolean(bool::arbitrary(g)),
-                ValueVariant::List => {
-                    // We shouldn't generate lists too long too often,
-                    // or this procedure will not terminate with finite
-                    // probability.
-                    let max_len = choices.len();
-                    let len = g.size() % max_len;
-                    let mut vek = Vec::new();
+impl ListPath {
+    fn element(&self, index: usize) -> ListPath {
+        let mut indices = self.indices.clone();
+        indices.push(self.dropped + index);
 
-                    for _ in 0..len {
-                        vek.push(Arbitrary::arbitrary(g));
+        ListPath {
+            indices,
+            dropped: 0,
+        }
+    }
+
+    fn without_first(&self, count: usize) -> ListPath {
+        ListPath {
+            indices: self.indices.clone(),
+            dropped: self.dropped + count,
+        }
+    }
+}
+
+/// A test on part of a value. Used for pattern matching.
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum PartTest {
+    /// The part is a list of the given length. The bool states whether
+    /// it may be longer.
+    Length(usize, bool),
+    /// The part is equal to the value
+    Equal(LispValue),
+    /// The part is a record of the given type
+    Record(InternedString),
+}
+
+/// The tests that a value must pass to match a pattern, and the parts
+/// of the value that are bound to the names in the pattern.
+#[derive(Default)]
+struct MatchPattern {
+    tests: Vec<(ListPath, PartTest)>,
+    bindings: Vec<(InternedString, ListPath)>,
+}
+
+impl MatchPattern {
+    /// Reads the pattern for the part of the value at the given path. List
+    /// patterns are read like nested conses, so the first element matches
+    /// the car of the list. A pattern after `.` matches the list of the
+    /// remaining elements. A list pattern that starts with a record
+    /// constructor matches the records it creates, and its other elements
+    /// match their fields.
+    fn read(&mut self, pattern: LispExpr, path: ListPath) -> EvaluationResult<()> {
+        match pattern {
+            LispExpr::Macro(LispMacro::Wildcard) => {}
+            LispExpr::OpVar(name) => {
+                if self.bindings.iter().any(|&(n, _)| n == name) {
+                    return Err(EvaluationError::MalformedDefinition);
+                }
+
+                self.bindings.push((name, path));
+            }
+            LispExpr::Value(LispValue::Function(..)) => {
+                return Err(EvaluationError::MalformedDefinition)
+            }
+            LispExpr::Value(v) => self.tests.push((path, PartTest::Equal(v))),
+            LispExpr::Call(mut exprs) => {
+                if exprs.first() == Some(&LispExpr::Macro(LispMacro::Quote)) {
+                    let mut datum_iter = exprs.into_iter().skip(1);
+                    let datum = destructure!(datum_iter, [datum], datum.into_datum()?);
+                    self.tests.push((path, PartTest::Equal(datum)));
+                    return Ok(());
+                }
+
+                if let Some(&LispExpr::Value(LispValue::Function(LispFunc::BuiltIn(
+                    BuiltIn::MakeRecord(name, count),
+                )))) = exprs.first()
+                {
+                    if exprs.len() != count + 1 {
+                        return Err(EvaluationError::MalformedDefinition);
                     }
 
-                    LispValue::List(vek)
+                    self.tests.push((path.clone(), PartTest::Record(name)));
+
+                    for (index, field) in exprs.into_iter().skip(1).enumerate() {
+                        self.read(field, path.element(index))?;
+                    }
+
+                    return Ok(());
                 }
-                ValueVariant::Func => {
-                    let f = LispFunc::BuiltIn(BuiltIn::AddOne);
-                    LispValue::Function(f)
+
+                let rest = match exprs
+                    .iter()
+                    .position(|e| *e == LispExpr::Macro(LispMacro::Dot))
+                {
+                    Some(index) if index + 2 == exprs.len() => {
+                        let rest = exprs.pop();
+                        exprs.pop();
+                        rest
+                    }
+                    Some(..) => return Err(EvaluationError::MalformedDefinition),
+                    None => None,
+                };
+                let count = exprs.len();
+                self.tests
+                    .push((path.clone(), PartTest::Length(count, rest.is_some())));
+
+                for (index, element) in exprs.into_iter().enumerate() {
+                    self.read(element, path.element(index))?;
+                }
+
+                if let Some(rest) = rest {
+                    self.read(rest, path.without_first(count))?;
                 }
             }
+            LispExpr::Macro(..) => return Err(EvaluationError::MalformedDefinition),
         }
+
+        Ok(())
     }
+}
 
-    // Quickcheck tests
-    #[quickcheck]
-    fn quickcheck_identity(x: LispValue) -> bool {
-        let mut state = Default::default();
-        let expr = LispExpr::Call(vec![
-            parse_lisp_string("(lambda (x) x)", &mut state).unwrap(),
-            LispExpr::Value(x.clone()),
-        ]);
+/// Finalizes a match expression. The value is kept in a local variable
+/// and every clause tests its pattern on it, from first to last.
+fn finalize_match(
+    value: LispExpr,
+    clauses: vec::IntoIter<LispExpr>,
+    ctx: &mut FinalizationContext,
+) -> EvaluationResult<(FinalizedExpr, bool)> {
+    let scope = ctx.local_scope();
+    let depth = ctx.stack_depth;
+    let offset = StackOffset::from(depth);
+
+    ctx.stack_depth = depth + 1;
+    let (finalized_clauses, returns) = finalize_match_clauses(offset, clauses, ctx)?;
+    ctx.stack_depth = depth;
+    ctx.tail_call_status = TailCallStatus::CannotTailCall;
+
+    Ok((
+        FinalizedExpr::Let(
+            scope,
+            offset,
+            vec![value.finalize(ctx)?.0],
+            Box::new(finalized_clauses),
+        ),
+        returns,
+    ))
+}
 
-        let result = evaluator::eval(expr, &mut state).unwrap();
+/// Finalizes the clauses of a match expression as nested conditionals. A
+/// clause is a pattern, optionally followed by `when` and a guard, and
+/// a body. The names in the pattern are bound in the guard and the body.
+fn finalize_match_clauses(
+    offset: StackOffset,
+    mut clauses: vec::IntoIter<LispExpr>,
+    ctx: &mut FinalizationContext,
+) -> EvaluationResult<(FinalizedExpr, bool)> {
+    let mut clause_iter = match clauses.next() {
+        Some(LispExpr::Call(clause)) => clause.into_iter(),
+        Some(..) => return Err(EvaluationError::ArgumentTypeMismatch),
+        None => return Ok((FinalizedExpr::NoMatch, true)),
+    };
+    let mut pattern = MatchPattern::default();
+    pattern.read(
+        clause_iter
+            .next()
+            .ok_or(EvaluationError::ArgumentCountMismatch)?,
+        ListPath::default(),
+    )?;
+
+    let guard = if clause_iter.as_slice().first() == Some(&LispExpr::Macro(LispMacro::When)) {
+        clause_iter.next();
+        Some(
+            clause_iter
+                .next()
+                .ok_or(EvaluationError::ArgumentCountMismatch)?,
+        )
+    } else {
+        None
+    };
+    let body: Vec<_> = clause_iter.collect();
+    let MatchPattern { tests, bindings } = pattern;
+    let (names, paths): (Vec<_>, Vec<_>) = bindings.into_iter().unzip();
+
+    // Once a clause applies, the value is no longer needed by the others, so
+    // the body can move the parts out of it
+    let finalize_body = |ctx: &mut FinalizationContext| {
+        finalize_let(
+            names.clone(),
+            paths.clone(),
+            None,
+            |path, _, _| Ok(FinalizedExpr::Part(offset, path, true)),
+            |ctx| finalize_sequence(body, ctx),
+            ctx,
+        )
+    };
 
-        result == x
+    // Clauses that always apply make the ones after them unreachable
+    if tests.is_empty() && guard.is_none() {
+        return finalize_body(ctx);
     }
 
-    #[test]
-    fn add_bytecode() {
-        let bytecode = get_bytecode(
-            "(lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y))))",
-            "add",
-        );
+    finalize_if(
+        |ctx| {
+            let guard_expr = match guard {
+                Some(guard) => {
+                    finalize_let(
+                        names.clone(),
+                        paths.clone(),
+                        None,
+                        |path, _, _| Ok(FinalizedExpr::Part(offset, path, false)),
+                        |ctx| guard.finalize(ctx),
+                        ctx,
+                    )?
+                    .0
+                }
+                None => FinalizedExpr::Value(LispValue::Boolean(true)),
+            };
 
-        assert_eq!(
-            bytecode,
-            vec![
-                Instr::Return,
-                Instr::MoveArgument(From::from(0)),
-                Instr::Return,
-                Instr::Recurse(0),
-                Instr::VarAddOne(From::from(0)),
-                Instr::CondZeroJumpDecr(From::from(1), 3),
-            ]
-        );
+            // The tests are combined like an and expression
+            Ok(tests
+                .into_iter()
+                .rev()
+                .fold(guard_expr, |rest, (path, test)| {
+                    if rest == FinalizedExpr::Value(LispValue::Boolean(true)) {
+                        FinalizedExpr::TestPart(offset, path, test)
+                    } else {
+                        FinalizedExpr::Cond(
+                            Box::new((
+                                FinalizedExpr::TestPart(offset, path, test),
+                                rest,
+                                FinalizedExpr::Value(LispValue::Boolean(false)),
+                            )),
+                            true,
+                            TailCallStatus::CannotTailCall,
+                        )
+                    }
+                }))
+        },
+        finalize_body,
+        |ctx| finalize_match_clauses(offset, clauses, ctx),
+        ctx,
+    )
+}
+
+/// Finalizes a try expression. The handler is evaluated before the body
+/// and kept on the stack while the body is evaluated.
+fn finalize_try(
+    body: LispExpr,
+    handler: LispExpr,
+    ctx: &mut FinalizationContext,
+) -> EvaluationResult<(FinalizedExpr, bool)> {
+    let depth = ctx.stack_depth;
+
+    // The body may be left at any point, so it cannot remove parts of
+    // variables that are used later on
+    for &mut (_, (_, _, ref mut constraint)) in &mut ctx.arguments {
+        *constraint = constraint.combine(VariableConstraint::Unconstrained);
     }
 
-    #[test]
-    fn map_bytecode() {
-        let bytecode = get_bytecode(
-            "(lambda (f xs) (cond (null? xs) xs (cons (f (car xs)) (map f (cdr xs)))))",
-            "map",
-        );
+    ctx.stack_depth = depth + 1;
+    ctx.tail_call_status = TailCallStatus::CannotTailCall;
+    let (finalized_body, _) = body.finalize(ctx)?;
+    ctx.stack_depth = depth;
+    ctx.tail_call_status = TailCallStatus::CannotTailCall;
+    let (finalized_handler, _) = handler.finalize(ctx)?;
+
+    Ok((
+        FinalizedExpr::Try(Box::new(finalized_handler), Box::new(finalized_body)),
+        true,
+    ))
+}
 
-        assert_eq!(
-            bytecode,
-            vec![
-                Instr::Return,
-                Instr::MoveArgument(From::from(1)),
-                Instr::Return,
-                Instr::Cons,
-                Instr::EvalFunction(2, None),
-                // 1337 is the magic number representing the function itself
-                Instr::PushValue(LispValue::Integer(1337)),
-                Instr::MoveArgument(From::from(1)),
-                Instr::MoveArgument(From::from(0)),
-                Instr::EvalFunction(1, None),
-                Instr::CloneArgument(From::from(0)),
-                Instr::VarSplit(From::from(1)),
-                Instr::CondJump(9),
-                Instr::VarCheckNull(From::from(1)),
-            ]
-        );
+/// Finalizes a let expression. Its values are pushed to the stack, where
+/// the body can use them as local variables. For letrec expressions, the
+/// arguments counts of the group are given.
+fn finalize_let<V, F, B>(
+    names: Vec<InternedString>,
+    values: Vec<V>,
+    group: Option<Vec<usize>>,
+    mut finalize_value: F,
+    finalize_body: B,
+    ctx: &mut FinalizationContext,
+) -> EvaluationResult<(FinalizedExpr, bool)>
+where
+    F: FnMut(V, usize, &mut FinalizationContext) -> EvaluationResult<FinalizedExpr>,
+    B: FnOnce(&mut FinalizationContext) -> EvaluationResult<(FinalizedExpr, bool)>,
+{
+    if names.is_empty() {
+        return finalize_body(ctx);
     }
 
-    #[test]
-    fn comp_bytecode() {
-        let bytecode = get_bytecode(
-            "(lambda (x y) (cond (zero? x) #f (cond (zero? y) #t (> (sub1 x) (sub1 y)))))",
-            ">",
-        );
-        assert_eq!(
-            bytecode,
-            vec![
-                Instr::Return,
-                Instr::PushValue(LispValue::Boolean(false)),
-                Instr::Return,
-                Instr::Return,
+    let scope = ctx.local_scope();
+    let depth = ctx.stack_depth;
+    let offset = StackOffset::from(depth);
+    let arguments_len = ctx.arguments.len();
+    let is_group = group.is_some();
+    let constraint = if is_group {
+        VariableConstraint::NeedFull
+    } else {
+        VariableConstraint::Unconstrained
+    };
+
+    for (idx, &name) in names.iter().enumerate() {
+        ctx.arguments
+            .push((name, (scope, StackOffset::from(depth + idx), constraint)));
+    }
+
+    if let Some(arg_counts) = group {
+        ctx.groups.push((scope, offset, arg_counts));
+    }
+
+    ctx.stack_depth = depth + names.len();
+    let (finalized_body, returns) = finalize_body(ctx)?;
+    ctx.arguments.truncate(arguments_len);
+    ctx.tail_call_status = TailCallStatus::CannotTailCall;
+
+    if is_group {
+        ctx.groups.pop();
+    }
+
+    // Like function arguments, the values are finalized from last to first
+    let mut finalized_values = Vec::with_capacity(values.len());
+
+    for (idx, value) in values.into_iter().enumerate().rev() {
+        ctx.stack_depth = depth + idx;
+        finalized_values.push(finalize_value(value, idx, ctx)?);
+    }
+    finalized_values.reverse();
+    ctx.stack_depth = depth;
+
+    Ok((
+        FinalizedExpr::Let(scope, offset, finalized_values, Box::new(finalized_body)),
+        returns,
+    ))
+}
+
+/// Finalizes a let* expression as nested let expressions.
+fn finalize_let_star(
+    mut names: Vec<InternedString>,
+    mut values: Vec<LispExpr>,
+    body: LispExpr,
+    ctx: &mut FinalizationContext,
+) -> EvaluationResult<(FinalizedExpr, bool)> {
+    let rest = if names.len() > 1 {
+        Some((names.split_off(1), values.split_off(1)))
+    } else {
+        None
+    };
+
+    finalize_let(
+        names,
+        values,
+        None,
+        |value, _, ctx| Ok(value.finalize(ctx)?.0),
+        |ctx| match rest {
+            Some((rest_names, rest_values)) => {
+                finalize_let_star(rest_names, rest_values, body, ctx)
+            }
+            None => body.finalize(ctx),
+        },
+        ctx,
+    )
+}
+
+/// The arguments that pass a group of letrec functions to one of its members
+fn group_arguments(
+    scope: Scope,
+    base: StackOffset,
+    size: usize,
+) -> impl DoubleEndedIterator<Item = FinalizedExpr> {
+    (0..size).map(move |idx| {
+        FinalizedExpr::Argument(
+            base + StackOffset::from(idx),
+            scope,
+            VariableConstraint::NeedFull,
+        )
+    })
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum VariableConstraint {
+    NeedFull,
+    Unconstrained,
+    RemovedTail,
+    RemovedHead,
+}
+
+impl VariableConstraint {
+    fn combine(self, other: Self) -> Self {
+        if self == other {
+            self
+        } else {
+            VariableConstraint::NeedFull
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum EvaluationError {
+    UnexpectedOperator,
+    ArgumentCountMismatch,
+    ArgumentTypeMismatch,
+    EmptyListEvaluation,
+    NonFunctionApplication,
+    DivisionByZero,
+    IndexOutOfRange,
+    InvalidCodePoint,
+    EmptyList,
+    UnknownVariable(String),
+    MalformedDefinition,
+    BadDefine,
+    /// None of the rules of a syntax-rules macro matches its use
+    NoMatchingRule,
+    /// A macro call was still expanding after the maximum number of
+    /// expansions
+    ExpansionLimit,
+    /// None of the clauses of a match expression matches its value
+    NoMatch,
+    /// A value was raised and not caught
+    Raised(LispValue),
+}
+
+/// The names of the evaluation errors, as seen by lisp code.
+const ERROR_KINDS: [&str; 16] = [
+    "unexpected-operator",
+    "argument-count-mismatch",
+    "argument-type-mismatch",
+    "empty-list-evaluation",
+    "non-function-application",
+    "division-by-zero",
+    "index-out-of-range",
+    "invalid-code-point",
+    "empty-list",
+    "unknown-variable",
+    "malformed-definition",
+    "bad-define",
+    "no-matching-rule",
+    "expansion-limit",
+    "no-match",
+    "raised",
+];
+
+impl EvaluationError {
+    /// The name of the error in `ERROR_KINDS`. It does not depend on the
+    /// Rust representation, and is passed to the handlers of try
+    /// expressions as a symbol.
+    pub fn kind(&self) -> &'static str {
+        ERROR_KINDS[match *self {
+            EvaluationError::UnexpectedOperator => 0,
+            EvaluationError::ArgumentCountMismatch => 1,
+            EvaluationError::ArgumentTypeMismatch => 2,
+            EvaluationError::EmptyListEvaluation => 3,
+            EvaluationError::NonFunctionApplication => 4,
+            EvaluationError::DivisionByZero => 5,
+            EvaluationError::IndexOutOfRange => 6,
+            EvaluationError::InvalidCodePoint => 7,
+            EvaluationError::EmptyList => 8,
+            EvaluationError::UnknownVariable(..) => 9,
+            EvaluationError::MalformedDefinition => 10,
+            EvaluationError::BadDefine => 11,
+            EvaluationError::NoMatchingRule => 12,
+            EvaluationError::ExpansionLimit => 13,
+            EvaluationError::NoMatch => 14,
+            EvaluationError::Raised(..) => 15,
+        }]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LispValue {
+    Boolean(bool),
+    Integer(i64),
+    /// Integers that do not fit in 64 bits. Smaller integers are never
+    /// represented this way.
+    BigInteger(BigInt),
+    String(String),
+    Char(char),
+    Symbol(InternedString),
+    Function(LispFunc),
+    List(Vec<LispValue>),
+    /// A record of the type with the given name. Its fields are in the
+    /// order of their definition.
+    Record(InternedString, Vec<LispValue>),
+}
+
+impl LispValue {
+    fn get_type(&self) -> ArgType {
+        match *self {
+            LispValue::Boolean(..) => ArgType::Boolean,
+            LispValue::Integer(..) | LispValue::BigInteger(..) => ArgType::Integer,
+            LispValue::Function(..) => ArgType::Function,
+            LispValue::List(..) => ArgType::List,
+            LispValue::String(..) => ArgType::String,
+            LispValue::Char(..) => ArgType::Char,
+            LispValue::Symbol(..) => ArgType::Symbol,
+            LispValue::Record(..) => ArgType::Record,
+        }
+    }
+
+    /// Whether the value can be the same as another one for `eq?`. Strings,
+    /// non-empty lists and records are compared by their contents, so they
+    /// are left to `equal?`.
+    fn is_atom(&self) -> bool {
+        match *self {
+            LispValue::String(..) | LispValue::Record(..) => false,
+            LispValue::List(ref l) => l.is_empty(),
+            _ => true,
+        }
+    }
+}
+
+fn builtin_instr(f: BuiltIn, arg_count: usize) -> EvaluationResult<Instr> {
+    Ok(match (f, arg_count) {
+        (BuiltIn::AddOne, 1) => Instr::AddOne,
+        (BuiltIn::SubOne, 1) => Instr::SubOne,
+        (BuiltIn::CheckZero, 1) => Instr::CheckZero,
+        (BuiltIn::CheckNull, 1) => Instr::CheckNull,
+        (BuiltIn::List, _) => Instr::List(arg_count),
+        (BuiltIn::Cons, 2) => Instr::Cons,
+        (BuiltIn::Car, 1) => Instr::Car,
+        (BuiltIn::Cdr, 1) => Instr::Cdr,
+        (BuiltIn::CheckType(t), 1) => Instr::CheckType(t),
+        (BuiltIn::Add, _) => Instr::Add(arg_count),
+        (BuiltIn::Sub, _) if arg_count > 0 => Instr::Sub(arg_count),
+        (BuiltIn::Mul, _) => Instr::Mul(arg_count),
+        (BuiltIn::Quotient, 2) => Instr::Quotient,
+        (BuiltIn::Remainder, 2) => Instr::Remainder,
+        (BuiltIn::Compare(c), _) if arg_count > 0 => Instr::Compare(c, arg_count),
+        (BuiltIn::StringLength, 1) => Instr::StringLength,
+        (BuiltIn::StringAppend, _) => Instr::StringAppend(arg_count),
+        (BuiltIn::Substring, 3) => Instr::Substring,
+        (BuiltIn::StringEqual, _) if arg_count > 0 => Instr::StringEqual(arg_count),
+        (BuiltIn::StringToList, 1) => Instr::StringToList,
+        (BuiltIn::NumberToString, 1) => Instr::NumberToString,
+        (BuiltIn::CharToInteger, 1) => Instr::CharToInteger,
+        (BuiltIn::IntegerToChar, 1) => Instr::IntegerToChar,
+        (BuiltIn::CharUpcase, 1) => Instr::CharUpcase,
+        (BuiltIn::CheckAlphabetic, 1) => Instr::CheckAlphabetic,
+        (BuiltIn::CheckEq, 2) => Instr::CheckEq,
+        (BuiltIn::CheckEqual, 2) => Instr::CheckEqual,
+        (BuiltIn::Append, _) => Instr::Append(arg_count),
+        (BuiltIn::MacroExpand, 1) => Instr::MacroExpand,
+        (BuiltIn::MakeRecord(name, count), _) if arg_count == count => {
+            Instr::MakeRecord(name, count)
+        }
+        (BuiltIn::CheckRecord(name), 1) => Instr::CheckRecord(name),
+        (BuiltIn::RecordField(name, _, index), 1) => Instr::RecordField(name, index),
+        (BuiltIn::Raise, 1) => Instr::Raise,
+        (_, _) => return Err(EvaluationError::ArgumentCountMismatch),
+    })
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum VarStatus {
+    RemovedHead,
+    RemovedTail,
+}
+
+// Compiles a finalized expression into instructions and writes them to the
+// given buffer *in reverse order*
+fn inner_compile(
+    expr: FinalizedExpr,
+    state: &State,
+    instructions: &mut Vec<Instr>,
+    var_stats: &mut Vec<(StackOffset, Scope, VarStatus)>,
+) -> EvaluationResult<()> {
+    match expr {
+        FinalizedExpr::Argument(offset, _scope, VariableConstraint::Unconstrained) => {
+            instructions.push(Instr::MoveArgument(offset));
+        }
+        FinalizedExpr::Argument(offset, _scope, _move_status) => {
+            instructions.push(Instr::CloneArgument(offset));
+        }
+        FinalizedExpr::Value(v) => {
+            instructions.push(Instr::PushValue(v));
+        }
+        FinalizedExpr::Variable(n) => {
+            if let Some(v) = state.get(n) {
+                instructions.push(Instr::PushValue(v.clone()));
+            } else {
+                // Only fails when evaluated, so that try can catch it
+                instructions.push(Instr::UnknownVariable(n));
+            }
+        }
+        FinalizedExpr::Cond(triple, true_expr_returns, tail_call_status) => {
+            let unpacked = *triple;
+            let (test, true_expr, false_expr) = unpacked;
+
+            // Test must be done before to ensure that the true/ false branches
+            // get the right var_stats.
+            let mut test_expr_buf = Vec::new();
+            inner_compile(test.clone(), state, &mut test_expr_buf, var_stats)?;
+            let mut false_expr_var_stats = var_stats.clone();
+            let before_len = instructions.len();
+
+            if true_expr_returns && tail_call_status == TailCallStatus::CanTailCall {
+                instructions.push(Instr::Return);
+            }
+
+            inner_compile(true_expr, state, instructions, var_stats)?;
+            let jump_size = instructions.len() - before_len;
+            let before_len = instructions.len();
+
+            // We should probably always place either one of these. Even if we knew
+            // that the false expression *could* tail call, we are not sure if it
+            // will, for example when it is a curried result
+            if tail_call_status == TailCallStatus::CanTailCall {
+                instructions.push(Instr::Return);
+            } else {
+                instructions.push(Instr::Jump(jump_size));
+            }
+
+            if let FinalizedExpr::FunctionCall(ref f_box, ref args, ..) = test {
+                if let FinalizedExpr::Value(LispValue::Function(LispFunc::BuiltIn(
+                    BuiltIn::CheckZero,
+                ))) = **f_box
+                {
+                    if let Some(&FinalizedExpr::Argument(offset, scope, _)) = args.first() {
+                        // OK, so at this point we know we are jumping conditionally
+                        // on whether a function arg is zero.
+                        // Next: make sure that every use of this argument in the false branch
+                        // is within a sub1 call.
+                        // If this is the case, replace all these sub1 calls by uses
+                        // of the argument itself (maintaining its move status!).
+                        // Then, encode the conditional jump, zero check and decrement using
+                        // a single, superspecialized instruction.
+                        if args.len() == 1 && false_expr.only_use_after_sub(offset, scope, false) {
+                            let new_false_expr = false_expr.remove_subs_of(offset, scope);
+                            inner_compile(
+                                new_false_expr,
+                                state,
+                                instructions,
+                                &mut false_expr_var_stats,
+                            )?;
+                            let jump_size = instructions.len() - before_len;
+                            instructions.push(Instr::CondZeroJumpDecr(offset, jump_size));
+
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+
+            inner_compile(false_expr, state, instructions, &mut false_expr_var_stats)?;
+            let jump_size = instructions.len() - before_len;
+            instructions.push(Instr::CondJump(jump_size));
+            instructions.extend(test_expr_buf);
+        }
+        FinalizedExpr::Lambda(arity, scope, body, returns, defaults) => {
+            let default_instr_vecs: Vec<_> = defaults
+                .into_iter()
+                .map(|expr| {
+                    let mut sub_buf = Vec::new();
+                    inner_compile(expr, state, &mut sub_buf, var_stats)?;
+                    Ok(sub_buf)
+                })
+                .collect::<Result<_, _>>()?;
+
+            instructions.push(Instr::CreateLambda(scope, arity, body, returns));
+
+            for buf in default_instr_vecs.into_iter().rev() {
+                instructions.extend(buf);
+            }
+        }
+        FinalizedExpr::Let(scope, offset, values, body) => {
+            let local_count = values.len();
+            let value_instr_vecs: Vec<_> = values
+                .into_iter()
+                .map(|expr| {
+                    let mut sub_buf = Vec::new();
+                    inner_compile(expr, state, &mut sub_buf, var_stats)?;
+                    Ok(sub_buf)
+                })
+                .collect::<Result<_, _>>()?;
+
+            instructions.push(Instr::DropLocals(local_count));
+            inner_compile(*body, state, instructions, var_stats)?;
+
+            for buf in value_instr_vecs.into_iter().rev() {
+                instructions.extend(buf);
+            }
+
+            // Later local variables may reuse these stack positions
+            let end = offset + StackOffset::from(local_count);
+            var_stats.retain(|&(o, s, _)| s != scope || o < offset || o >= end);
+        }
+        FinalizedExpr::Begin(exprs) => {
+            let mut expr_instr_vecs = exprs
+                .into_iter()
+                .map(|expr| {
+                    let mut sub_buf = Vec::new();
+                    inner_compile(expr, state, &mut sub_buf, var_stats)?;
+                    Ok(sub_buf)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            // Only the value of the last expression is kept
+            if let Some(last) = expr_instr_vecs.pop() {
+                instructions.extend(last);
+            }
+
+            for buf in expr_instr_vecs.into_iter().rev() {
+                instructions.push(Instr::Pop);
+                instructions.extend(buf);
+            }
+        }
+        FinalizedExpr::TestPart(offset, path, test) => {
+            instructions.push(Instr::VarTest(offset, Box::new((path, test))));
+        }
+        FinalizedExpr::Part(offset, path, false) => {
+            instructions.push(Instr::VarPart(offset, Box::new(path)));
+        }
+        FinalizedExpr::Part(offset, path, true) => {
+            instructions.push(Instr::VarTakePart(offset, Box::new(path)));
+        }
+        FinalizedExpr::NoMatch => {
+            instructions.push(Instr::NoMatch);
+        }
+        FinalizedExpr::Try(handler, body) => {
+            let mut handler_buf = Vec::new();
+            inner_compile(*handler, state, &mut handler_buf, var_stats)?;
+
+            // After applying the handler, execution continues right after
+            // the body
+            instructions.push(Instr::EndTry);
+            let before_len = instructions.len();
+            inner_compile(*body, state, instructions, var_stats)?;
+            let body_len = instructions.len() - before_len;
+            instructions.push(Instr::Try(body_len + 1));
+            instructions.extend(handler_buf);
+        }
+        FinalizedExpr::FunctionCall(funk, args, is_tail_call, is_self_call) => {
+            // Here we check for special patterns of builtin functions on single
+            // arguments and try to generate specialized instructions for them.
+            if let FinalizedExpr::Value(LispValue::Function(LispFunc::BuiltIn(bf))) = *funk {
+                if let (1, Some(&FinalizedExpr::Argument(offset, scope, move_status))) =
+                    (args.len(), args.first())
+                {
+                    match (bf, offset, scope, move_status) {
+                        (BuiltIn::Car, offset, scope, VariableConstraint::RemovedTail) => {
+                            instructions.push(Instr::VarSplit(offset));
+                            var_stats.push((offset, scope, VarStatus::RemovedHead));
+                            return Ok(());
+                        }
+                        (BuiltIn::Cdr, offset, scope, VariableConstraint::Unconstrained) => {
+                            if var_stats.iter().any(|&(o, s, v)| {
+                                o == offset && s == scope && v == VarStatus::RemovedHead
+                            }) {
+                                // Head was previously removed. We can just move the remainder
+                                instructions.push(Instr::MoveArgument(offset));
+                                return Ok(());
+                            }
+                        }
+                        (BuiltIn::Cdr, offset, scope, VariableConstraint::RemovedHead) => {
+                            instructions.push(Instr::VarReverseSplit(offset));
+                            var_stats.push((offset, scope, VarStatus::RemovedTail));
+                            return Ok(());
+                        }
+                        (BuiltIn::Car, offset, scope, VariableConstraint::Unconstrained) => {
+                            if var_stats.iter().any(|&(o, s, v)| {
+                                o == offset && s == scope && v == VarStatus::RemovedTail
+                            }) {
+                                // Tail was previously removed. We can just move the head
+                                instructions.push(Instr::MoveArgument(offset));
+                            } else {
+                                // Tail is still on.
+                                instructions.push(Instr::VarCar(offset));
+                            }
+                            return Ok(());
+                        }
+                        (BuiltIn::Car, offset, ..)
+                        | (BuiltIn::CheckNull, offset, ..)
+                        | (BuiltIn::CheckZero, offset, ..) => {
+                            // Inspection mode!
+                            instructions.push(match bf {
+                                BuiltIn::CheckNull => Instr::VarCheckNull(offset),
+                                BuiltIn::CheckZero => Instr::VarCheckZero(offset),
+                                BuiltIn::Car => Instr::VarCar(offset),
+                                _ => unreachable!(),
+                            });
+                            return Ok(());
+                        }
+                        (BuiltIn::AddOne, offset, _scope, VariableConstraint::Unconstrained) => {
+                            instructions.push(Instr::MoveArgument(offset));
+                            instructions.push(Instr::VarAddOne(offset));
+                            return Ok(());
+                        }
+                        (..) => {}
+                    }
+                }
+            }
+
+            // Here, for tail calls, we try to reuse function arguments
+            // and elide copies thereof. For strict recursions, it's
+            // possible to do a (partial) elision when some non-zero prefix
+            // of the called function arguments is an in-order move from the
+            // prefix of the calling function.
+            let args_len = args.len();
+            let init_len = instructions.len();
+            // Local variables are stored right after the arguments that have
+            // been pushed so far, so skipping arguments would move them.
+            let has_locals = funk.has_locals() || args.iter().any(FinalizedExpr::has_locals);
+            // Built-in functions that are applied to more arguments than they
+            // take are called like other functions, which applies their result
+            // to the remaining arguments
+            let builtin = match *funk {
+                FinalizedExpr::Value(LispValue::Function(LispFunc::BuiltIn(bf)))
+                    if bf.arity().is_none_or(|n| n >= args_len) =>
+                {
+                    Some(bf)
+                }
+                _ => None,
+            };
+
+            if let Some(bf) = builtin {
+                instructions.push(builtin_instr(bf, args_len)?);
+            } else if is_self_call && is_tail_call {
+                instructions.push(Instr::Recurse(args_len));
+            } else {
+                instructions.push(Instr::EvalFunction(args_len, None));
+                inner_compile(*funk, state, instructions, var_stats)?;
+            }
+
+            // Compiling all arguments
+            let arg_instr_vecs: Vec<_> = args
+                .into_iter()
+                .map(|expr| {
+                    let mut sub_buf = Vec::new();
+                    inner_compile(expr, state, &mut sub_buf, var_stats)?;
+                    Ok(sub_buf)
+                })
+                .collect::<Result<_, _>>()?;
+
+            let arg_skip_count = if has_locals {
+                0
+            } else {
+                arg_instr_vecs
+                    .iter()
+                    .enumerate()
+                    .take_while(|&(idx, buf): &(_, &Vec<_>)| {
+                        if let Instr::MoveArgument(offset) = *buf.index(0) {
+                            idx == offset.to_usize()
+                        } else {
+                            false
+                        }
+                    })
+                    .count()
+            };
+
+            // Built-in functions do not reuse the stack frame, so their
+            // arguments must always be pushed.
+            for (idx, mut buf) in arg_instr_vecs.into_iter().enumerate().rev() {
+                if idx < arg_skip_count && is_tail_call && builtin.is_none() {
+                    instructions.extend(buf.drain(1..));
+                } else {
+                    instructions.extend(buf);
+                }
+            }
+
+            if is_self_call && is_tail_call {
+                // Store the number of copies that we have to be for
+                // execution time.
+                instructions[init_len] = Instr::Recurse(args_len - arg_skip_count);
+            } else if is_tail_call && builtin.is_none() {
+                instructions[init_len] = Instr::EvalFunction(args_len, Some(arg_skip_count));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn compile_finalized_expr(
+    expr: FinalizedExpr,
+    expr_returns: bool,
+    state: &State,
+) -> EvaluationResult<Vec<Instr>> {
+    let mut instructions = Vec::with_capacity(32);
+
+    if expr_returns {
+        instructions.push(Instr::Return);
+    }
+
+    inner_compile(expr, state, &mut instructions, &mut Vec::new())?;
+
+    Ok(instructions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse::{parse_lisp_string, ParseError};
+    use super::*;
+    use quickcheck::{Arbitrary, Gen};
+    use std::convert::From;
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum LispError {
+        Parse(ParseError),
+        Evaluation(EvaluationError),
+    }
+
+    impl From<EvaluationError> for LispError {
+        fn from(err: EvaluationError) -> LispError {
+            LispError::Evaluation(err)
+        }
+    }
+
+    impl From<ParseError> for LispError {
+        fn from(err: ParseError) -> LispError {
+            LispError::Parse(err)
+        }
+    }
+
+    fn check_lisp<'i, I>(state: &mut State, commands: I) -> Result<LispValue, LispError>
+    where
+        I: IntoIterator<Item = &'i str>,
+    {
+        let mut last_ret_val = None;
+
+        for cmd in commands {
+            let expr = parse_lisp_string(cmd, state)?;
+            last_ret_val = Some(evaluator::eval(expr, state)?);
+        }
+
+        Ok(last_ret_val.unwrap())
+    }
+
+    fn check_lisp_ok<'i, I>(commands: I, expected_out: &str)
+    where
+        I: IntoIterator<Item = &'i str>,
+    {
+        let mut state = State::default();
+        let val = check_lisp(&mut state, commands).unwrap();
+        assert_eq!(expected_out, print::print_value(&val, &state, 0));
+    }
+
+    fn check_lisp_err<'i, I>(commands: I, expected_err: LispError)
+    where
+        I: IntoIterator<Item = &'i str>,
+    {
+        let mut state = State::default();
+        assert_eq!(expected_err, check_lisp(&mut state, commands).unwrap_err());
+    }
+
+    fn get_bytecode(definition: &str, self_name: &str) -> Vec<Instr> {
+        let mut state = State::default();
+        check_lisp(
+            &mut state,
+            vec![&format!("(define {} 1337)", self_name)[..]],
+        )
+        .unwrap();
+        let intern = state.intern(self_name);
+        let expr = super::parse::parse_lisp_string(definition, &mut state).unwrap();
+        let mut finalization_ctx = super::FinalizationContext::new(Some(intern));
+        let (finalized_expr, returns) = expr.finalize(&mut finalization_ctx).unwrap();
+
+        if let FinalizedExpr::Lambda(_, _, body, returns, _) = finalized_expr {
+            super::compile_finalized_expr(*body, returns, &state).unwrap()
+        } else {
+            super::compile_finalized_expr(finalized_expr, returns, &state).unwrap()
+        }
+    }
+
+    impl Arbitrary for LispValue {
+        fn arbitrary(g: &mut Gen) -> LispValue {
+            enum ValueVariant {
+                List,
+                Int,
+                Bool,
+                Func,
+                Str,
+                Char,
+            }
+
+            let choices = [
+                ValueVariant::List,
+                ValueVariant::Int,
+                ValueVariant::Bool,
+                ValueVariant::Func,
+                ValueVariant::Str,
+                ValueVariant::Char,
+            ];
+            let variant = g.choose(&choices).unwrap();
+
+            match *variant {
+                ValueVariant::Int => LispValue::Integer(i64::arbitrary(g)),
+                ValueVariant::Bool => LispValue::Boolean(bool::arbitrary(g)),
+                ValueVariant::Str => LispValue::String(String::arbitrary(g)),
+                ValueVariant::Char => LispValue::Char(char::arbitrary(g)),
+                ValueVariant::List => {
+                    // We shouldn't generate lists too long too often,
+                    // or this procedure will not terminate with finite
+                    // probability.
+                    let max_len = choices.len();
+                    let len = g.size() % max_len;
+                    let mut vek = Vec::new();
+
+                    for _ in 0..len {
+                        vek.push(Arbitrary::arbitrary(g));
+                    }
+
+                    LispValue::List(vek)
+                }
+                ValueVariant::Func => {
+                    let f = LispFunc::BuiltIn(BuiltIn::AddOne);
+                    LispValue::Function(f)
+                }
+            }
+        }
+    }
+
+    // Quickcheck tests
+    #[quickcheck]
+    fn quickcheck_identity(x: LispValue) -> bool {
+        let mut state = Default::default();
+        let expr = LispExpr::Call(vec![
+            parse_lisp_string("(lambda (x) x)", &mut state).unwrap(),
+            LispExpr::Value(x.clone()),
+        ]);
+
+        let result = evaluator::eval(expr, &mut state).unwrap();
+
+        result == x
+    }
+
+    #[quickcheck]
+    fn quickcheck_integer_literal_roundtrip(i: i64) -> bool {
+        let mut state = State::default();
+
+        [2, 8, 10, 16].iter().all(|&radix| {
+            let literal = print::print_integer(i, radix);
+            parse_lisp_string(&literal, &mut state) == Ok(LispExpr::Value(LispValue::Integer(i)))
+        })
+    }
+
+    #[quickcheck]
+    fn quickcheck_big_integer_literal_roundtrip(x: i64, y: i64) -> bool {
+        let mut state = State::default();
+        let i = BigInt::from(x) * BigInt::from(y) * BigInt::from(x);
+
+        [2, 8, 10, 16].iter().all(|&radix| {
+            let literal = print::print_integer(i.clone(), radix);
+            parse_lisp_string(&literal, &mut state)
+                == Ok(LispExpr::Value(integer::normalize(i.clone())))
+        })
+    }
+
+    #[quickcheck]
+    fn quickcheck_string_literal_roundtrip(s: String) -> bool {
+        let mut state = State::default();
+        let literal = print::print_value(&LispValue::String(s.clone()), &state, 0);
+
+        parse_lisp_string(&literal, &mut state) == Ok(LispExpr::Value(LispValue::String(s)))
+    }
+
+    #[quickcheck]
+    fn quickcheck_char_literal_roundtrip(c: char) -> bool {
+        let mut state = State::default();
+        let literal = print::print_value(&LispValue::Char(c), &state, 0);
+
+        parse_lisp_string(&literal, &mut state) == Ok(LispExpr::Value(LispValue::Char(c)))
+    }
+
+    #[quickcheck]
+    fn quickcheck_big_integer_arithmetic(x: i64, y: i64) -> bool {
+        let mut state = State::default();
+        let expected = BigInt::from(x) * BigInt::from(y) - BigInt::from(y);
+        let command = format!("(- (* {} {}) {})", x, y, y);
+
+        check_lisp(&mut state, vec![&command[..]]) == Ok(integer::normalize(expected))
+    }
+
+    #[test]
+    fn add_bytecode() {
+        let bytecode = get_bytecode(
+            "(lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y))))",
+            "add",
+        );
+
+        assert_eq!(
+            bytecode,
+            vec![
+                Instr::Return,
+                Instr::MoveArgument(From::from(0)),
+                Instr::Return,
+                Instr::Recurse(0),
+                Instr::VarAddOne(From::from(0)),
+                Instr::CondZeroJumpDecr(From::from(1), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn begin_bytecode() {
+        let bytecode = get_bytecode("(lambda (x) (add1 x) (count (sub1 x)))", "count");
+
+        assert_eq!(
+            bytecode,
+            vec![
+                Instr::Recurse(1),
+                Instr::SubOne,
+                Instr::MoveArgument(From::from(0)),
+                Instr::Pop,
+                Instr::AddOne,
+                Instr::CloneArgument(From::from(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn cond_bytecode() {
+        let bytecode = get_bytecode(
+            "(lambda (x y) (cond ((zero? y) x) (else (add (add1 x) (sub1 y)))))",
+            "add",
+        );
+
+        assert_eq!(
+            bytecode,
+            vec![
+                Instr::Return,
+                Instr::MoveArgument(From::from(0)),
+                Instr::Return,
+                Instr::Recurse(0),
+                Instr::VarAddOne(From::from(0)),
+                Instr::CondZeroJumpDecr(From::from(1), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn match_bytecode() {
+        let bytecode = get_bytecode(
+            "(lambda (l acc) (match l (() acc) ((x . r) (sum r (+ acc x)))))",
+            "sum",
+        );
+
+        let whole_list = ListPath::default();
+
+        assert_eq!(
+            bytecode,
+            vec![
+                Instr::DropLocals(1),
+                Instr::Return,
+                Instr::MoveArgument(From::from(1)),
+                Instr::Return,
+                Instr::DropLocals(2),
+                Instr::Recurse(2),
+                Instr::Add(2),
+                Instr::MoveArgument(From::from(3)),
+                Instr::MoveArgument(From::from(1)),
+                Instr::MoveArgument(From::from(4)),
+                Instr::VarTakePart(From::from(2), Box::new(whole_list.without_first(1))),
+                Instr::VarTakePart(From::from(2), Box::new(whole_list.element(0))),
+                Instr::Return,
+                Instr::NoMatch,
+                Instr::CondJump(2),
+                Instr::VarTest(
+                    From::from(2),
+                    Box::new((whole_list.clone(), PartTest::Length(1, true))),
+                ),
+                Instr::CondJump(13),
+                Instr::VarTest(
+                    From::from(2),
+                    Box::new((whole_list.clone(), PartTest::Length(0, false))),
+                ),
+                Instr::MoveArgument(From::from(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn map_bytecode() {
+        let bytecode = get_bytecode(
+            "(lambda (f xs) (if (null? xs) xs (cons (f (car xs)) (map f (cdr xs)))))",
+            "map",
+        );
+
+        assert_eq!(
+            bytecode,
+            vec![
+                Instr::Return,
+                Instr::MoveArgument(From::from(1)),
+                Instr::Return,
+                Instr::Cons,
+                Instr::EvalFunction(2, None),
+                // 1337 is the magic number representing the function itself
+                Instr::PushValue(LispValue::Integer(1337)),
+                Instr::MoveArgument(From::from(1)),
+                Instr::MoveArgument(From::from(0)),
+                Instr::EvalFunction(1, None),
+                Instr::CloneArgument(From::from(0)),
+                Instr::VarSplit(From::from(1)),
+                Instr::CondJump(9),
+                Instr::VarCheckNull(From::from(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn comp_bytecode() {
+        let bytecode = get_bytecode(
+            "(lambda (x y) (if (zero? x) #f (if (zero? y) #t (gt (sub1 x) (sub1 y)))))",
+            "gt",
+        );
+        assert_eq!(
+            bytecode,
+            vec![
+                Instr::Return,
+                Instr::PushValue(LispValue::Boolean(false)),
+                Instr::Return,
+                Instr::Return,
                 Instr::PushValue(LispValue::Boolean(true)),
                 Instr::Return,
                 Instr::Recurse(0),
@@ -1356,264 +3731,2025 @@ mod tests {
         );
     }
 
-    // TODO: add test for partial copy recursive functions.
+    // TODO: add test for partial copy recursive functions.
+
+    #[test]
+    fn shadowing() {
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (x) (lambda (x) x)))",
+                "(list ((f #t) 3) ((f (list)) #f))",
+            ],
+            "(3 #f)",
+        );
+    }
+
+    #[test]
+    fn church_pair() {
+        check_lisp_ok(
+            vec![
+                "(define pair (lambda (x y f) (f x y)))",
+                "(define snd (lambda (x y) y))",
+                "(pair 1 2 snd)",
+            ],
+            "2",
+        );
+    }
+
+    #[test]
+    fn cond_argument() {
+        check_lisp_ok(
+            vec![
+                "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
+                "(add (if (> 12 5) 1 2) 3)",
+            ],
+            "4",
+        );
+    }
+
+    #[test]
+    fn shadowing_two() {
+        check_lisp_ok(
+            vec![
+                "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
+                "((lambda (x) (add ((lambda (x) (add1 x)) x) x)) 10)",
+            ],
+            "21",
+        );
+    }
+
+    #[test]
+    fn shadowing_three() {
+        check_lisp_ok(
+            vec![
+                "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
+                "((lambda (x) (add x ((lambda (x) (add (sub1 x) x)) x))) 4)",
+            ],
+            "11",
+        );
+    }
+
+    #[test]
+    fn return_curried_fn() {
+        check_lisp_ok(
+            vec![
+                "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
+                "(((lambda () (if #f 0 (add 1)))) 2)",
+            ],
+            "3",
+        );
+    }
+
+    #[test]
+    fn church_numerals() {
+        check_lisp_ok(
+            vec![
+                "(define n0 (lambda (f x) x))",
+                "(define incr (lambda (n f x) (f (n f x))))",
+                "((incr n0) add1 0)",
+            ],
+            "1",
+        );
+    }
+
+    #[test]
+    fn display_int_val() {
+        let state = Default::default();
+        let val = LispValue::Integer(5);
+        assert_eq!("5", print::print_value(&val, &state, 0));
+    }
+
+    #[test]
+    fn display_list_val() {
+        let state = Default::default();
+        let val = LispValue::List(vec![LispValue::Integer(1), LispValue::List(vec![])]);
+        assert_eq!("(1 ())", print::print_value(&val, &state, 0));
+    }
+
+    #[test]
+    fn function_add() {
+        check_lisp_ok(
+            vec![
+                "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
+                "(add 77 12)",
+            ],
+            "89",
+        );
+    }
+
+    #[test]
+    fn function_multiply() {
+        check_lisp_ok(
+            vec![
+                "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
+                "(define mult (lambda (x y) (if (zero? y) 0 (add x (mult x (sub1 y))))))",
+                "(mult 7 3)",
+            ],
+            "21",
+        );
+    }
+
+    #[test]
+    fn map2_zip() {
+        check_lisp_ok(
+            vec![
+                "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
+                "(define zip (lambda (x y) (if (or (null? x) (null? y)) (list) (cons (list (car x) (car y)) (zip (cdr x) (cdr y))))))",
+                "(define map2 (lambda (f l) (if (null? l) (list) (cons (f (car (cdr (car l))) (car (car l))) (map2 f (cdr l))))))",
+                "(map2 add (zip (list 1 2 3 4 5) (list 0 20 40 60)))",
+            ],
+            "(2 23 44 65)",
+        );
+    }
+
+    #[test]
+    fn function_def() {
+        check_lisp_ok(
+            vec!["(define add2 (lambda (x) (add1 (add1 x))))", "(add2 5)"],
+            "7",
+        );
+    }
+
+    #[test]
+    fn variable_overwrite() {
+        check_lisp_err(
+            vec!["(define x 1)", "(define x 1000)", "(add1 x)"],
+            LispError::Evaluation(EvaluationError::BadDefine),
+        );
+    }
+
+    #[test]
+    fn eval_empty_call() {
+        check_lisp_err(
+            vec!["()"],
+            LispError::Evaluation(EvaluationError::EmptyListEvaluation),
+        );
+    }
+
+    #[test]
+    fn check_int() {
+        check_lisp_ok(
+            vec![
+                "(define map (lambda (f xs) (if (null? xs) (list) (cons (f (car xs)) (map f (cdr xs))))))",
+                "(map int? (list 1 2 #t (list 3 4) add1 0))",
+            ],
+            "(#t #t #f #f #f #t)",
+        );
+    }
+
+    #[test]
+    fn check_bool() {
+        check_lisp_ok(
+            vec!["(list (bool? #t) (bool? #f) (bool? 5) (bool? (list #t)) (bool? bool?))"],
+            "(#t #t #f #f #f)",
+        );
+    }
+
+    #[test]
+    fn check_fun() {
+        check_lisp_ok(
+            vec![
+                "(list (fun? #t) (fun? fun?) (fun? 0) (fun? bool?) (fun? (lambda (x) #t)) (fun? #t))",
+            ],
+            "(#f #t #f #t #t #f)",
+        );
+    }
+
+    #[test]
+    fn check_use_var_after_cond_zero() {
+        check_lisp_ok(
+            vec!["(define f (lambda (x) (if (zero? x) x x)))", "(f 1)"],
+            "1",
+        );
+    }
+
+    #[test]
+    fn check_list() {
+        check_lisp_ok(
+            vec!["(list (list? list) (list? list?) (list? (list)))"],
+            "(#f #f #t)",
+        );
+    }
+
+    #[test]
+    fn is_null_empty_list() {
+        check_lisp_ok(vec!["(null? (list))"], "#t");
+    }
+
+    #[test]
+    fn cdr() {
+        check_lisp_ok(vec!["(cdr (list 1 2 3 4))"], "(1 2 3)");
+    }
+
+    #[test]
+    fn is_zero_of_zero() {
+        check_lisp_ok(vec!["(zero? 0)"], "#t");
+    }
+
+    #[test]
+    fn is_zero_of_nonzero() {
+        check_lisp_ok(vec!["(zero? 5)"], "#f");
+    }
+
+    #[test]
+    fn is_zero_of_list() {
+        check_lisp_err(
+            vec!["(zero? (list 0))"],
+            LispError::Evaluation(EvaluationError::ArgumentTypeMismatch),
+        );
+    }
+
+    #[test]
+    fn is_zero_two_args() {
+        // The result of zero? is applied to the second argument
+        check_lisp_err(
+            vec!["(zero? 0 0)"],
+            LispError::Evaluation(EvaluationError::NonFunctionApplication),
+        );
+    }
+
+    #[test]
+    fn add_one_max_int() {
+        check_lisp_ok(vec!["(add1 9223372036854775806)"], "9223372036854775807");
+        check_lisp_ok(vec!["(add1 9223372036854775807)"], "9223372036854775808");
+    }
+
+    #[test]
+    fn var_add_one_max_int() {
+        // Incrementing a function argument compiles to VarAddOne
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (x) (add1 x)))",
+                "(f 9223372036854775807)",
+            ],
+            "9223372036854775808",
+        );
+    }
+
+    #[test]
+    fn add_one_max_int_as_value() {
+        check_lisp_ok(
+            vec!["((lambda (f x) (f x)) add1 #x7fff_ffff_ffff_ffff)"],
+            "9223372036854775808",
+        );
+    }
+
+    #[test]
+    fn sub_one_zero() {
+        check_lisp_ok(vec!["(sub1 0)"], "-1");
+        check_lisp_ok(vec!["(define f (lambda (x) (sub1 x)))", "(f 0)"], "-1");
+    }
+
+    #[test]
+    fn sub_one_min_int() {
+        check_lisp_ok(vec!["(sub1 -9223372036854775808)"], "-9223372036854775809");
+        // Decrementing an argument after a zero check compiles to CondZeroJumpDecr
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (x) (if (zero? x) 0 (list (sub1 x)))))",
+                "(list (f -9223372036854775808) (f 9223372036854775809))",
+            ],
+            "((-9223372036854775809) (9223372036854775808))",
+        );
+    }
+
+    #[test]
+    fn big_integers_demote() {
+        let mut state = State::default();
+        let result = check_lisp(&mut state, vec!["(sub1 (add1 9223372036854775807))"]);
+        assert_eq!(Ok(LispValue::Integer(i64::MAX)), result);
+
+        let result = check_lisp(&mut state, vec!["(quotient (* 4294967296 4294967296) 4)"]);
+        assert_eq!(Ok(LispValue::Integer(1 << 62)), result);
+    }
+
+    #[test]
+    fn big_integer_arithmetic() {
+        check_lisp_ok(
+            vec![
+                "(define fact (lambda (n) (if (zero? n) 1 (* n (fact (sub1 n))))))",
+                "(fact 30)",
+            ],
+            "265252859812191058636308480000000",
+        );
+        check_lisp_ok(
+            vec![
+                "(list (- 100000000000000000000 1) (quotient -100000000000000000000 3) (remainder 100000000000000000000 7))",
+            ],
+            "(99999999999999999999 -33333333333333333333 2)",
+        );
+        check_lisp_ok(
+            vec![
+                "(list (< 9223372036854775807 9223372036854775808) (= 100000000000000000000 100000000000000000000) (> -100000000000000000000 -1) (zero? 100000000000000000000) (int? 100000000000000000000))",
+            ],
+            "(#t #t #f #f #t)",
+        );
+        check_lisp_err(
+            vec!["(quotient 100000000000000000000 0)"],
+            LispError::Evaluation(EvaluationError::DivisionByZero),
+        );
+    }
+
+    #[test]
+    fn negative_integers() {
+        check_lisp_ok(
+            vec!["(list -5 (- 3 4) (- 7) (* -2 3) (quotient -7 2) (remainder -7 2) (< -1 0))"],
+            "(-5 -1 -7 -6 -3 -1 #t)",
+        );
+        check_lisp_ok(vec!["(int? -3)"], "#t");
+    }
+
+    #[test]
+    fn native_arithmetic() {
+        check_lisp_ok(
+            vec!["(list (+ 1 2) (- 10 4) (* 6 7) (quotient 17 5) (remainder 17 5))"],
+            "(3 6 42 3 2)",
+        );
+    }
+
+    #[test]
+    fn variadic_arithmetic() {
+        check_lisp_ok(
+            vec!["(list (+) (+ 5) (+ 1 2 3 4) (*) (* 2 3 4) (- 0) (- 10 1 2 3))"],
+            "(0 5 10 1 24 0 4)",
+        );
+    }
+
+    #[test]
+    fn native_mult_large() {
+        check_lisp_ok(vec!["(* 1000 1000)"], "1000000");
+    }
+
+    #[test]
+    fn arithmetic_overflow_promotes() {
+        check_lisp_ok(
+            vec!["(list (- -9223372036854775808 1) (- -9223372036854775808) (quotient -9223372036854775808 -1) (+ 9223372036854775807 1) (* 4294967296 4294967296))"],
+            "(-9223372036854775809 9223372036854775808 9223372036854775808 9223372036854775808 18446744073709551616)",
+        );
+    }
+
+    #[test]
+    fn native_comparisons() {
+        check_lisp_ok(
+            vec!["(list (= 3 3) (< 1 2) (<= 2 2) (> 2 1) (>= 1 2) (< 1 2 2) (<= 1 2 2) (= 7))"],
+            "(#t #t #t #t #f #f #t #t)",
+        );
+    }
+
+    #[test]
+    fn arithmetic_builtin_as_value() {
+        check_lisp_ok(
+            vec![
+                "(define foldr (lambda (f xs init) (if (null? xs) init (foldr f (cdr xs) (f init (car xs))))))",
+                "(list (foldr + (list 1 2 3 4) 0) (foldr * (list 1 2 3 4) 1) ((lambda (f) (f 3 2 1)) >))",
+            ],
+            "(10 24 #t)",
+        );
+    }
+
+    #[test]
+    fn arithmetic_errors() {
+        check_lisp_err(
+            vec!["(quotient 1 0)"],
+            LispError::Evaluation(EvaluationError::DivisionByZero),
+        );
+        check_lisp_err(
+            vec!["(remainder 1 0)"],
+            LispError::Evaluation(EvaluationError::DivisionByZero),
+        );
+        check_lisp_err(
+            vec!["(< 1 #t)"],
+            LispError::Evaluation(EvaluationError::ArgumentTypeMismatch),
+        );
+        check_lisp_err(
+            vec!["(+ 1 (list))"],
+            LispError::Evaluation(EvaluationError::ArgumentTypeMismatch),
+        );
+    }
+
+    #[test]
+    fn arithmetic_arg_counts() {
+        check_lisp_err(
+            vec!["(-)"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
+        );
+        check_lisp_err(
+            vec!["(<)"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
+        );
+        check_lisp_err(
+            vec!["(quotient 1)"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
+        );
+        check_lisp_err(
+            vec!["(quotient 1 2 3)"],
+            LispError::Evaluation(EvaluationError::NonFunctionApplication),
+        );
+    }
+
+    #[test]
+    fn define_builtin_name() {
+        check_lisp_err(
+            vec!["(define > (lambda (x y) #t))"],
+            LispError::Evaluation(EvaluationError::BadDefine),
+        );
+    }
+
+    #[test]
+    fn string_literal() {
+        check_lisp_ok(vec![r#""a \"b\" \\ c\n\u{7}""#], r#""a \"b\" \\ c\n\u{7}""#);
+        check_lisp_ok(vec![r#"(list "" "λ")"#], r#"("" "λ")"#);
+    }
+
+    #[test]
+    fn string_type_check() {
+        check_lisp_ok(
+            vec![r#"(list (string? "a") (string? 1) (int? "1") (list? "abc"))"#],
+            "(#t #f #f #f)",
+        );
+    }
+
+    #[test]
+    fn string_builtins() {
+        check_lisp_ok(
+            vec![
+                r#"(list (string-length "héllo") (string-length "") (string-append "ab" "" "cd") (string-append))"#,
+            ],
+            r#"(5 0 "abcd" "")"#,
+        );
+        check_lisp_ok(
+            vec![r#"(list (substring "héllo" 1 3) (substring "abc" 3 3) (substring "abc" 0 3))"#],
+            r#"("él" "" "abc")"#,
+        );
+        check_lisp_ok(
+            vec![r#"(list (string=? "a" "a") (string=? "a" "a" "b") (string=? "x"))"#],
+            "(#t #f #t)",
+        );
+        check_lisp_ok(
+            vec![
+                r#"(list (number->string 42) (number->string -7) (number->string (* 4294967296 4294967296)))"#,
+            ],
+            r#"("42" "-7" "18446744073709551616")"#,
+        );
+    }
+
+    #[test]
+    fn string_to_list() {
+        // Consistent with the order of list
+        check_lisp_ok(
+            vec![r#"(list (string->list "abc") (list #\a #\b #\c) (car (string->list "abc")))"#],
+            r#"((#\a #\b #\c) (#\a #\b #\c) #\c)"#,
+        );
+        check_lisp_ok(vec![r#"(string->list "")"#], "()");
+    }
+
+    #[test]
+    fn char_literal() {
+        check_lisp_ok(
+            vec![r"(list #\a #\space #\newline #\x41 #\λ #\( #\x7f)"],
+            r"(#\a #\space #\newline #\A #\λ #\( #\x7f)",
+        );
+    }
+
+    #[test]
+    fn char_builtins() {
+        check_lisp_ok(
+            vec![r#"(list (char? #\a) (char? "a") (string? #\a) (int? #\1))"#],
+            "(#t #f #f #f)",
+        );
+        check_lisp_ok(
+            vec![
+                r"(list (char->integer #\A) (char->integer #\λ) (integer->char 955) (integer->char 97))",
+            ],
+            r"(65 955 #\λ #\a)",
+        );
+        check_lisp_ok(
+            vec![r"(list (char-upcase #\a) (char-upcase #\ä) (char-upcase #\ß) (char-upcase #\1))"],
+            r"(#\A #\Ä #\ß #\1)",
+        );
+        check_lisp_ok(
+            vec![
+                r"(list (char-alphabetic? #\a) (char-alphabetic? #\λ) (char-alphabetic? #\1) (char-alphabetic? #\space))",
+            ],
+            "(#t #t #f #f)",
+        );
+    }
+
+    #[test]
+    fn char_errors() {
+        check_lisp_err(
+            vec!["(integer->char 1114112)"],
+            LispError::Evaluation(EvaluationError::InvalidCodePoint),
+        );
+        check_lisp_err(
+            vec!["(integer->char 55296)"],
+            LispError::Evaluation(EvaluationError::InvalidCodePoint),
+        );
+        check_lisp_err(
+            vec!["(integer->char -1)"],
+            LispError::Evaluation(EvaluationError::InvalidCodePoint),
+        );
+        check_lisp_err(
+            vec![r#"(char->integer "a")"#],
+            LispError::Evaluation(EvaluationError::ArgumentTypeMismatch),
+        );
+        check_lisp_err(
+            vec!["(char-upcase 97)"],
+            LispError::Evaluation(EvaluationError::ArgumentTypeMismatch),
+        );
+    }
+
+    #[test]
+    fn string_errors() {
+        check_lisp_err(
+            vec![r#"(substring "abc" 2 4)"#],
+            LispError::Evaluation(EvaluationError::IndexOutOfRange),
+        );
+        check_lisp_err(
+            vec![r#"(substring "abc" 2 1)"#],
+            LispError::Evaluation(EvaluationError::IndexOutOfRange),
+        );
+        check_lisp_err(
+            vec![r#"(substring "abc" -1 1)"#],
+            LispError::Evaluation(EvaluationError::IndexOutOfRange),
+        );
+        check_lisp_err(
+            vec![r#"(string-append "a" 1)"#],
+            LispError::Evaluation(EvaluationError::ArgumentTypeMismatch),
+        );
+        check_lisp_err(
+            vec!["(string-length (list))"],
+            LispError::Evaluation(EvaluationError::ArgumentTypeMismatch),
+        );
+        check_lisp_err(
+            vec![r#"(number->string "1")"#],
+            LispError::Evaluation(EvaluationError::ArgumentTypeMismatch),
+        );
+        check_lisp_err(
+            vec![r#"(string=? 1)"#],
+            LispError::Evaluation(EvaluationError::ArgumentTypeMismatch),
+        );
+    }
+
+    #[test]
+    fn quote_symbol() {
+        check_lisp_ok(vec!["'foo"], "foo");
+        check_lisp_ok(vec!["(quote foo)"], "foo");
+        check_lisp_ok(vec!["''foo"], "(quote foo)");
+    }
+
+    #[test]
+    fn quote_list() {
+        check_lisp_ok(
+            vec![r#"'(a (b 1) "s" #\c #t ())"#],
+            r#"(a (b 1) "s" #\c #t ())"#,
+        );
+        check_lisp_ok(vec!["'(lambda (x) (add1 x))"], "(lambda (x) (add1 x))");
+        // Quoted lists are consistent with the list function
+        check_lisp_ok(
+            vec!["(list (equal? '(a b c) (list 'a 'b 'c)) (car '(a b c)))"],
+            "(#t c)",
+        );
+    }
+
+    #[test]
+    fn symbol_predicates() {
+        check_lisp_ok(
+            vec![r#"(list (symbol? 'a) (symbol? 'car) (symbol? "a") (symbol? car) (list? '()))"#],
+            "(#t #t #f #f #t)",
+        );
+        check_lisp_ok(
+            vec![r#"(list (eq? 'a 'a) (eq? 'a 'b) (eq? 'a #\a) (eq? 1 1) (eq? '() (list)))"#],
+            "(#t #f #f #t #t)",
+        );
+        check_lisp_ok(
+            vec![r#"(list (equal? "a" "a") (equal? '(a (1)) (list 'a (list 1))) (equal? 'a 'b))"#],
+            "(#t #t #f)",
+        );
+        // Strings, lists and records have no identity to compare
+        check_lisp_ok(
+            vec![
+                "(define-record point x y)",
+                r#"(list (eq? "a" "a") (eq? (list 1) (list 1)) (eq? '(a) '(a)) (eq? (make-point 1 2) (make-point 1 2)) (eq? '() "a"))"#,
+            ],
+            "(#f #f #f #f #f)",
+        );
+    }
+
+    #[test]
+    fn symbols_as_data() {
+        check_lisp_ok(
+            vec![
+                "(define op (lambda (name) (if (eq? name 'plus) + (if (eq? name 'times) * -))))",
+                "(define x 'times)",
+                "(list ((op 'plus) 2 3) ((op x) 2 3) ((op 'minus) 2 3) x)",
+            ],
+            "(5 6 -1 times)",
+        );
+    }
+
+    #[test]
+    fn quasiquote_constant() {
+        check_lisp_ok(vec!["`x"], "x");
+        check_lisp_ok(vec!["`(a (b 1) #t)"], "(a (b 1) #t)");
+        check_lisp_ok(vec!["(quasiquote (lambda (x) x))"], "(lambda (x) x)");
+    }
+
+    #[test]
+    fn quasiquote_unquote() {
+        check_lisp_ok(
+            vec!["(define x 5)", "`(a ,x (b ,(+ x 1)) ,'c)"],
+            "(a 5 (b 6) c)",
+        );
+        check_lisp_ok(
+            vec![
+                "(define x 5)",
+                "(quasiquote (a (unquote x) (b (unquote (+ x 1)))))",
+            ],
+            "(a 5 (b 6))",
+        );
+        check_lisp_ok(vec!["(define x 5)", "`(a '(b ,x))"], "(a (quote (b 5)))");
+        // Nested quasiquotes are plain data
+        check_lisp_ok(
+            vec!["(define x 5)", "`(a `(b ,x))"],
+            "(a (quasiquote (b (unquote x))))",
+        );
+        check_lisp_ok(
+            vec!["(define x 5)", "`(a `(b ,(c ,x)))"],
+            "(a (quasiquote (b (unquote (c (unquote x))))))",
+        );
+    }
+
+    #[test]
+    fn quasiquote_splicing() {
+        check_lisp_ok(
+            vec!["(define l (list 1 2))", "`(0 ,@l 3 ,@l ,@(list) 4)"],
+            "(0 1 2 3 1 2 4)",
+        );
+        check_lisp_ok(vec!["(define l (list 1 2))", "`(,@l)"], "(1 2)");
+        check_lisp_ok(
+            vec![
+                "(define l (list 1 2))",
+                "(quasiquote ((unquote-splicing l) 3))",
+            ],
+            "(1 2 3)",
+        );
+        // Templates are consistent with the list function
+        check_lisp_ok(
+            vec![
+                "(define l (list 1 2))",
+                "(list (car `(,@l 3)) (cdr `(0 ,@l)) (equal? `(0 ,@l) (list 0 1 2)))",
+            ],
+            "(3 (0 1) #t)",
+        );
+        // Splices do not depend on user definitions
+        check_lisp_ok(
+            vec![
+                "(define append (lambda (x y) x))",
+                "(define l (list 1 2))",
+                "`(0 ,@l 3)",
+            ],
+            "(0 1 2 3)",
+        );
+    }
+
+    #[test]
+    fn quasiquote_arguments() {
+        check_lisp_ok(
+            vec![
+                "(define make (lambda (op x y) `(,op ,x (,y))))",
+                "(make 'add 1 2)",
+            ],
+            "(add 1 (2))",
+        );
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (l) `(,@l ,(car l) ,@(cdr l))))",
+                "(f (list 1 2))",
+            ],
+            "(1 2 2 1)",
+        );
+    }
+
+    #[test]
+    fn builtin_tail_call_arguments() {
+        check_lisp_ok(vec!["((lambda (x y) (list x)) 1 2)"], "(1)");
+        check_lisp_ok(vec!["((lambda (x y) (list x (list y))) 1 2)"], "(1 (2))");
+    }
+
+    #[test]
+    fn quasiquote_errors() {
+        check_lisp_err(
+            vec!["`(a ,@1)"],
+            LispError::Evaluation(EvaluationError::ArgumentTypeMismatch),
+        );
+        check_lisp_err(
+            vec!["(define x 1)", ",x"],
+            LispError::Evaluation(EvaluationError::UnexpectedOperator),
+        );
+        check_lisp_err(
+            vec!["(define l (list))", "`,@l"],
+            LispError::Evaluation(EvaluationError::UnexpectedOperator),
+        );
+        check_lisp_err(
+            vec!["`(a (unquote))"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
+        );
+    }
+
+    #[test]
+    fn defmacro() {
+        check_lisp_ok(
+            vec![
+                "(defmacro my-if (c a b) `(if ,c ,a ,b))",
+                "(list (my-if #t 1 2) (my-if #f 1 2))",
+            ],
+            "(1 2)",
+        );
+        // Arguments are not evaluated before expansion
+        check_lisp_ok(
+            vec![
+                "(defmacro my-if (c a b) `(if ,c ,a ,b))",
+                "(my-if #t 1 (car (list)))",
+            ],
+            "1",
+        );
+        // Built-in functions and special forms are passed as symbols
+        check_lisp_ok(
+            vec![
+                "(defmacro swap (f a b) (list f b a))",
+                "(defmacro symbols (a b) (list 'quote (list (symbol? a) (symbol? b))))",
+                "(list (swap - 1 10) (symbols cond +))",
+            ],
+            "(9 (#t #t))",
+        );
+    }
+
+    #[test]
+    fn defmacro_recursive_expansion() {
+        check_lisp_ok(
+            vec![
+                "(defmacro my-if (c a b) `(if ,c ,a ,b))",
+                "(defmacro my-unless (c a b) `(my-if ,c ,b ,a))",
+                "(define f (lambda (x) (my-unless (zero? x) (f (sub1 x)) 'done)))",
+                "(f 10)",
+            ],
+            "done",
+        );
+        check_lisp_ok(
+            vec![
+                "(defmacro my-if (c a b) `(if ,c ,a ,b))",
+                "(define x 0)",
+                "`(a ,(my-if (zero? x) 'zero 'other) (my-if 1 2 3))",
+            ],
+            "(a zero (my-if 1 2 3))",
+        );
+    }
+
+    #[test]
+    fn defmacro_shadowing() {
+        check_lisp_ok(
+            vec![
+                "(defmacro twice (x) `(+ ,x ,x))",
+                "(define f (lambda (twice) (twice 3)))",
+                "(list (f add1) (twice 3) '(twice 3))",
+            ],
+            "(4 6 (twice 3))",
+        );
+    }
+
+    #[test]
+    fn macroexpand() {
+        check_lisp_ok(
+            vec![
+                "(defmacro my-if (c a b) `(if ,c ,a ,b))",
+                "(defmacro my-unless (c a b) `(my-if ,c ,b ,a))",
+                "(list (macroexpand '(my-unless #t 1 (my-unless #f 2 3))) (macroexpand '(add1 2)) (macroexpand 5))",
+            ],
+            "((if #t (my-unless #f 2 3) 1) (add1 2) 5)",
+        );
+    }
+
+    #[test]
+    fn defmacro_errors() {
+        check_lisp_err(
+            vec!["(defmacro m (a b) a)", "(m 1)"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
+        );
+        check_lisp_err(
+            vec!["(defmacro m (a) a)", "(defmacro m (a) a)"],
+            LispError::Evaluation(EvaluationError::BadDefine),
+        );
+        check_lisp_err(
+            vec!["(defmacro cond (a) a)"],
+            LispError::Evaluation(EvaluationError::BadDefine),
+        );
+        check_lisp_err(
+            vec!["(lambda (x) (defmacro m (a) a))"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(defmacro m (a) a)", "(macroexpand '(m 1 2))"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
+        );
+    }
+
+    #[test]
+    fn expansion_limit() {
+        check_lisp_err(
+            vec!["(defmacro lp () '(lp))", "(lp)"],
+            LispError::Evaluation(EvaluationError::ExpansionLimit),
+        );
+        check_lisp_err(
+            vec!["(defmacro lp () '(lp))", "(macroexpand '(lp))"],
+            LispError::Evaluation(EvaluationError::ExpansionLimit),
+        );
+        check_lisp_err(
+            vec!["(defmacro grow (x) `(list (grow ,x)))", "(grow 1)"],
+            LispError::Evaluation(EvaluationError::ExpansionLimit),
+        );
+        check_lisp_err(
+            vec![
+                "(define-syntax lp (syntax-rules () ((_ x) (lp (x)))))",
+                "(lp 1)",
+            ],
+            LispError::Evaluation(EvaluationError::ExpansionLimit),
+        );
+    }
+
+    #[test]
+    fn syntax_rules() {
+        check_lisp_ok(
+            vec![
+                "(define-syntax my-let (syntax-rules () ((_ ((n v) ...) body) ((lambda (n ...) body) v ...))))",
+                "(my-let ((x 1) (y 2)) (+ x y))",
+            ],
+            "3",
+        );
+        check_lisp_ok(
+            vec![
+                "(define-syntax my-cond (syntax-rules (else) ((_ (else e)) e) ((_ (c e) rest ...) (if c e (my-cond rest ...)))))",
+                "(list (my-cond (#f 1) ((zero? 0) 2) (else 3)) (my-cond (#f 1) (else 3)) (my-cond (else 4)))",
+            ],
+            "(2 3 4)",
+        );
+        check_lisp_ok(
+            vec![
+                "(define-syntax last (syntax-rules () ((_ x ... y) 'y)))",
+                "(define-syntax second (syntax-rules () ((_ _ b) b)))",
+                "(list (last 1 2 3) (last 1) (second 1 2))",
+            ],
+            "(3 1 2)",
+        );
+    }
+
+    #[test]
+    fn syntax_rules_hygiene() {
+        check_lisp_ok(
+            vec![
+                "(define-syntax my-or (syntax-rules () ((_) #f) ((_ e) e) ((_ e r ...) ((lambda (t) (if t t (my-or r ...))) e))))",
+                "(define t 5)",
+                "(list (my-or #f t) (my-or #f #f) (my-or))",
+            ],
+            "(5 #f #f)",
+        );
+        check_lisp_ok(
+            vec![
+                "(define-syntax with-ten (syntax-rules () ((_ body) ((lambda (ten) body) 10))))",
+                "(define ten 1)",
+                "(define f (lambda (ten) (with-ten (+ ten ten))))",
+                "(list (with-ten ten) (f 3))",
+            ],
+            "(1 6)",
+        );
+        // Free names of the template are not captured by the use
+        check_lisp_ok(
+            vec![
+                "(define scale 10)",
+                "(define-syntax scaled (syntax-rules () ((_ e) (* scale e))))",
+                "(define f (lambda (scale) (scaled scale)))",
+                "(let ((scale 2)) (list (scaled 3) (f 4)))",
+            ],
+            "(30 40)",
+        );
+        check_lisp_ok(
+            vec![
+                "(define items '(a b))",
+                "(define-syntax first-item (syntax-rules () ((_) (car items))))",
+                "(let ((items '(c d))) (first-item))",
+            ],
+            "b",
+        );
+        // Quoted data in the template is left as it is
+        check_lisp_ok(
+            vec![
+                "(define-syntax code (syntax-rules () ((_ e) (list 'scale '(lambda (x) x) `(t ,e)))))",
+                "(define t 1)",
+                "(define scale 2)",
+                "(let ((scale 3)) (code scale))",
+            ],
+            "(scale (lambda (x) x) (t 3))",
+        );
+    }
+
+    #[test]
+    fn syntax_rules_macroexpand() {
+        check_lisp_ok(
+            vec![
+                "(define-syntax my-let (syntax-rules () ((_ ((n v) ...) body) ((lambda (n ...) body) v ...))))",
+                "(macroexpand '(my-let ((x 1) (y 2)) (list x y)))",
+            ],
+            "((lambda (x y) (list x y)) 1 2)",
+        );
+    }
+
+    #[test]
+    fn syntax_rules_errors() {
+        check_lisp_err(
+            vec![
+                "(define-syntax two (syntax-rules () ((_ a b) b)))",
+                "(two 1)",
+            ],
+            LispError::Evaluation(EvaluationError::NoMatchingRule),
+        );
+        check_lisp_err(
+            vec![
+                "(define-syntax pairs (syntax-rules () ((_ (a ...) (b ...)) '((a b) ...))))",
+                "(pairs (1 2) (3))",
+            ],
+            LispError::Evaluation(EvaluationError::NoMatchingRule),
+        );
+        check_lisp_err(
+            vec!["(define-syntax m (syntax-rules))"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(define-syntax m (syntax-rules () (x)))"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(define-syntax e (syntax-rules () (() 1)))", "(e)"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(define-syntax e (syntax-rules () (e 1)))"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(define-syntax m (syntax-rules () ((_ a ...) a)))", "(m 1)"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec![
+                "(defmacro m (a) a)",
+                "(define-syntax m (syntax-rules () ((_ a) a)))",
+            ],
+            LispError::Evaluation(EvaluationError::BadDefine),
+        );
+    }
+
+    #[test]
+    fn let_basic() {
+        check_lisp_ok(vec!["(let ((x 1) (y 2)) (+ x y))"], "3");
+        check_lisp_ok(vec!["(let () 5)"], "5");
+        check_lisp_ok(
+            vec!["(list 1 (let ((x 2)) x) (let ((y 3)) (add1 y)))"],
+            "(1 2 4)",
+        );
+        check_lisp_ok(
+            vec!["(let ((l (list 1 2 3))) (list (car l) (cdr l)))"],
+            "(3 (1 2))",
+        );
+    }
+
+    #[test]
+    fn let_shadowing() {
+        check_lisp_ok(
+            vec!["(let ((x 1)) (let ((x 5) (y x)) (list x y)))"],
+            "(5 1)",
+        );
+        check_lisp_ok(
+            vec!["(define f (lambda (x) (let ((x (add1 x))) x)))", "(f 1)"],
+            "2",
+        );
+    }
+
+    #[test]
+    fn let_closures() {
+        check_lisp_ok(vec!["(let ((x 10)) ((lambda (y) (+ x y)) 5))"], "15");
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (a) (let ((b (add1 a))) (lambda (c) (list a b c)))))",
+                "((f 1) 3)",
+            ],
+            "(1 2 3)",
+        );
+    }
+
+    #[test]
+    fn let_tail_calls() {
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (n acc) (if (zero? n) acc (let ((m (sub1 n))) (f m (add1 acc))))))",
+                "(f 100000 0)",
+            ],
+            "100000",
+        );
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (n) (if (zero? n) 0 (let ((m 5)) (f (sub1 n))))))",
+                "(f 100000)",
+            ],
+            "0",
+        );
+    }
+
+    #[test]
+    fn let_star() {
+        check_lisp_ok(
+            vec!["(let* ((x 1) (y (add1 x)) (z (* y 3))) (list x y z))"],
+            "(1 2 6)",
+        );
+        check_lisp_ok(vec!["(let ((x 1)) (let* ((x 2) (y x)) y))"], "2");
+    }
+
+    #[test]
+    fn letrec() {
+        check_lisp_ok(
+            vec![
+                "(letrec ((ev? (lambda (n) (if (zero? n) #t (od? (sub1 n))))) (od? (lambda (n) (if (zero? n) #f (ev? (sub1 n)))))) (list (ev? 10) (od? 7) (ev? 100001)))",
+            ],
+            "(#t #t #f)",
+        );
+        check_lisp_ok(
+            vec!["(letrec ((loop (lambda (n acc) (if (zero? n) acc (loop (sub1 n) (add1 acc)))))) (loop 1000000 0))"],
+            "1000000",
+        );
+        check_lisp_ok(
+            vec!["(letrec ((k (lambda () 42))) (list (k) ((lambda (f) (f)) k)))"],
+            "(42 42)",
+        );
+        check_lisp_ok(
+            vec!["(letrec ((add (lambda (a b) (+ a b)))) ((add 1) 2))"],
+            "3",
+        );
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (x) (letrec ((g (lambda (n) (if (zero? n) x (g (sub1 n)))))) g)))",
+                "((f 7) 3)",
+            ],
+            "7",
+        );
+        // Values that are not functions are bound before their users
+        check_lisp_ok(
+            vec!["(letrec ((x 1) (f (lambda () x)) (g (lambda () (f))) (y (g))) (list x (f) y))"],
+            "(1 1 1)",
+        );
+        check_lisp_ok(vec!["(letrec ((f (lambda (n) (+ n x))) (x 2)) (f 1))"], "3");
+    }
+
+    #[test]
+    fn nested_lambda_self_name() {
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (x) (lambda (y) (if (zero? y) x (f y)))))",
+                "(fun? ((f 1) 2))",
+            ],
+            "#t",
+        );
+    }
+
+    #[test]
+    fn let_shadows_macros() {
+        check_lisp_ok(
+            vec![
+                "(defmacro twice (x) `(list ,x ,x))",
+                "(let ((twice (lambda (x) x))) (twice 1))",
+            ],
+            "1",
+        );
+        check_lisp_ok(
+            vec![
+                "(define-syntax with-x (syntax-rules () ((_ body) (let ((x 10)) body))))",
+                "(define x 1)",
+                "(with-x (add1 x))",
+            ],
+            "2",
+        );
+    }
+
+    #[test]
+    fn let_errors() {
+        check_lisp_err(
+            vec!["(let ((x)) x)"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(let (x 1) x)"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(let ((x 1) (x 2)) x)"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(let* ((x 1) (x 2)) x)"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(letrec ((f (lambda () 1)) (f (lambda () 2))) (f))"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(letrec ((x (f)) (f (lambda () x))) x)"],
+            LispError::Evaluation(EvaluationError::BadDefine),
+        );
+        check_lisp_err(
+            vec!["(let ((x 1)))"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
+        );
+    }
+
+    #[test]
+    fn quote_arg_count() {
+        check_lisp_err(
+            vec!["(quote)"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
+        );
+        check_lisp_err(
+            vec!["(quote a b)"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
+        );
+    }
+
+    #[test]
+    fn too_few_arguments() {
+        check_lisp_err(
+            vec!["(add1)"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
+        );
+    }
+
+    #[test]
+    fn lower_level_define() {
+        check_lisp_err(
+            vec!["(list (define x 5))"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+    }
+
+    #[test]
+    fn internal_define() {
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (x) (define y (add1 x)) (define z (* y 2)) (list x y z)))",
+                "(f 1)",
+            ],
+            "(1 2 4)",
+        );
+        check_lisp_ok(
+            vec![
+                "(define y 100)",
+                "(define f (lambda (x) (define y 1) (+ x y)))",
+                "(list (f 1) y)",
+            ],
+            "(2 100)",
+        );
+    }
+
+    #[test]
+    fn internal_define_recursion() {
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (n) (define ev? (lambda (n) (if (zero? n) #t (od? (sub1 n))))) (define od? (lambda (n) (if (zero? n) #f (ev? (sub1 n))))) (list (ev? n) (od? n))))",
+                "(f 10001)",
+            ],
+            "(#f #t)",
+        );
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (n) (define k 3) (define loop (lambda (n acc) (if (zero? n) acc (loop (sub1 n) (+ acc k))))) (loop n 0)))",
+                "(f 100000)",
+            ],
+            "300000",
+        );
+    }
+
+    #[test]
+    fn internal_define_forward_references() {
+        check_lisp_ok(
+            vec!["((lambda (x) (define f (lambda () k)) (define k 3) (f)) 1)"],
+            "3",
+        );
+        check_lisp_ok(
+            vec![
+                "(define h (lambda () (define a 1) (define f (lambda () (g))) (define b 2) (define g (lambda () a)) (list (f) b)))",
+                "(h)",
+            ],
+            "(1 2)",
+        );
+        // Values may use functions defined before them
+        check_lisp_ok(
+            vec!["((lambda () (define f (lambda (x) (* x k))) (define k 3) (define y (f 2)) y))"],
+            "6",
+        );
+        // Local names do not count as references
+        check_lisp_ok(
+            vec!["((lambda () (define f (lambda (n) (* n 2))) (define n (f 3)) n))"],
+            "6",
+        );
+    }
+
+    #[test]
+    fn begin() {
+        check_lisp_ok(vec!["(begin 1 2 3)"], "3");
+        check_lisp_ok(
+            vec!["(list (begin 1 (let ((x 2)) x)) (begin (add1 1) 3))"],
+            "(2 3)",
+        );
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (l) (car l) (cdr l) (list (car l) l)))",
+                "(f (list 1 2))",
+            ],
+            "(2 (1 2))",
+        );
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (x) (define y 2) (add1 x) (* x y)))",
+                "(f 5)",
+            ],
+            "10",
+        );
+    }
+
+    #[test]
+    fn begin_tail_calls() {
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (n acc) (add1 n) (if (zero? n) acc (f (sub1 n) (add1 acc)))))",
+                "(f 100000 0)",
+            ],
+            "100000",
+        );
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (n) (begin (add1 n) (if (zero? n) 0 (f (sub1 n))))))",
+                "(f 100000)",
+            ],
+            "0",
+        );
+    }
+
+    #[test]
+    fn begin_errors() {
+        check_lisp_err(
+            vec!["(begin)"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
+        );
+        check_lisp_err(
+            vec!["(lambda (x) x (define y 1) y)"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+    }
 
     #[test]
-    fn shadowing() {
+    fn cond_clauses() {
+        check_lisp_ok(vec!["(cond ((zero? 1) 1) ((zero? 0) 2) (else 3))"], "2");
+        check_lisp_ok(vec!["(cond ((zero? 1) 1) (else 2 3))"], "3");
+        check_lisp_ok(vec!["(cond ((zero? 1) 1))"], "()");
+        check_lisp_ok(vec!["(cond)"], "()");
         check_lisp_ok(
             vec![
-                "(define f (lambda (x) (lambda (x) x)))",
-                "(list ((f #t) 3) ((f (list)) #f))",
+                "(define f (lambda (n acc) (cond ((zero? n) acc) (else (f (sub1 n) (add1 acc))))))",
+                "(f 100000 0)",
             ],
-            "(3 #f)",
+            "100000",
+        );
+    }
+
+    #[test]
+    fn cond_test_values() {
+        check_lisp_ok(vec!["(cond ((car (list 5)) => add1) (else 0))"], "6");
+        check_lisp_ok(vec!["(cond ((car (list #f)) => add1) (else 0))"], "0");
+        check_lisp_ok(vec!["(cond (#f 1) ((car (list 7))))"], "7");
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (l n) (cond ((null? l) n) ((car l) => (lambda (x) (f (cdr l) (+ n x)))))))",
+                "(f (list 1 2 3) 0)",
+            ],
+            "6",
+        );
+    }
+
+    #[test]
+    fn when_unless() {
+        check_lisp_ok(vec!["(when (zero? 0) 1 2)"], "2");
+        check_lisp_ok(vec!["(when (zero? 1) 1 2)"], "()");
+        check_lisp_ok(vec!["(unless (zero? 1) 5)"], "5");
+        check_lisp_ok(vec!["(unless (zero? 0) 5)"], "()");
+    }
+
+    #[test]
+    fn and_or() {
+        check_lisp_ok(
+            vec!["(list (and) (or) (and #t #f) (and #t #t) (or #f #f) (or #f #t))"],
+            "(#t #f #f #t #f #t)",
+        );
+        check_lisp_ok(vec!["(list (and #t 5) (or #f 6) (and 7))"], "(5 6 7)");
+    }
+
+    #[test]
+    fn and_or_short_circuit() {
+        check_lisp_ok(vec!["(and (null? (list 1)) (car (list)))"], "#f");
+        check_lisp_ok(vec!["(or (null? (list)) (car (list)))"], "#t");
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (l) (and (not (null? l)) (car l))))",
+                "(define not (lambda (t) (if t #f #t)))",
+                "(list (f (list)) (f (list 1 2)))",
+            ],
+            "(#f 2)",
+        );
+    }
+
+    #[test]
+    fn and_or_tail_calls() {
+        check_lisp_ok(
+            vec![
+                "(define all-zero? (lambda (l) (or (null? l) (and (zero? (car l)) (all-zero? (cdr l))))))",
+                "(define zeros (lambda (n l) (if (zero? n) l (zeros (sub1 n) (cons 0 l)))))",
+                "(all-zero? (zeros 100000 (list)))",
+            ],
+            "#t",
+        );
+    }
+
+    #[test]
+    fn rest_arguments() {
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (a b . rest) (list a b rest)))",
+                "(list (f 1 2) (f 1 2 3 4))",
+            ],
+            "((1 2 ()) (1 2 (3 4)))",
+        );
+        check_lisp_ok(
+            vec!["(define f (lambda args args))", "(list (f) (f 1 2 3))"],
+            "(() (1 2 3))",
+        );
+        check_lisp_ok(vec!["((lambda (. xs) xs) 1 2)"], "(1 2)");
+    }
+
+    #[test]
+    fn optional_arguments() {
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (a #!optional b (c (+ 1 2))) (list a b c)))",
+                "(list (f 1) (f 1 2) (f 1 2 4))",
+            ],
+            "((1 () 3) (1 2 3) (1 2 4))",
+        );
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (#!optional (a 0) . rest) (list a rest)))",
+                "(list (f) (f 1) (f 1 2 3))",
+            ],
+            "((0 ()) (1 ()) (1 (2 3)))",
+        );
+        // Default values may use variables around the lambda
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (x) (lambda (#!optional (y x)) (list x y))))",
+                "(list ((f 5)) ((f 5) 6))",
+            ],
+            "((5 5) (5 6))",
+        );
+    }
+
+    #[test]
+    fn arity_rules() {
+        // Too few required arguments returns a function that takes the
+        // remaining arguments, including the optional and rest ones
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (a b #!optional (c 3) . rest) (list a b c rest)))",
+                "(list ((f 1) 2) ((f 1) 2 4) ((f 1) 2 4 5 6))",
+            ],
+            "((1 2 3 ()) (1 2 4 ()) (1 2 4 (5 6)))",
+        );
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (a b . r) (list a b r)))",
+                "(define g (f 1))",
+                "(list (g 2) (g 2 3 4))",
+            ],
+            "((1 2 ()) (1 2 (3 4)))",
+        );
+        // Without a rest argument, the result of a function is applied to the
+        // arguments after its required and optional ones
+        check_lisp_ok(
+            vec!["((lambda (a #!optional b) (lambda (c) (list a b c))) 1 2 3)"],
+            "(1 2 3)",
+        );
+        // Default values are evaluated once, when the lambda is created
+        check_lisp_ok(
+            vec![
+                "(define counter (lambda (n) (lambda (#!optional (m (add1 n))) m)))",
+                "(define f (counter 1))",
+                "(list (f) (f) (f 5))",
+            ],
+            "(2 2 5)",
+        );
+    }
+
+    #[test]
+    fn variadic_recursion() {
+        check_lisp_ok(
+            vec![
+                "(define count (lambda (n #!optional (acc 0)) (if (zero? n) acc (count (sub1 n) (add1 acc)))))",
+                "(count 100000)",
+            ],
+            "100000",
+        );
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (x) (define g (lambda (#!optional (y 3) . ys) (+ x y))) (list (g) (g 1 2))))",
+                "(f 10)",
+            ],
+            "(13 11)",
+        );
+        check_lisp_ok(
+            vec!["(defmacro my-list (. xs) `(list ,@xs))", "(my-list 1 2 3)"],
+            "(1 2 3)",
+        );
+    }
+
+    #[test]
+    fn variadic_errors() {
+        check_lisp_err(
+            vec!["(lambda (a . b c) a)"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(lambda (a .) a)"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(lambda (#!optional (a)) a)"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(lambda (#!optional a #!optional b) a)"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(lambda ((a 1)) a)"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(letrec ((f (lambda (x . r) x))) (f 1))"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+    }
+
+    #[test]
+    fn over_application() {
+        check_lisp_ok(vec!["((lambda (x) (lambda (y) (+ x y))) 1 2)"], "3");
+        check_lisp_ok(
+            vec!["((lambda (x) (lambda (y) (lambda (z) (list x y z)))) 1 2 3)"],
+            "(1 2 3)",
+        );
+        check_lisp_ok(vec!["((lambda (x) add1) 0 5)"], "6");
+        check_lisp_ok(
+            vec![
+                "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
+                "((add 10) 3)",
+            ],
+            "13",
+        );
+        check_lisp_ok(
+            vec!["(list 1 ((lambda (x) (lambda (y) y)) 1 2) 3)"],
+            "(1 2 3)",
+        );
+        check_lisp_err(
+            vec!["((lambda (x) 5) 1 2)"],
+            LispError::Evaluation(EvaluationError::NonFunctionApplication),
+        );
+    }
+
+    #[test]
+    fn builtin_over_application() {
+        check_lisp_ok(vec!["(car (list add1) 5)"], "6");
+        check_lisp_ok(vec!["(define g car)", "(g (list add1) 5)"], "6");
+        check_lisp_ok(vec!["((lambda (l) (car l 5)) (list add1))"], "6");
+        check_lisp_ok(
+            vec!["((lambda (l) (list (car l 5) (car l 6))) (list add1))"],
+            "(6 7)",
+        );
+        check_lisp_ok(
+            vec!["(define f (lambda (l n) (car l n)))", "(f (list sub1) 5)"],
+            "4",
+        );
+        check_lisp_err(
+            vec!["(add1 1 2)"],
+            LispError::Evaluation(EvaluationError::NonFunctionApplication),
+        );
+        check_lisp_err(
+            vec!["((lambda (l) (car l 5)) (list 1))"],
+            LispError::Evaluation(EvaluationError::NonFunctionApplication),
+        );
+    }
+
+    #[test]
+    fn builtin_tail_calls_with_locals() {
+        check_lisp_ok(vec!["(let ((f add1)) (f 1))"], "2");
+        check_lisp_ok(vec!["(match add1 (f (f 1)))"], "2");
+        check_lisp_ok(
+            vec![
+                "(define app (lambda (g x) (let ((y x)) (g y))))",
+                "(app add1 1)",
+            ],
+            "2",
+        );
+        check_lisp_ok(
+            vec![
+                "(define app5 (lambda (g) (define h g) (h 1)))",
+                "(app5 add1)",
+            ],
+            "2",
+        );
+        check_lisp_ok(
+            vec![
+                "(define app8 (lambda (g) (let ((y 1)) (g (list add1) y))))",
+                "(list (app8 car) (app8 car))",
+            ],
+            "(2 2)",
+        );
+        // Arguments reused in place while locals are on the stack
+        check_lisp_ok(vec!["((lambda (x g) (let ((y 1)) (g x))) 5 add1)"], "6");
+        check_lisp_ok(
+            vec!["(((lambda (x g) (let ((y 1)) (g x))) 5 (lambda (a b) (+ a b))) 10)"],
+            "15",
+        );
+    }
+
+    #[test]
+    fn over_application_tail_calls() {
+        check_lisp_ok(
+            vec![
+                "(define adder (lambda (x) (lambda (y) (+ x y))))",
+                "(define f (lambda (n) (adder n 1)))",
+                "(f 5)",
+            ],
+            "6",
+        );
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (n acc) (if (zero? n) acc ((lambda (x) (lambda (m a) (f m a))) 0 (sub1 n) (add1 acc)))))",
+                "(f 100000 0)",
+            ],
+            "100000",
+        );
+        // Calls to the function itself with a different number of arguments
+        // do not recurse in place
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (x y) (if (zero? x) y (f (sub1 x)))))",
+                "(((f 2 0) 5) 7)",
+            ],
+            "7",
+        );
+        // Tail calls that reuse arguments in place also work for built-in
+        // and curried functions
+        check_lisp_ok(vec!["((lambda (x f) (f x)) 1 add1)"], "2");
+        check_lisp_ok(
+            vec!["(((lambda (x f) (f x)) 1 (lambda (a b) (+ a b))) 5)"],
+            "6",
+        );
+    }
+
+    #[test]
+    fn match_patterns() {
+        check_lisp_ok(
+            vec!["(list (match 1 (0 'zero) (1 'one) (_ 'other)) (match 5 (0 'zero) (n (add1 n))))"],
+            "(one 6)",
+        );
+        check_lisp_ok(
+            vec![
+                r#"(list (match "hi" ("hi" #t) (_ #f)) (match 'foo ('bar 1) ('foo 2)) (match (list 1 2) ('(1 2) 3)))"#,
+            ],
+            "(#t 2 3)",
+        );
+        // List patterns are read like nested conses
+        check_lisp_ok(
+            vec!["(match (cons 1 (cons 2 (list))) ((a b) (list a b)))"],
+            "(1 2)",
+        );
+        check_lisp_ok(
+            vec!["(match (list 1 2 3) ((a b c) (list (car (list 1 2 3)) a)))"],
+            "(3 3)",
+        );
+        check_lisp_ok(
+            vec!["(match (list (list 2 3) 1) ((x (y z)) (+ x y z)))"],
+            "6",
+        );
+        check_lisp_ok(
+            vec!["(list (match (list) (() 'empty) (_ 'full)) (match (list 1) (() 'empty) (_ 'full)))"],
+            "(empty full)",
+        );
+    }
+
+    #[test]
+    fn match_rest_patterns() {
+        check_lisp_ok(
+            vec!["(match (cons 1 (list 2 3)) ((a . rest) (list a rest)))"],
+            "(1 (2 3))",
+        );
+        check_lisp_ok(
+            vec!["(match (cons 1 (cons 2 (list 3))) ((a . (b . c)) (list a b c)))"],
+            "(1 2 (3))",
+        );
+        check_lisp_ok(
+            vec!["(match (list 1 2 3) ((a b) 'two) ((a b c d . r) 'many) ((_ . _) 'some))"],
+            "some",
+        );
+        check_lisp_ok(
+            vec![
+                "(define sum (lambda (l acc) (match l (() acc) ((x . r) (sum r (+ acc x))))))",
+                "(define range (lambda (n l) (if (zero? n) l (range (sub1 n) (cons n l)))))",
+                "(sum (range 100000 (list)) 0)",
+            ],
+            "5000050000",
+        );
+    }
+
+    #[test]
+    fn match_guards() {
+        check_lisp_ok(
+            vec![
+                "(define size (lambda (n) (match n (n when (> n 3) 'big) (0 'none) (_ 'small))))",
+                "(list (size 4) (size 0) (size 2))",
+            ],
+            "(big none small)",
+        );
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (x) (match x ((a b) (lambda (c) (list a b c))))))",
+                "((f (cons 1 (list 2))) 3)",
+            ],
+            "(1 2 3)",
+        );
+        // Names bound by patterns in templates do not capture arguments
+        check_lisp_ok(
+            vec![
+                "(define-syntax add-one (syntax-rules () ((_ e) (match 1 (x (+ x e))))))",
+                "(let ((x 10)) (add-one x))",
+            ],
+            "11",
+        );
+    }
+
+    #[test]
+    fn match_errors() {
+        check_lisp_err(
+            vec!["(match 7 (0 1))"],
+            LispError::Evaluation(EvaluationError::NoMatch),
+        );
+        check_lisp_err(
+            vec!["(match 5 (n when (> n 5) n))"],
+            LispError::Evaluation(EvaluationError::NoMatch),
+        );
+        check_lisp_err(
+            vec!["(match (list 1 2) ((a a) a))"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(match (list 1 2) ((a . b c) a))"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(match 1 (car 1))"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(match 1 2)"],
+            LispError::Evaluation(EvaluationError::ArgumentTypeMismatch),
+        );
+        check_lisp_err(
+            vec!["(match)"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
         );
     }
 
     #[test]
-    fn church_pair() {
+    fn records() {
         check_lisp_ok(
             vec![
-                "(define pair (lambda (x y f) (f x y)))",
-                "(define snd (lambda (x y) y))",
-                "(pair 1 2 snd)",
+                "(define-record point x y)",
+                "(define p (make-point 1 (list 2 3)))",
+                "(list p (point-x p) (point-y p) (point? p) (point? (list 1 2)) (record? p))",
             ],
-            "2",
+            "(#<point 1 (2 3)> 1 (2 3) #t #f #t)",
         );
-    }
-
-    #[test]
-    fn cond_argument() {
         check_lisp_ok(
             vec![
-                "(define > (lambda (x y) (cond (zero? x) #f (cond (zero? y) #t (> (sub1 x) (sub1 y))))))",
-                "(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))",
-                "(add (cond (> 12 5) 1 2) 3)",
+                "(define-record point x y)",
+                "(define apply-to (lambda (f v) (f v)))",
+                "(list make-point point? point-y (apply-to point-x (make-point 1 2)))",
             ],
-            "4",
+            "(make-point point? point-y 1)",
         );
-    }
-
-    #[test]
-    fn shadowing_two() {
         check_lisp_ok(
             vec![
-                "(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))",
-                "((lambda (x) (add ((lambda (x) (add1 x)) x) x)) 10)",
+                "(define-record empty)",
+                "(define-record pair a b)",
+                "(list (equal? (make-empty) (make-empty)) (equal? (make-pair 1 2) (make-pair 1 2)) (empty? (make-pair 1 2)))",
             ],
-            "21",
+            "(#t #t #f)",
         );
     }
 
     #[test]
-    fn shadowing_three() {
+    fn record_patterns() {
         check_lisp_ok(
             vec![
-                "(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))",
-                "((lambda (x) (add x ((lambda (x) (add (sub1 x) x)) x))) 4)",
+                "(define-record leaf)",
+                "(define-record node left value right)",
+                "(define sum (lambda (t) (match t ((make-leaf) 0) ((make-node l v r) (+ (sum l) v (sum r))))))",
+                "(sum (make-node (make-node (make-leaf) 1 (make-leaf)) 2 (make-node (make-leaf) 3 (make-leaf))))",
             ],
-            "11",
+            "6",
         );
-    }
-
-    #[test]
-    fn return_curried_fn() {
         check_lisp_ok(
             vec![
-                "(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))",
-                "(((lambda () (cond #f 0 (add 1)))) 2)",
+                "(define-record point x y)",
+                "(define classify (lambda (v) (match v ((make-point 0 _) 'axis) ((make-point x y) when (= x y) 'diagonal) ((a b) 'list) (_ 'other))))",
+                "(list (classify (make-point 0 1)) (classify (make-point 2 2)) (classify (make-point 1 2)) (classify (list 1 2)))",
             ],
-            "3",
+            "(axis diagonal other list)",
         );
-    }
-
-    #[test]
-    fn church_numerals() {
+        // Constructors in patterns are not renamed by syntax-rules
         check_lisp_ok(
             vec![
-                "(define n0 (lambda (f x) x))",
-                "(define incr (lambda (n f x) (f (n f x))))",
-                "((incr n0) add1 0)",
+                "(define-record point x y)",
+                "(define-syntax point-sum (syntax-rules () ((_ v) (match v ((make-point x y) (+ x y))))))",
+                "(let ((x 1)) (point-sum (make-point x 2)))",
             ],
-            "1",
+            "3",
         );
-    }
-
-    #[test]
-    fn display_int_val() {
-        let state = Default::default();
-        let val = LispValue::Integer(5);
-        assert_eq!("5", print::print_value(&val, &state, 0));
-    }
-
-    #[test]
-    fn display_list_val() {
-        let state = Default::default();
-        let val = LispValue::List(vec![LispValue::Integer(1), LispValue::List(vec![])]);
-        assert_eq!("(1 ())", print::print_value(&val, &state, 0));
-    }
-
-    #[test]
-    fn function_add() {
+        // Local bindings shadow constructors in patterns
         check_lisp_ok(
             vec![
-                "(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))",
-                "(add 77 12)",
+                "(define-record point x y)",
+                "(let ((make-point 1)) (match (list 1 2 3) ((make-point a b) 'list) (_ 'other)))",
             ],
-            "89",
+            "list",
         );
-    }
-
-    #[test]
-    fn function_multiply() {
         check_lisp_ok(
             vec![
-                "(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))",
-                "(define mult (lambda (x y) (cond (zero? y) 0 (add x (mult x (sub1 y))))))",
-                "(mult 7 3)",
+                "(define-record point x y)",
+                "(define f (lambda (make-point) (match (list 1 2 3) ((make-point a b) 'param) (_ 'other))))",
+                "(define g (lambda () (define make-point 0) (match (list 1 2 3) ((make-point a b) 'define) (_ 'other))))",
+                "(list (f 0) (g))",
             ],
-            "21",
+            "(param define)",
         );
     }
 
     #[test]
-    fn map2_zip() {
-        check_lisp_ok(
+    fn record_errors() {
+        check_lisp_err(
+            vec!["(define-record point x y)", "(point-x (list 1 2))"],
+            LispError::Evaluation(EvaluationError::ArgumentTypeMismatch),
+        );
+        check_lisp_err(
             vec![
-                "(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))",
-                "(define or (lambda (x y) (cond x #t y)))",
-                "(define zip (lambda (x y) (cond (or (null? x) (null? y)) (list) (cons (list (car x) (car y)) (zip (cdr x) (cdr y))))))",
-                "(define map2 (lambda (f l) (cond (null? l) (list) (cons (f (car (cdr (car l))) (car (car l))) (map2 f (cdr l))))))",
-                "(map2 add (zip (list 1 2 3 4 5) (list 0 20 40 60)))",
+                "(define-record point x y)",
+                "(define-record size x)",
+                "(point-x (make-size 1))",
             ],
-            "(2 23 44 65)",
+            LispError::Evaluation(EvaluationError::ArgumentTypeMismatch),
         );
-    }
-
-    #[test]
-    fn function_def() {
-        check_lisp_ok(
-            vec!["(define add2 (lambda (x) (add1 (add1 x))))", "(add2 5)"],
-            "7",
+        check_lisp_err(
+            vec!["(define-record point x y)", "(make-point 1)"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
         );
-    }
-
-    #[test]
-    fn variable_overwrite() {
         check_lisp_err(
-            vec!["(define x 1)", "(define x 1000)", "(add1 x)"],
+            vec!["(define-record point x y)", "(define-record point z)"],
             LispError::Evaluation(EvaluationError::BadDefine),
         );
-    }
-
-    #[test]
-    fn eval_empty_call() {
         check_lisp_err(
-            vec!["()"],
-            LispError::Evaluation(EvaluationError::EmptyListEvaluation),
+            vec!["(define-record point x x)"],
+            LispError::Evaluation(EvaluationError::BadDefine),
+        );
+        check_lisp_err(
+            vec!["(define-record string length)"],
+            LispError::Evaluation(EvaluationError::BadDefine),
+        );
+        check_lisp_err(
+            vec!["(define-record point x y)", "(match 1 ((make-point x) x))"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
+        );
+        check_lisp_err(
+            vec!["(lambda (x) (define-record point x))"],
+            LispError::Evaluation(EvaluationError::MalformedDefinition),
         );
     }
 
     #[test]
-    fn check_int() {
+    fn raise_and_try() {
+        check_lisp_ok(
+            vec!["(list (try (raise 5) (lambda (e) (list 'caught e))) (try 3 (lambda (e) 0)))"],
+            "((caught 5) 3)",
+        );
         check_lisp_ok(
             vec![
-                "(define map (lambda (f xs) (cond (null? xs) (list) (cons (f (car xs)) (map f (cdr xs))))))",
-                "(map int? (list 1 2 #t (list 3 4) add1 0))",
+                "(list (try (car (list)) (lambda (e) e)) (try (quotient 1 0) (lambda (e) (eq? e 'division-by-zero))))",
             ],
-            "(#t #t #f #f #f #t)",
+            "(empty-list #t)",
         );
-    }
-
-    #[test]
-    fn check_bool() {
         check_lisp_ok(
-            vec!["(list (bool? #t) (bool? #f) (bool? 5) (bool? (list #t)) (bool? bool?))"],
-            "(#t #t #f #f #f)",
+            vec![
+                "(define kind (lambda (thunk) (try (thunk) (lambda (e) e))))",
+                "(list (kind (lambda () (car 1))) (kind (lambda () (quotient 1))) (kind (lambda () (1 2))))",
+            ],
+            "(argument-type-mismatch argument-count-mismatch non-function-application)",
         );
-    }
-
-    #[test]
-    fn check_fun() {
         check_lisp_ok(
             vec![
-                "(list (fun? #t) (fun? fun?) (fun? 0) (fun? bool?) (fun? (lambda (x) #t)) (fun? #t))",
+                "(define f (lambda () (g 1)))",
+                "(list (try undefined-name (lambda (e) e)) (try (f) (lambda (e) e)) (if #f undefined-name 1))",
             ],
-            "(#f #t #f #t #t #f)",
+            "(unknown-variable unknown-variable 1)",
         );
-    }
-
-    #[test]
-    fn check_use_var_after_cond_zero() {
         check_lisp_ok(
-            vec!["(define f (lambda (x) (cond (zero? x) x x)))", "(f 1)"],
-            "1",
+            vec![
+                "(try (try (raise 1) (lambda (e) (raise (add1 e)))) (lambda (e) (list 'outer e)))",
+            ],
+            "(outer 2)",
         );
-    }
-
-    #[test]
-    fn check_list() {
         check_lisp_ok(
-            vec!["(list (list? list) (list? list?) (list? (list)))"],
-            "(#f #f #t)",
+            vec![
+                "(define-record failure code)",
+                "(try (raise (make-failure 3)) (lambda (e) (match e ((make-failure c) c))))",
+            ],
+            "3",
         );
     }
 
     #[test]
-    fn is_null_empty_list() {
-        check_lisp_ok(vec!["(null? (list))"], "#t");
-    }
-
-    #[test]
-    fn cdr() {
-        check_lisp_ok(vec!["(cdr (list 1 2 3 4))"], "(1 2 3)");
+    fn try_unwinding() {
+        check_lisp_ok(
+            vec![
+                "(define deep (lambda (n) (if (zero? n) (raise 'bottom) (add1 (deep (sub1 n))))))",
+                "(try (deep 1000) (lambda (e) e))",
+            ],
+            "bottom",
+        );
+        check_lisp_ok(
+            vec![
+                "(define count (lambda (n) (if (zero? n) (raise n) (count (sub1 n)))))",
+                "(try (count 100000) (lambda (e) (list 'done e)))",
+            ],
+            "(done 0)",
+        );
+        // Try expressions in tail position and among local variables
+        check_lisp_ok(
+            vec![
+                "(define f (lambda (l) (try (car l) (lambda (e) 0))))",
+                "(define g (lambda (l) (let ((a 1)) (+ a (try (let ((b 2)) (+ b (car l))) (lambda (e) 100))))))",
+                "(list (f (list)) (f (list 7)) (g (list)) (g (list 5)))",
+            ],
+            "(0 7 101 8)",
+        );
+        // Variables used after the body are intact when it fails
+        check_lisp_ok(
+            vec![
+                "(define k (lambda (l) (list (try (car (cdr l)) (lambda (e) 'none)) (car l))))",
+                "(list (k (list 1 2 3)) (k (list 1)))",
+            ],
+            "((2 3) (none 1))",
+        );
+        check_lisp_ok(
+            vec![
+                "(define make (lambda (x) (lambda (y) (try (quotient x y) (lambda (e) x)))))",
+                "(list ((make 6) 2) ((make 6) 0))",
+            ],
+            "(3 6)",
+        );
     }
 
     #[test]
-    fn is_zero_of_zero() {
-        check_lisp_ok(vec!["(zero? 0)"], "#t");
+    fn try_errors() {
+        check_lisp_err(
+            vec!["(raise (list 1 2))"],
+            LispError::Evaluation(EvaluationError::Raised(LispValue::List(vec![
+                LispValue::Integer(1),
+                LispValue::Integer(2),
+            ]))),
+        );
+        check_lisp_err(
+            vec!["(try (raise 1) (lambda (e) (car e)))"],
+            LispError::Evaluation(EvaluationError::ArgumentTypeMismatch),
+        );
+        check_lisp_err(
+            vec!["(try (raise 1))"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
+        );
+        check_lisp_err(
+            vec!["(try (raise 1) 2)"],
+            LispError::Evaluation(EvaluationError::NonFunctionApplication),
+        );
     }
 
     #[test]
-    fn is_zero_of_nonzero() {
-        check_lisp_ok(vec!["(zero? 5)"], "#f");
+    fn truthiness() {
+        // Every conditional treats any value but false as true
+        check_lisp_ok(
+            vec!["(list (if 1 2 3) (if '() 2 3) (if #f 2 3) (if \"\" 2 3))"],
+            "(2 2 3 2)",
+        );
+        check_lisp_ok(
+            vec!["(list (cond (1 2) (else 3)) (cond (#f 2) (else 3)))"],
+            "(2 3)",
+        );
+        check_lisp_ok(vec!["(list (cond (5 => add1)) (cond (5)))"], "(6 5)");
+        check_lisp_ok(
+            vec!["(list (when 1 2) (when #f 2) (unless 1 2) (unless #f 2))"],
+            "(2 () () 2)",
+        );
+        check_lisp_ok(
+            vec!["(list (and 1 2) (and 1 #f 2) (or #f 3) (or #f #f))"],
+            "(2 #f 3 #f)",
+        );
+        check_lisp_ok(
+            vec!["(list (match 1 (x when x 2) (_ 3)) (match 1 (x when #f 2) (_ 3)))"],
+            "(2 3)",
+        );
     }
 
     #[test]
-    fn is_zero_of_list() {
+    fn cond_errors() {
         check_lisp_err(
-            vec!["(zero? (list 0))"],
+            vec!["(cond 1)"],
             LispError::Evaluation(EvaluationError::ArgumentTypeMismatch),
         );
-    }
-
-    #[test]
-    fn is_zero_two_args() {
         check_lisp_err(
-            vec!["(zero? 0 0)"],
+            vec!["(cond (else 1) (#t 2))"],
+            LispError::Evaluation(EvaluationError::UnexpectedOperator),
+        );
+        check_lisp_err(
+            vec!["(cond (else))"],
             LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
         );
-    }
-
-    #[test]
-    fn too_few_arguments() {
         check_lisp_err(
-            vec!["(add1)"],
+            vec!["(cond (1 => car 2))"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
+        );
+        check_lisp_err(
+            vec!["(if #t 1)"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
+        );
+        check_lisp_err(
+            vec!["(when)"],
             LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
         );
     }
 
     #[test]
-    fn lower_level_define() {
+    fn internal_define_errors() {
         check_lisp_err(
-            vec!["(list (define x 5))"],
+            vec!["(lambda (x) (define 5 1) x)"],
+            LispError::Evaluation(EvaluationError::BadDefine),
+        );
+        check_lisp_err(
+            vec!["(lambda (x) (define y) x)"],
+            LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
+        );
+        check_lisp_err(
+            vec!["(lambda (x) (define y 1))"],
             LispError::Evaluation(EvaluationError::MalformedDefinition),
         );
+        // Values cannot depend on themselves through functions
+        check_lisp_err(
+            vec!["(lambda (x) (define f (lambda () y)) (define y (f)) y)"],
+            LispError::Evaluation(EvaluationError::BadDefine),
+        );
+        check_lisp_err(
+            vec!["(lambda (x) (define a 1) (define a 2) a)"],
+            LispError::Evaluation(EvaluationError::BadDefine),
+        );
+        check_lisp_err(
+            vec!["(lambda (x) (define f (lambda () 1)) (define f (lambda () (f))) (f))"],
+            LispError::Evaluation(EvaluationError::BadDefine),
+        );
     }
 
     #[test]
     fn too_many_arguments() {
         check_lisp_err(
-            vec!["(lambda f (x) (add1 x) ())"],
+            vec!["(define f (x) (add1 x))"],
             LispError::Evaluation(EvaluationError::ArgumentCountMismatch),
         );
     }
@@ -1629,7 +5765,7 @@ mod tests {
     #[test]
     fn unexpected_operator() {
         check_lisp_err(
-            vec!["(cond cond cond cond)"],
+            vec!["(if cond cond cond)"],
             LispError::Evaluation(EvaluationError::UnexpectedOperator),
         );
     }
@@ -1644,14 +5780,7 @@ mod tests {
 
     #[test]
     fn test_variable_list() {
-        check_lisp_ok(
-            vec![
-                "(define x 3)",
-                "(define + (lambda (x y) (cond (zero? y) x (+ (add1 x) (sub1 y)))))",
-                "(list x 1 (+ 1 x) 5)",
-            ],
-            "(3 1 4 5)",
-        );
+        check_lisp_ok(vec!["(define x 3)", "(list x 1 (+ 1 x) 5)"], "(3 1 4 5)");
     }
 
     #[test]
@@ -1663,7 +5792,7 @@ mod tests {
     fn map() {
         check_lisp_ok(
             vec![
-                "(define map (lambda (f xs) (cond (null? xs) (list) (cons (f (car xs)) (map f (cdr xs))))))",
+                "(define map (lambda (f xs) (if (null? xs) (list) (cons (f (car xs)) (map f (cdr xs))))))",
                 "(map add1 (list 1 2 3))",
             ],
             "(2 3 4)",
@@ -1674,7 +5803,7 @@ mod tests {
     fn popn() {
         check_lisp_ok(
             vec![
-                "(define popn (lambda (l n) (cond (zero? n) l (popn (cdr l) (sub1 n)))))",
+                "(define popn (lambda (l n) (if (zero? n) l (popn (cdr l) (sub1 n)))))",
                 "(popn (list 1 2 3 4 5) 2)",
             ],
             "(1 2 3)",
@@ -1685,9 +5814,9 @@ mod tests {
     fn lambda() {
         check_lisp_ok(
             vec![
-                "(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))",
-                "(define mult (lambda (x y) (cond (zero? y) 0 (add x (mult x (sub1 y))))))",
-                "(define map (lambda (f xs) (cond (null? xs) (list) (cons (f (car xs)) (map f (cdr xs))))))",
+                "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
+                "(define mult (lambda (x y) (if (zero? y) 0 (add x (mult x (sub1 y))))))",
+                "(define map (lambda (f xs) (if (null? xs) (list) (cons (f (car xs)) (map f (cdr xs))))))",
                 "(map (lambda (x) (mult x x)) (list 1 2 3))",
             ],
             "(1 4 9)",
@@ -1695,25 +5824,22 @@ mod tests {
     }
 
     const SORT_COMMANDS: &[&str] = &[
-        "(define filter (lambda (f xs) (cond (null? xs) (list) (cond (f (car xs)) (cons (car xs) (filter f (cdr xs))) (filter f (cdr xs))))))",
-        "(define not (lambda (t) (cond t #f #t)))",
-        "(define > (lambda (x y) (cond (zero? x) #f (cond (zero? y) #t (> (sub1 x) (sub1 y))))))",
-        "(define and (lambda (t1 t2) (cond t1 t2 #f)))",
-        "(define append (lambda (l1 l2) (cond (null? l2) l1 (cons (car l2) (append l1 (cdr l2))))))",
-        "(define sort (lambda (l) (cond (null? l) l (append (cons (car l) (sort (filter (lambda (x) (not (> x (car l)))) (cdr l)))) (sort (filter (lambda (x) (> x (car l))) l))))))",
-        "(define sort_rev (lambda (l) (cond (null? l) l (app_rev (cons (car l) (sort_rev (filter (lambda (x) (not (< x (car l)))) (cdr l)))) (sort' (filter (lambda (x) (< x (car l))) l))))))",
-        "(define sort' (lambda (l) (cond (null? l) l (app_rev (cons (car l) (sort' (filter (lambda (x) (not (> x (car l)))) (cdr l)))) (sort_rev (filter (lambda (x) (> x (car l))) l))))))",
-        "(define app_rev (lambda (l r) (cond (null? r) l (app_rev (cons (car r) l) (cdr r)))))",
-        "(define < (lambda (x y) (> y x)))",
+        "(define filter (lambda (f xs) (if (null? xs) (list) (if (f (car xs)) (cons (car xs) (filter f (cdr xs))) (filter f (cdr xs))))))",
+        "(define not (lambda (t) (if t #f #t)))",
+        "(define append (lambda (l1 l2) (if (null? l2) l1 (cons (car l2) (append l1 (cdr l2))))))",
+        "(define sort (lambda (l) (if (null? l) l (append (cons (car l) (sort (filter (lambda (x) (not (> x (car l)))) (cdr l)))) (sort (filter (lambda (x) (> x (car l))) l))))))",
+        "(define sort_rev (lambda (l) (if (null? l) l (app_rev (cons (car l) (sort_rev (filter (lambda (x) (not (< x (car l)))) (cdr l)))) (sort' (filter (lambda (x) (< x (car l))) l))))))",
+        "(define sort' (lambda (l) (if (null? l) l (app_rev (cons (car l) (sort' (filter (lambda (x) (not (> x (car l)))) (cdr l)))) (sort_rev (filter (lambda (x) (> x (car l))) l))))))",
+        "(define app_rev (lambda (l r) (if (null? r) l (app_rev (cons (car r) l) (cdr r)))))",
     ];
 
     #[test]
     fn sort() {
         check_lisp_ok(
             SORT_COMMANDS
-                .into_iter()
+                .iter()
                 .cloned()
-                .chain(Some("(sort (list 5 3 2 10 0 7))").into_iter()),
+                .chain(Some("(sort (list 5 3 2 10 0 7))")),
             "(0 2 3 5 7 10)",
         );
     }
@@ -1722,8 +5848,8 @@ mod tests {
     fn closures() {
         check_lisp_ok(
             vec![
-                "(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))",
-                "(define map (lambda (f xs) (cond (null? xs) (list) (cons (f (car xs)) (map f (cdr xs))))))",
+                "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
+                "(define map (lambda (f xs) (if (null? xs) (list) (cons (f (car xs)) (map f (cdr xs))))))",
                 "(map (lambda (f) (f 10)) (map (lambda (n) (lambda (x) (add x n))) (list 1 2 3 4 5 6 7 8 9 10)))",
             ],
             "(11 12 13 14 15 16 17 18 19 20)",
@@ -1740,7 +5866,7 @@ mod tests {
     fn curry() {
         check_lisp_ok(
             vec![
-                "(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))",
+                "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
                 "(define sum3 (lambda (x y z) (add x (add y z))))",
                 "(define sum2and5 (sum3 5))",
                 "(sum2and5 10 20)",
@@ -1753,9 +5879,9 @@ mod tests {
     fn cyclic_func_calls() {
         check_lisp_ok(
             vec![
-                "(define <' (lambda (x y) (cond (zero? y) #f (< x (sub1 y)))))",
-                "(define < (lambda (x y) (cond (zero? x) (cond (zero? y) #f #t) (<' (sub1 x) y))))",
-                "(list (< 1 2) (< 0 1) (< 2 2) (< 1 1) (< 1 0) (< 2 1))",
+                "(define lt' (lambda (x y) (if (zero? y) #f (lt x (sub1 y)))))",
+                "(define lt (lambda (x y) (if (zero? x) (if (zero? y) #f #t) (lt' (sub1 x) y))))",
+                "(list (lt 1 2) (lt 0 1) (lt 2 2) (lt 1 1) (lt 1 0) (lt 2 1))",
             ],
             "(#t #t #f #f #f #f)",
         );
@@ -1767,8 +5893,7 @@ mod tests {
     fn range() {
         check_lisp_ok(
             vec![
-                "(define > (lambda (x y) (cond (zero? x) #f (cond (zero? y) #t (> (sub1 x) (sub1 y))))))",
-                "(define range (lambda (start end) (cond (> end start) (cons end (range start (sub1 end))) (list start))))",
+                "(define range (lambda (start end) (if (> end start) (cons end (range start (sub1 end))) (list start))))",
                 "(range 1 5)",
             ],
             "(1 2 3 4 5)",
@@ -1790,7 +5915,7 @@ mod tests {
         if let LispValue::Function(..) = check_lisp(
             &mut state,
             vec![
-                "(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))",
+                "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
                 "(add)",
             ],
         )
@@ -1801,6 +5926,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn eval_program() {
+        let mut state = State::default();
+        let program = super::parse::parse_program(
+            "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))
+             (define x 5)
+             (add x 10)",
+            &mut state,
+        )
+        .unwrap();
+        let val = evaluator::eval_program(program, &mut state).unwrap();
+
+        assert_eq!(LispValue::Integer(15), val);
+    }
+
+    #[test]
+    fn eval_program_error_index() {
+        let mut state = State::default();
+        let program = super::parse::parse_program(
+            "(define x 5) (add1 x) (car (list)) (define y 3)",
+            &mut state,
+        )
+        .unwrap();
+        let err = evaluator::eval_program(program, &mut state).unwrap_err();
+
+        assert_eq!(
+            evaluator::ProgramError {
+                form_index: 2,
+                error: EvaluationError::EmptyList,
+            },
+            err
+        );
+        // Forms after the failing one are not evaluated
+        assert_eq!(None, state.get(state.interns.get("y").unwrap()));
+    }
+
+    #[test]
+    fn eval_empty_program() {
+        let mut state = State::default();
+        let val = evaluator::eval_program(vec![], &mut state).unwrap();
+
+        assert_eq!(LispValue::List(vec![]), val);
+    }
+
     #[bench]
     fn bench_add_including_compilation(b: &mut super::test::Bencher) {
         b.iter(|| {
@@ -1808,7 +5977,7 @@ mod tests {
             check_lisp(
                 &mut state,
                 vec![
-                    "(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))",
+                    "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
                     "(add 100 100)",
                 ],
             )
@@ -1819,7 +5988,7 @@ mod tests {
     fn bench_curry(b: &mut super::test::Bencher) {
         let mut state = State::default();
         let init_commands = vec![
-            "(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))",
+            "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
             "(define curried-add (lambda (x y) ((add x) y)))",
         ];
 
@@ -1835,8 +6004,8 @@ mod tests {
     fn bench_mutual_recursion(b: &mut super::test::Bencher) {
         let mut state = State::default();
         let init_commands = vec![
-            "(define <' (lambda (x y) (cond (zero? y) #f (< x (sub1 y)))))",
-            "(define < (lambda (x y) (cond (zero? x) (cond (zero? y) #f #t) (<' (sub1 x) y))))",
+            "(define lt' (lambda (x y) (if (zero? y) #f (lt x (sub1 y)))))",
+            "(define lt (lambda (x y) (if (zero? x) (if (zero? y) #f #t) (lt' (sub1 x) y))))",
         ];
 
         for cmd in init_commands {
@@ -1844,14 +6013,14 @@ mod tests {
             evaluator::eval(expr, &mut state).unwrap();
         }
 
-        b.iter(|| check_lisp(&mut state, vec!["(< 10000 10000)"]));
+        b.iter(|| check_lisp(&mut state, vec!["(lt 10000 10000)"]));
     }
 
     #[bench]
     fn bench_big_add(b: &mut super::test::Bencher) {
         let mut state = State::default();
         let init_commands =
-            vec!["(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))"];
+            vec!["(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))"];
 
         for cmd in init_commands {
             let expr = parse_lisp_string(cmd, &mut state).unwrap();
@@ -1866,15 +6035,13 @@ mod tests {
     fn bench_arithmetic_sums(b: &mut super::test::Bencher) {
         let mut state = State::default();
         let init_commands = vec![
-            "(define > (lambda (x y) (cond (zero? x) #f (cond (zero? y) #t (> (sub1 x) (sub1 y))))))",
-            "(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))",
-            "(define range (lambda (start end) (cond (> end start) (cons end (range start (sub1 end))) (list start))))",
-            "(define map2 (lambda (f l) (cond (null? l) l (cons (f (car (cdr (car l))) (car (car l))) (map2 f (cdr l))))))",
-            "(define foldr (lambda (xs f init) (cond (null? xs) init (foldr (cdr xs) f (f init (car xs))))))",
-            "(define zip (lambda (x y) (cond (or (null? x) (null? y)) (list) (cons (list (car x) (car y)) (zip (cdr x) (cdr y))))))",
-            "(define reverse (lambda (l) (cond (null? l) l (append (list (car l)) (reverse (cdr l))))))",
-            "(define append (lambda (l1 l2) (cond (null? l2) l1 (cons (car l2) (append l1 (cdr l2))))))",
-            "(define or (lambda (x y) (cond x #t y)))",
+            "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
+            "(define range (lambda (start end) (if (> end start) (cons end (range start (sub1 end))) (list start))))",
+            "(define map2 (lambda (f l) (if (null? l) l (cons (f (car (cdr (car l))) (car (car l))) (map2 f (cdr l))))))",
+            "(define foldr (lambda (xs f init) (if (null? xs) init (foldr (cdr xs) f (f init (car xs))))))",
+            "(define zip (lambda (x y) (if (or (null? x) (null? y)) (list) (cons (list (car x) (car y)) (zip (cdr x) (cdr y))))))",
+            "(define reverse (lambda (l) (if (null? l) l (append (list (car l)) (reverse (cdr l))))))",
+            "(define append (lambda (l1 l2) (if (null? l2) l1 (cons (car l2) (append l1 (cdr l2))))))",
         ];
 
         for cmd in init_commands {
@@ -1899,8 +6066,8 @@ mod tests {
             check_lisp(
                 &mut state,
                 vec![
-                    "(define add (lambda (x y) (cond (zero? y) x (add (add1 x) (sub1 y)))))",
-                    "(define mult (lambda (x y) (cond (zero? y) 0 (add (mult x (sub1 y)) x))))",
+                    "(define add (lambda (x y) (if (zero? y) x (add (add1 x) (sub1 y)))))",
+                    "(define mult (lambda (x y) (if (zero? y) 0 (add (mult x (sub1 y)) x))))",
                     "(mult 10 100)",
                 ],
             )
@@ -1,136 +1,732 @@
+use std::error::Error;
+use std::fmt;
 use std::iter::Peekable;
-use std::str::Chars;
+use std::str::CharIndices;
 
+use super::integer;
 use super::{BuiltIn, LispExpr, LispFunc, LispMacro, LispValue, State};
+use num_bigint::{BigInt, Sign};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ParseError {
-    UnbalancedParens,
+    /// The input ended where an expression was expected
+    UnexpectedEof,
+    /// The parenthesis opened at the given position is never closed
+    UnclosedParen(Position),
+    /// Closing parenthesis at the given position without matching opening one
+    UnexpectedCloseParen(Position),
+    /// There is more input after a complete expression
+    TrailingInput(String, Position),
+    /// The token at the given position looks like a number but is not a
+    /// valid one
+    InvalidNumberLiteral(String, Position),
+    /// The token at the given position is not a valid name or literal
+    InvalidToken(String, Position),
+    /// The block comment opened at the given position is never closed
+    UnterminatedComment(Position),
+    /// The string literal starting at the given position is never closed
+    UnterminatedString(Position),
+    /// Invalid escape sequence in a string literal at the given position
+    InvalidEscape(String, Position),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::UnexpectedEof => write!(f, "unexpected end of input"),
+            ParseError::UnclosedParen(pos) => {
+                write!(f, "unclosed parenthesis opened at {}", pos)
+            }
+            ParseError::UnexpectedCloseParen(pos) => {
+                write!(f, "unexpected closing parenthesis at {}", pos)
+            }
+            ParseError::TrailingInput(ref text, pos) => {
+                write!(f, "unexpected input `{}` after expression at {}", text, pos)
+            }
+            ParseError::InvalidNumberLiteral(ref text, pos) => {
+                write!(f, "invalid number literal `{}` at {}", text, pos)
+            }
+            ParseError::InvalidToken(ref text, pos) => {
+                write!(f, "invalid token `{}` at {}", text, pos)
+            }
+            ParseError::UnterminatedComment(pos) => {
+                write!(f, "unterminated block comment opened at {}", pos)
+            }
+            ParseError::UnterminatedString(pos) => {
+                write!(f, "unterminated string literal starting at {}", pos)
+            }
+            ParseError::InvalidEscape(ref text, pos) => {
+                write!(f, "invalid escape sequence `{}` at {}", text, pos)
+            }
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// A location in the source text. Offset is in bytes, lines and
+/// columns start counting at one.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Position {
+            offset: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+}
+
+/// The part of the source text an expression was parsed from. The end
+/// position points just past the last character.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+/// Source locations of a parsed expression. It mirrors the shape of the
+/// `LispExpr` it was parsed with: calls have a child for every element of
+/// the call, all other expressions have no children.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SpanTree {
+    pub span: Span,
+    pub children: Vec<SpanTree>,
+}
+
+impl SpanTree {
+    fn leaf(span: Span) -> Self {
+        SpanTree {
+            span,
+            children: Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 enum Token {
-    Integer(u64),
+    Integer(BigInt),
+    Boolean(bool),
+    String(String),
+    Char(char),
     OpenParen,
     CloseParen,
+    /// The `'` shorthand for quote
+    Quote,
+    /// The `` ` `` shorthand for quasiquote
+    Quasiquote,
+    /// The `,` shorthand for unquote
+    Unquote,
+    /// The `,@` shorthand for unquote-splicing
+    UnquoteSplicing,
     // Either an operator or a variable
     OpVar(String),
+    /// Marks that the next datum should be skipped
+    DatumComment,
 }
 
 /// Token Iterator
 struct Tokens<'a> {
-    chars: Peekable<Chars<'a>>,
+    source: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    position: Position,
 }
 
 impl<'x> Tokens<'x> {
-    fn from_str(literal: &str) -> Tokens {
+    fn from_str(literal: &'x str) -> Tokens<'x> {
         Tokens {
-            chars: literal.chars().peekable(),
+            source: literal,
+            chars: literal.char_indices().peekable(),
+            position: Position::default(),
         }
     }
-}
 
-impl<'a> Iterator for Tokens<'a> {
-    type Item = Token;
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|&(_, c)| c)
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        fn is_function_char(c: &char) -> bool {
-            match *c {
-                '(' | ')' => false,
-                x if x.is_whitespace() => false,
-                _ => true,
+    /// Consumes the next character, keeping track of where we are.
+    fn bump(&mut self) -> Option<char> {
+        let (idx, c) = self.chars.next()?;
+
+        self.position.offset = idx + c.len_utf8();
+        if c == '\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else {
+            self.position.column += 1;
+        }
+
+        Some(c)
+    }
+
+    /// Reads characters until the next delimiter.
+    fn read_word(&mut self, first: char) -> String {
+        let mut buf = first.to_string();
+
+        while let Some(true) = self.peek().map(is_function_char) {
+            buf.push(self.bump().unwrap());
+        }
+
+        buf
+    }
+
+    fn skip_line_comment(&mut self) {
+        while let Some(c) = self.bump() {
+            if c == '\n' {
+                break;
             }
         }
+    }
 
-        fn parse_func(first: char, chars: &mut Peekable<Chars>) -> Token {
-            let mut buf = first.to_string();
+    /// Skips a block comment, taking nested block comments into account.
+    /// Expects the opening `#|` to be consumed already.
+    fn skip_block_comment(&mut self, start: Position) -> Result<(), ParseError> {
+        let mut depth = 1;
 
-            while let Some(true) = chars.peek().map(is_function_char) {
-                buf.push(chars.next().unwrap());
+        while depth > 0 {
+            match self.bump() {
+                Some('|') if self.peek() == Some('#') => {
+                    self.bump();
+                    depth -= 1;
+                }
+                Some('#') if self.peek() == Some('|') => {
+                    self.bump();
+                    depth += 1;
+                }
+                Some(_) => {}
+                None => return Err(ParseError::UnterminatedComment(start)),
             }
+        }
 
-            Token::OpVar(buf)
+        Ok(())
+    }
+
+    /// Reads a string literal, resolving escape sequences. Expects the
+    /// opening quote to be consumed already.
+    fn read_string(&mut self, start: Position) -> Result<String, ParseError> {
+        let mut buf = String::new();
+
+        loop {
+            let escape_start = self.position;
+
+            match self.bump() {
+                Some('"') => return Ok(buf),
+                Some('\\') => {
+                    let escaped = match self.bump() {
+                        Some('n') => Some('\n'),
+                        Some('t') => Some('\t'),
+                        Some('r') => Some('\r'),
+                        Some('0') => Some('\0'),
+                        Some('\\') => Some('\\'),
+                        Some('"') => Some('"'),
+                        Some('u') if self.peek() == Some('{') => self.read_unicode_escape(),
+                        Some(_) => None,
+                        None => return Err(ParseError::UnterminatedString(start)),
+                    };
+
+                    match escaped {
+                        Some(c) => buf.push(c),
+                        None => {
+                            let text = &self.source[escape_start.offset..self.position.offset];
+                            return Err(ParseError::InvalidEscape(text.into(), escape_start));
+                        }
+                    }
+                }
+                Some(c) => buf.push(c),
+                None => return Err(ParseError::UnterminatedString(start)),
+            }
         }
+    }
 
-        fn parse_integer(first: char, chars: &mut Peekable<Chars>) -> Token {
-            let mut num = first.to_digit(10).unwrap();
+    /// Reads the `{...}` part of a `\u{...}` escape, which contains up to six
+    /// hexadecimal digits.
+    fn read_unicode_escape(&mut self) -> Option<char> {
+        self.bump();
+        let mut digits = String::new();
 
-            while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
-                num = num.wrapping_mul(10).wrapping_add(d);
-                chars.next();
+        loop {
+            match self.bump()? {
+                '}' => break,
+                c if c.is_ascii_hexdigit() && digits.len() < 6 => digits.push(c),
+                _ => return None,
             }
+        }
+
+        u32::from_str_radix(&digits, 16)
+            .ok()
+            .and_then(::std::char::from_u32)
+    }
+
+    /// Reads a character literal. Expects the `#\\` to be consumed already.
+    /// The first character is always part of the literal, even when it is a
+    /// delimiter, so that `#\\(` and `#\\ ` can be written.
+    fn read_char(&mut self, start: Position) -> Result<char, ParseError> {
+        let first = match self.bump() {
+            Some(c) => c,
+            None => return Err(ParseError::UnexpectedEof),
+        };
+        let word = self.read_word(first);
+        let mut chars = word.chars();
 
-            Token::Integer(u64::from(num))
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Ok(c);
         }
 
-        while let Some(c) = self.chars.next() {
-            return match c {
-                '(' => Some(Token::OpenParen),
-                ')' => Some(Token::CloseParen),
+        let named = match &word[..] {
+            "space" => Some(' '),
+            "newline" => Some('\n'),
+            "tab" => Some('\t'),
+            "return" => Some('\r'),
+            "nul" | "null" => Some('\0'),
+            "alarm" => Some('\u{7}'),
+            "backspace" => Some('\u{8}'),
+            "escape" => Some('\u{1b}'),
+            "delete" => Some('\u{7f}'),
+            _ if word.starts_with('x') && word[1..].chars().all(|c| c.is_ascii_hexdigit()) => {
+                u32::from_str_radix(&word[1..], 16)
+                    .ok()
+                    .and_then(::std::char::from_u32)
+            }
+            _ => None,
+        };
+
+        named.ok_or_else(|| ParseError::InvalidToken(format!("#\\{}", word), start))
+    }
+
+    /// Returns the remainder of the input, starting at the given position.
+    fn rest_from(&self, pos: Position) -> &'x str {
+        self.source[pos.offset..].trim_end()
+    }
+}
+
+fn is_function_char(c: char) -> bool {
+    match c {
+        '(' | ')' | ';' | '"' => false,
+        x if x.is_whitespace() => false,
+        _ => true,
+    }
+}
+
+/// Returns the radix for literals starting with `#x`, `#b` or `#o`.
+fn radix_prefix(word: &str) -> Option<u32> {
+    match word.get(..2) {
+        Some("#x") => Some(16),
+        Some("#b") => Some(2),
+        Some("#o") => Some(8),
+        _ => None,
+    }
+}
+
+/// Parses the digits of integer literal `word` in the given radix. The digits
+/// may start with a sign and may be separated by underscores.
+fn parse_integer(
+    word: &str,
+    digits: &str,
+    radix: u32,
+    pos: Position,
+) -> Result<BigInt, ParseError> {
+    let invalid = || ParseError::InvalidNumberLiteral(word.into(), pos);
+    let (sign, digits) = match digits.chars().next() {
+        Some('-') => (Sign::Minus, &digits[1..]),
+        Some('+') => (Sign::Plus, &digits[1..]),
+        _ => (Sign::Plus, digits),
+    };
+
+    // Separators must be between digits
+    if digits.is_empty()
+        || digits.starts_with('_')
+        || digits.ends_with('_')
+        || digits.contains("__")
+    {
+        return Err(invalid());
+    }
+
+    let digit_values = digits
+        .chars()
+        .filter(|&c| c != '_')
+        .map(|c| c.to_digit(radix).map(|d| d as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(invalid)?;
+
+    BigInt::from_radix_be(sign, &digit_values, radix).ok_or_else(invalid)
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Result<(Token, Span), ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let start = self.position;
+            let token = match self.bump()? {
+                '(' => Token::OpenParen,
+                ')' => Token::CloseParen,
+                '\'' => Token::Quote,
+                '`' => Token::Quasiquote,
+                ',' if self.peek() == Some('@') => {
+                    self.bump();
+                    Token::UnquoteSplicing
+                }
+                ',' => Token::Unquote,
                 x if x.is_whitespace() => continue,
-                x @ '0'..='9' => Some(parse_integer(x, &mut self.chars)),
-                x => Some(parse_func(x, &mut self.chars)),
+                ';' => {
+                    self.skip_line_comment();
+                    continue;
+                }
+                '#' if self.peek() == Some('|') => {
+                    self.bump();
+                    if let Err(e) = self.skip_block_comment(start) {
+                        return Some(Err(e));
+                    }
+                    continue;
+                }
+                '"' => match self.read_string(start) {
+                    Ok(s) => Token::String(s),
+                    Err(e) => return Some(Err(e)),
+                },
+                '#' if self.peek() == Some(';') => {
+                    self.bump();
+                    Token::DatumComment
+                }
+                // A sign is only part of an integer literal when it is directly
+                // followed by a digit. Otherwise it's a function name.
+                x @ '0'..='9' | x @ '-' | x @ '+'
+                    if x.is_ascii_digit() || self.peek().is_some_and(|c| c.is_ascii_digit()) =>
+                {
+                    let word = self.read_word(x);
+
+                    match parse_integer(&word, &word, 10, start) {
+                        Ok(i) => Token::Integer(i),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                '#' if self.peek() == Some('\\') => {
+                    self.bump();
+                    match self.read_char(start) {
+                        Ok(c) => Token::Char(c),
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+                '#' => {
+                    let word = self.read_word('#');
+
+                    match &word[..] {
+                        "#t" => Token::Boolean(true),
+                        "#f" => Token::Boolean(false),
+                        "#!optional" => Token::OpVar(word),
+                        _ => match radix_prefix(&word) {
+                            Some(radix) => match parse_integer(&word, &word[2..], radix, start) {
+                                Ok(i) => Token::Integer(i),
+                                Err(e) => return Some(Err(e)),
+                            },
+                            None => return Some(Err(ParseError::InvalidToken(word, start))),
+                        },
+                    }
+                }
+                x => Token::OpVar(self.read_word(x)),
+            };
+            let span = Span {
+                start,
+                end: self.position,
             };
+
+            return Some(Ok((token, span)));
         }
+    }
+}
 
-        None
+/// How names are read. In quoted data, every name is a symbol, even
+/// when it is also the name of a macro or built-in function. Quasiquoted
+/// data is the same, except that unquoted expressions are read as code.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Mode {
+    Code,
+    Quoted,
+    Quasiquoted,
+}
+
+impl Mode {
+    /// Returns the mode in which the arguments of a form starting with
+    /// `head` are read.
+    fn arguments(self, head: &LispExpr) -> Mode {
+        match *head {
+            LispExpr::Macro(LispMacro::Quote) => Mode::Quoted,
+            LispExpr::Macro(LispMacro::Quasiquote) => Mode::Quasiquoted,
+            LispExpr::Macro(LispMacro::Unquote) | LispExpr::Macro(LispMacro::UnquoteSplicing) => {
+                Mode::Code
+            }
+            _ => self,
+        }
+    }
+}
+
+/// Returns the next token that is not part of a datum comment.
+fn next_token(tokens: &mut Tokens, state: &mut State) -> Option<Result<(Token, Span), ParseError>> {
+    loop {
+        match tokens.next() {
+            Some(Ok((Token::DatumComment, _))) => {
+                let skip_result = match next_token(tokens, state) {
+                    Some(Ok((token, span))) => parse_expr(token, span, tokens, state, Mode::Quoted),
+                    Some(Err(e)) => Err(e),
+                    None => Err(ParseError::UnexpectedEof),
+                };
+
+                if let Err(e) = skip_result {
+                    return Some(Err(e));
+                }
+            }
+            next => return next,
+        }
     }
 }
 
 pub fn parse_lisp_string(lit: &str, state: &mut State) -> Result<LispExpr, ParseError> {
+    parse_lisp_string_with_spans(lit, state).map(|(expr, _spans)| expr)
+}
+
+/// Parses a single expression and also returns where in the source text
+/// each of its subexpressions was found.
+pub fn parse_lisp_string_with_spans(
+    lit: &str,
+    state: &mut State,
+) -> Result<(LispExpr, SpanTree), ParseError> {
     let mut tokens = Tokens::from_str(lit);
-    let first_token = if let Some(t) = tokens.next() {
-        t
-    } else {
-        return Err(ParseError::UnbalancedParens);
+    let (first_token, first_span) = match next_token(&mut tokens, state) {
+        Some(t) => t?,
+        None => return Err(ParseError::UnexpectedEof),
     };
-    let res = parse_expr(first_token, &mut tokens, state)?;
-    if !tokens.next().is_none() {
-        return Err(ParseError::UnbalancedParens);
+    let res = parse_expr(first_token, first_span, &mut tokens, state, Mode::Code)?;
+    match next_token(&mut tokens, state) {
+        None => Ok(res),
+        Some(Err(e)) => Err(e),
+        Some(Ok((Token::CloseParen, span))) => Err(ParseError::UnexpectedCloseParen(span.start)),
+        Some(Ok((_, span))) => Err(ParseError::TrailingInput(
+            tokens.rest_from(span.start).into(),
+            span.start,
+        )),
     }
-    Ok(res)
+}
+
+pub fn parse_program(lit: &str, state: &mut State) -> Result<Vec<LispExpr>, ParseError> {
+    parse_program_with_spans(lit, state).map(|(exprs, _spans)| exprs)
+}
+
+/// Parses all top-level expressions in the input, for example a complete
+/// source file. Every expression comes with its own span tree.
+pub fn parse_program_with_spans(
+    lit: &str,
+    state: &mut State,
+) -> Result<(Vec<LispExpr>, Vec<SpanTree>), ParseError> {
+    let mut tokens = Tokens::from_str(lit);
+    let mut exprs = Vec::new();
+    let mut spans = Vec::new();
+
+    while let Some(next) = next_token(&mut tokens, state) {
+        let (token, span) = next?;
+        let (expr, span_tree) = parse_expr(token, span, &mut tokens, state, Mode::Code)?;
+        exprs.push(expr);
+        spans.push(span_tree);
+    }
+
+    Ok((exprs, spans))
 }
 
 // Tries to parse an iterator of tokens into a list of expressions.
 // Expects the opening parenthesis to be stripped.
-fn parse_call(tokens: &mut Tokens, state: &mut State) -> Result<Vec<LispExpr>, ParseError> {
+fn parse_call(
+    open_span: Span,
+    tokens: &mut Tokens,
+    state: &mut State,
+    mut mode: Mode,
+) -> Result<(Vec<LispExpr>, SpanTree), ParseError> {
     let mut stack = Vec::new();
+    let mut spans = Vec::new();
+
+    while let Some(next) = next_token(tokens, state) {
+        let (token, span) = next?;
 
-    while let Some(token) = tokens.next() {
         if let Token::CloseParen = token {
-            return Ok(stack);
+            let span_tree = SpanTree {
+                span: Span {
+                    start: open_span.start,
+                    end: span.end,
+                },
+                children: spans,
+            };
+            return Ok((stack, span_tree));
         } else {
-            let next_expr = parse_expr(token, tokens, state)?;
+            let (next_expr, next_spans) = match token {
+                // Long form unquotes inside a quasiquote template
+                Token::OpVar(ref o) if stack.is_empty() && mode == Mode::Quasiquoted => {
+                    match unquote_name(o) {
+                        Some(mac) => (LispExpr::Macro(mac), SpanTree::leaf(span)),
+                        None => parse_expr(token, span, tokens, state, mode)?,
+                    }
+                }
+                _ => parse_expr(token, span, tokens, state, mode)?,
+            };
+
+            // The arguments of quoting forms are data
+            if stack.is_empty() {
+                mode = mode.arguments(&next_expr);
+            }
+
             stack.push(next_expr);
+            spans.push(next_spans);
         }
     }
 
-    Err(ParseError::UnbalancedParens)
+    Err(ParseError::UnclosedParen(open_span.start))
 }
 
 fn parse_expr(
     token: Token,
+    span: Span,
+    tokens: &mut Tokens,
+    state: &mut State,
+    mode: Mode,
+) -> Result<(LispExpr, SpanTree), ParseError> {
+    let expr = match token {
+        Token::OpenParen => {
+            let (list, span_tree) = parse_call(span, tokens, state, mode)?;
+            return Ok((LispExpr::Call(list), span_tree));
+        }
+        // 'x is read as (quote x), `x as (quasiquote x), ,x as (unquote x)
+        // and ,@x as (unquote-splicing x)
+        Token::Quote => return parse_shorthand(LispMacro::Quote, span, tokens, state, mode),
+        Token::Quasiquote => {
+            return parse_shorthand(LispMacro::Quasiquote, span, tokens, state, mode)
+        }
+        Token::Unquote => return parse_shorthand(LispMacro::Unquote, span, tokens, state, mode),
+        Token::UnquoteSplicing => {
+            return parse_shorthand(LispMacro::UnquoteSplicing, span, tokens, state, mode)
+        }
+        Token::CloseParen => return Err(ParseError::UnexpectedCloseParen(span.start)),
+        // Datum comments are skipped by next_token
+        Token::DatumComment => unreachable!(),
+        Token::Integer(l) => LispExpr::Value(integer::normalize(l)),
+        Token::Boolean(b) => LispExpr::Value(LispValue::Boolean(b)),
+        Token::String(s) => LispExpr::Value(LispValue::String(s)),
+        Token::Char(c) => LispExpr::Value(LispValue::Char(c)),
+        Token::OpVar(o) => match special_name(&o, mode) {
+            Some(expr) => expr,
+            None => LispExpr::OpVar(state.intern(o)),
+        },
+    };
+
+    Ok((expr, SpanTree::leaf(span)))
+}
+
+/// Returns the special form or built-in function that a name refers to.
+/// In quoted data, names never refer to anything.
+fn special_name(name: &str, mode: Mode) -> Option<LispExpr> {
+    if mode != Mode::Code {
+        None
+    } else if let Some(mac) = LispMacro::from_str(name) {
+        Some(LispExpr::Macro(mac))
+    } else {
+        BuiltIn::from_str(name).map(|f| LispExpr::Value(LispValue::Function(LispFunc::BuiltIn(f))))
+    }
+}
+
+fn unquote_name(name: &str) -> Option<LispMacro> {
+    match LispMacro::from_str(name) {
+        Some(mac @ LispMacro::Unquote) | Some(mac @ LispMacro::UnquoteSplicing) => Some(mac),
+        _ => None,
+    }
+}
+
+/// Reads a value as code, in the same way that the parser reads its
+/// printed form. This turns the output of macros back into expressions.
+pub fn read_datum(value: LispValue, state: &State) -> LispExpr {
+    datum_to_expr(value, state, Mode::Code)
+}
+
+fn datum_to_expr(value: LispValue, state: &State, mut mode: Mode) -> LispExpr {
+    match value {
+        LispValue::Symbol(n) => {
+            special_name(state.resolve_intern(n), mode).unwrap_or(LispExpr::OpVar(n))
+        }
+        LispValue::List(values) => {
+            let mut exprs = Vec::with_capacity(values.len());
+
+            for value in values {
+                let expr = match value {
+                    LispValue::Symbol(n) if exprs.is_empty() && mode == Mode::Quasiquoted => {
+                        match unquote_name(state.resolve_intern(n)) {
+                            Some(mac) => LispExpr::Macro(mac),
+                            None => LispExpr::OpVar(n),
+                        }
+                    }
+                    _ => datum_to_expr(value, state, mode),
+                };
+
+                if exprs.is_empty() {
+                    mode = mode.arguments(&expr);
+                }
+
+                exprs.push(expr);
+            }
+
+            LispExpr::Call(exprs)
+        }
+        v => LispExpr::Value(v),
+    }
+}
+
+/// Parses the datum following a quoting shorthand and wraps it in the
+/// corresponding form.
+fn parse_shorthand(
+    mac: LispMacro,
+    span: Span,
     tokens: &mut Tokens,
     state: &mut State,
-) -> Result<LispExpr, ParseError> {
-    Ok(match token {
-        Token::OpenParen => LispExpr::Call(parse_call(tokens, state)?),
-        Token::CloseParen => return Err(ParseError::UnbalancedParens),
-        Token::Integer(l) => LispExpr::Value(LispValue::Integer(l)),
-        Token::OpVar(o) => {
-            if let Some(mac) = LispMacro::from_str(&o) {
-                LispExpr::Macro(mac)
-            } else if let Some(built_in) = BuiltIn::from_str(&o) {
-                LispExpr::Value(LispValue::Function(LispFunc::BuiltIn(built_in)))
-            } else if o == "#t" {
-                LispExpr::Value(LispValue::Boolean(true))
-            } else if o == "#f" {
-                LispExpr::Value(LispValue::Boolean(false))
-            } else {
-                LispExpr::OpVar(state.intern(o))
-            }
-        }
-    })
+    mode: Mode,
+) -> Result<(LispExpr, SpanTree), ParseError> {
+    // Inside quoted data, the shorthand reads as a symbol. Quasiquote
+    // templates only have a single level, so nested quasiquotes are
+    // plain data.
+    let (head, datum_mode) = match (mode, mac) {
+        (Mode::Code, _)
+        | (Mode::Quasiquoted, LispMacro::Unquote)
+        | (Mode::Quasiquoted, LispMacro::UnquoteSplicing) => {
+            let head = LispExpr::Macro(mac);
+            let datum_mode = mode.arguments(&head);
+            (head, datum_mode)
+        }
+        (Mode::Quasiquoted, LispMacro::Quasiquote) => {
+            (LispExpr::OpVar(state.intern(mac.to_string())), Mode::Quoted)
+        }
+        _ => (LispExpr::OpVar(state.intern(mac.to_string())), mode),
+    };
+    let (datum, datum_spans) = match next_token(tokens, state) {
+        Some(next) => {
+            let (token, datum_span) = next?;
+            parse_expr(token, datum_span, tokens, state, datum_mode)?
+        }
+        None => return Err(ParseError::UnexpectedEof),
+    };
+    let span_tree = SpanTree {
+        span: Span {
+            start: span.start,
+            end: datum_spans.span.end,
+        },
+        children: vec![SpanTree::leaf(span), datum_spans],
+    };
+
+    Ok((LispExpr::Call(vec![head, datum]), span_tree))
 }
 
 #[cfg(test)]
@@ -160,7 +756,11 @@ mod tests {
     #[test]
     fn parse_double_closing_parens() {
         let lit = "(list))";
-        let expected = Err(ParseError::UnbalancedParens);
+        let expected = Err(ParseError::UnexpectedCloseParen(Position {
+            offset: 6,
+            line: 1,
+            column: 7,
+        }));
 
         let result = parse_lisp_string(lit, &mut State::default());
         assert_eq!(expected, result);
@@ -177,6 +777,20 @@ mod tests {
         assert_eq!(expected, result);
     }
 
+    #[test]
+    fn parse_negative_integer() {
+        let mut state = State::default();
+        let lit = "(- -5 -x +)";
+        let expected = Ok(LispExpr::Call(vec![
+            LispExpr::Value(LispValue::Function(LispFunc::BuiltIn(BuiltIn::Sub))),
+            LispExpr::Value(LispValue::Integer(-5)),
+            LispExpr::OpVar(state.intern("-x")),
+            LispExpr::Value(LispValue::Function(LispFunc::BuiltIn(BuiltIn::Add))),
+        ]));
+
+        assert_eq!(expected, parse_lisp_string(lit, &mut state));
+    }
+
     #[test]
     fn parse_lisp_string_ok() {
         let lit = "(lambda (list 1 (cond 2 3) 9))";
@@ -202,7 +816,7 @@ mod tests {
     #[test]
     fn parse_lisp_string_unbalanced() {
         let lit = "(+ 1 (- 10 5)";
-        let expected = Err(ParseError::UnbalancedParens);
+        let expected = Err(ParseError::UnclosedParen(Position::default()));
         let result = parse_lisp_string(lit, &mut State::default());
 
         assert_eq!(expected, result);
@@ -211,9 +825,689 @@ mod tests {
     #[test]
     fn parse_lisp_string_overbalanced() {
         let lit = "())";
-        let expected = Err(ParseError::UnbalancedParens);
+        let expected = Err(ParseError::UnexpectedCloseParen(Position {
+            offset: 2,
+            line: 1,
+            column: 3,
+        }));
+        let result = parse_lisp_string(lit, &mut State::default());
+
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn parse_unclosed_paren_location() {
+        let lit = "(define f\n  (lambda (x)\n    (add1 x))";
+        let expected = Err(ParseError::UnclosedParen(Position::default()));
+        let result = parse_lisp_string(lit, &mut State::default());
+
+        assert_eq!(expected, result);
+
+        let lit = "(list 1\n      (list 2)\n      (list 3 4)";
+        let expected = Err(ParseError::UnclosedParen(Position::default()));
+        let result = parse_lisp_string(lit, &mut State::default());
+
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn parse_stray_close_paren_location() {
+        let lit = "(list 1\n  2))\n";
+        let expected = Err(ParseError::UnexpectedCloseParen(Position {
+            offset: 12,
+            line: 2,
+            column: 5,
+        }));
+        let result = parse_lisp_string(lit, &mut State::default());
+
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn parse_spans() {
+        let lit = "(add1\n  (λ 10))";
+        let (_expr, spans) = parse_lisp_string_with_spans(lit, &mut State::default()).unwrap();
+
+        assert_eq!(Position::default(), spans.span.start);
+        assert_eq!(
+            Position {
+                offset: 16,
+                line: 2,
+                column: 10,
+            },
+            spans.span.end
+        );
+        assert_eq!(2, spans.children.len());
+        assert_eq!(
+            Span {
+                start: Position {
+                    offset: 1,
+                    line: 1,
+                    column: 2,
+                },
+                end: Position {
+                    offset: 5,
+                    line: 1,
+                    column: 6,
+                },
+            },
+            spans.children[0].span
+        );
+
+        let inner = &spans.children[1];
+        assert_eq!(
+            Position {
+                offset: 8,
+                line: 2,
+                column: 3,
+            },
+            inner.span.start
+        );
+        // The lambda character is two bytes wide, but only one column.
+        assert_eq!(
+            Span {
+                start: Position {
+                    offset: 12,
+                    line: 2,
+                    column: 6,
+                },
+                end: Position {
+                    offset: 14,
+                    line: 2,
+                    column: 8,
+                },
+            },
+            inner.children[1].span
+        );
+    }
+
+    #[test]
+    fn parse_empty_input() {
+        let expected = Err(ParseError::UnexpectedEof);
+
+        assert_eq!(expected, parse_lisp_string("", &mut State::default()));
+        assert_eq!(expected, parse_lisp_string(" \n\t", &mut State::default()));
+    }
+
+    #[test]
+    fn parse_trailing_input() {
+        let lit = "(add1 1) (add1 2)\n";
+        let expected = Err(ParseError::TrailingInput(
+            "(add1 2)".into(),
+            Position {
+                offset: 9,
+                line: 1,
+                column: 10,
+            },
+        ));
+        let result = parse_lisp_string(lit, &mut State::default());
+
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn parse_invalid_number_literal() {
+        let lit = "(list 12ab)";
+        let expected = Err(ParseError::InvalidNumberLiteral(
+            "12ab".into(),
+            Position {
+                offset: 6,
+                line: 1,
+                column: 7,
+            },
+        ));
+        let result = parse_lisp_string(lit, &mut State::default());
+
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn parse_invalid_token() {
+        let lit = "(list #true)";
+        let expected = Err(ParseError::InvalidToken(
+            "#true".into(),
+            Position {
+                offset: 6,
+                line: 1,
+                column: 7,
+            },
+        ));
         let result = parse_lisp_string(lit, &mut State::default());
 
         assert_eq!(expected, result);
     }
+
+    #[test]
+    fn display_parse_errors() {
+        let pos = Position {
+            offset: 10,
+            line: 2,
+            column: 4,
+        };
+
+        assert_eq!(
+            "unexpected end of input",
+            ParseError::UnexpectedEof.to_string()
+        );
+        assert_eq!(
+            "unclosed parenthesis opened at line 2, column 4",
+            ParseError::UnclosedParen(pos).to_string()
+        );
+        assert_eq!(
+            "unexpected closing parenthesis at line 2, column 4",
+            ParseError::UnexpectedCloseParen(pos).to_string()
+        );
+        assert_eq!(
+            "unexpected input `5` after expression at line 2, column 4",
+            ParseError::TrailingInput("5".into(), pos).to_string()
+        );
+        assert_eq!(
+            "invalid number literal `1x` at line 2, column 4",
+            ParseError::InvalidNumberLiteral("1x".into(), pos).to_string()
+        );
+        assert_eq!(
+            "invalid token `#y` at line 2, column 4",
+            ParseError::InvalidToken("#y".into(), pos).to_string()
+        );
+    }
+
+    #[test]
+    fn parse_program_multiple_forms() {
+        let lit = "(define x 5)\n(add1 x)\n\n#t";
+        let mut state = State::default();
+        let (exprs, spans) = parse_program_with_spans(lit, &mut state).unwrap();
+        let x = state.intern("x");
+
+        assert_eq!(
+            vec![
+                LispExpr::Call(vec![
+                    LispExpr::Macro(LispMacro::Define),
+                    LispExpr::OpVar(x),
+                    LispExpr::Value(LispValue::Integer(5)),
+                ]),
+                LispExpr::Call(vec![
+                    LispExpr::Value(LispValue::Function(LispFunc::BuiltIn(BuiltIn::AddOne))),
+                    LispExpr::OpVar(x),
+                ]),
+                LispExpr::Value(LispValue::Boolean(true)),
+            ],
+            exprs
+        );
+        assert_eq!(3, spans.len());
+        assert_eq!(4, spans[2].span.start.line);
+    }
+
+    #[test]
+    fn parse_program_empty() {
+        assert_eq!(Ok(vec![]), parse_program("  \n", &mut State::default()));
+    }
+
+    #[test]
+    fn parse_program_unbalanced() {
+        let lit = "(add1 1)\n(add1 2))";
+        let expected = Err(ParseError::UnexpectedCloseParen(Position {
+            offset: 17,
+            line: 2,
+            column: 9,
+        }));
+
+        assert_eq!(expected, parse_program(lit, &mut State::default()));
+    }
+
+    #[test]
+    fn parse_line_comments() {
+        let lit = "; leading comment\n(list 1 ; one\n 2);trailing";
+        let expected = Ok(LispExpr::Call(vec![
+            LispExpr::Value(LispValue::Function(LispFunc::BuiltIn(BuiltIn::List))),
+            LispExpr::Value(LispValue::Integer(1)),
+            LispExpr::Value(LispValue::Integer(2)),
+        ]));
+
+        assert_eq!(expected, parse_lisp_string(lit, &mut State::default()));
+    }
+
+    #[test]
+    fn parse_comment_delimits_symbol() {
+        let mut state = State::default();
+        let result = parse_lisp_string("x;comment", &mut state);
+        let x = state.intern("x");
+
+        assert_eq!(Ok(LispExpr::OpVar(x)), result);
+    }
+
+    #[test]
+    fn parse_nested_block_comments() {
+        let lit = "(list #| outer #| inner |# still outer |# 1 #||# 2)";
+        let expected = Ok(LispExpr::Call(vec![
+            LispExpr::Value(LispValue::Function(LispFunc::BuiltIn(BuiltIn::List))),
+            LispExpr::Value(LispValue::Integer(1)),
+            LispExpr::Value(LispValue::Integer(2)),
+        ]));
+
+        assert_eq!(expected, parse_lisp_string(lit, &mut State::default()));
+    }
+
+    #[test]
+    fn parse_unterminated_block_comment() {
+        let lit = "(list 1)\n #| #| |#";
+        let expected = Err(ParseError::UnterminatedComment(Position {
+            offset: 10,
+            line: 2,
+            column: 2,
+        }));
+
+        assert_eq!(expected, parse_lisp_string(lit, &mut State::default()));
+    }
+
+    #[test]
+    fn parse_datum_comments() {
+        let lit = "#;(define x 1) (list #;(add1 2) 3 #; #; 4 5 6 #;7)";
+        let expected = Ok(LispExpr::Call(vec![
+            LispExpr::Value(LispValue::Function(LispFunc::BuiltIn(BuiltIn::List))),
+            LispExpr::Value(LispValue::Integer(3)),
+            LispExpr::Value(LispValue::Integer(6)),
+        ]));
+
+        assert_eq!(expected, parse_lisp_string(lit, &mut State::default()));
+    }
+
+    #[test]
+    fn parse_datum_comment_without_datum() {
+        assert_eq!(
+            Err(ParseError::UnexpectedEof),
+            parse_lisp_string("1 #;", &mut State::default())
+        );
+        assert_eq!(
+            Err(ParseError::UnexpectedCloseParen(Position {
+                offset: 10,
+                line: 1,
+                column: 11,
+            })),
+            parse_lisp_string("(list 1 #;)", &mut State::default())
+        );
+    }
+
+    #[test]
+    fn parse_large_integer() {
+        let lit = "(list 4294967296 9223372036854775807 -9223372036854775808)";
+        let expected = Ok(LispExpr::Call(vec![
+            LispExpr::Value(LispValue::Function(LispFunc::BuiltIn(BuiltIn::List))),
+            LispExpr::Value(LispValue::Integer(4_294_967_296)),
+            LispExpr::Value(LispValue::Integer(i64::MAX)),
+            LispExpr::Value(LispValue::Integer(i64::MIN)),
+        ]));
+
+        assert_eq!(expected, parse_lisp_string(lit, &mut State::default()));
+    }
+
+    #[test]
+    fn parse_string_literal() {
+        let cases = [
+            (r#""hello""#, "hello"),
+            (r#""""#, ""),
+            (r#""a\"b\\c""#, "a\"b\\c"),
+            (r#""\n\t\r\0""#, "\n\t\r\0"),
+            (r#""\u{3bb}\u{1F600}""#, "\u{3bb}\u{1F600}"),
+            ("\"two\nlines\"", "two\nlines"),
+            (
+                r#""(not a list) ; or a comment""#,
+                "(not a list) ; or a comment",
+            ),
+        ];
+
+        for &(lit, expected) in &cases {
+            assert_eq!(
+                Ok(LispExpr::Value(LispValue::String(expected.into()))),
+                parse_lisp_string(lit, &mut State::default())
+            );
+        }
+    }
+
+    #[test]
+    fn parse_string_delimits_words() {
+        let mut state = State::default();
+        let expected = Ok(LispExpr::Call(vec![
+            LispExpr::OpVar(state.intern("f")),
+            LispExpr::Value(LispValue::String("a".into())),
+            LispExpr::Value(LispValue::String("b".into())),
+        ]));
+
+        assert_eq!(expected, parse_lisp_string(r#"(f"a""b")"#, &mut state));
+    }
+
+    #[test]
+    fn parse_unterminated_string() {
+        assert_eq!(
+            Err(ParseError::UnterminatedString(Position {
+                offset: 3,
+                line: 1,
+                column: 4,
+            })),
+            parse_lisp_string(r#"(f "abc)"#, &mut State::default())
+        );
+        assert_eq!(
+            Err(ParseError::UnterminatedString(Position::default())),
+            parse_lisp_string(r#""abc\"#, &mut State::default())
+        );
+    }
+
+    #[test]
+    fn parse_invalid_escape() {
+        let cases = [
+            (r#""ab\q""#, r#"\q"#),
+            (r#""ab\u{110000}""#, r#"\u{110000}"#),
+            (r#""ab\u{d800}""#, r#"\u{d800}"#),
+            (r#""ab\u{xyz}""#, r#"\u{x"#),
+            (r#""ab\u41""#, r#"\u"#),
+        ];
+
+        for &(lit, text) in &cases {
+            assert_eq!(
+                Err(ParseError::InvalidEscape(
+                    text.into(),
+                    Position {
+                        offset: 3,
+                        line: 1,
+                        column: 4,
+                    }
+                )),
+                parse_lisp_string(lit, &mut State::default())
+            );
+        }
+    }
+
+    #[test]
+    fn parse_position_after_multiline_string() {
+        assert_eq!(
+            Err(ParseError::InvalidToken(
+                "#y".into(),
+                Position {
+                    offset: 7,
+                    line: 2,
+                    column: 5,
+                }
+            )),
+            parse_program("\"a\nbc\" #y", &mut State::default())
+        );
+    }
+
+    #[test]
+    fn parse_char_literal() {
+        let cases = [
+            (r"#\a", 'a'),
+            (r"#\A", 'A'),
+            (r"#\space", ' '),
+            (r"#\newline", '\n'),
+            (r"#\tab", '\t'),
+            (r"#\nul", '\0'),
+            (r"#\x41", 'A'),
+            (r"#\x3bb", 'λ'),
+            (r"#\x", 'x'),
+            (r"#\λ", 'λ'),
+            (r"#\(", '('),
+            (r"#\;", ';'),
+            (r"#\ ", ' '),
+        ];
+
+        for &(lit, expected) in &cases {
+            assert_eq!(
+                Ok(LispExpr::Value(LispValue::Char(expected))),
+                parse_lisp_string(lit, &mut State::default())
+            );
+        }
+    }
+
+    #[test]
+    fn parse_char_in_call() {
+        let expected = Ok(LispExpr::Call(vec![
+            LispExpr::Value(LispValue::Function(LispFunc::BuiltIn(BuiltIn::List))),
+            LispExpr::Value(LispValue::Char(')')),
+            LispExpr::Value(LispValue::Char('a')),
+        ]));
+
+        assert_eq!(
+            expected,
+            parse_lisp_string(r"(list #\) #\a)", &mut State::default())
+        );
+    }
+
+    #[test]
+    fn parse_invalid_char_literal() {
+        for &lit in &[r"#\spaces", r"#\xd800", r"#\x+41", r"#\ab"] {
+            assert_eq!(
+                Err(ParseError::InvalidToken(lit.into(), Position::default())),
+                parse_lisp_string(lit, &mut State::default())
+            );
+        }
+        assert_eq!(
+            Err(ParseError::UnexpectedEof),
+            parse_lisp_string(r"#\", &mut State::default())
+        );
+    }
+
+    #[test]
+    fn parse_quote_shorthand() {
+        let mut state = State::default();
+        let expected = Ok(LispExpr::Call(vec![
+            LispExpr::Macro(LispMacro::Quote),
+            LispExpr::OpVar(state.intern("x")),
+        ]));
+
+        assert_eq!(expected, parse_lisp_string("'x", &mut state));
+        assert_eq!(expected, parse_lisp_string("(quote x)", &mut state));
+    }
+
+    #[test]
+    fn parse_quoted_names_are_symbols() {
+        let mut state = State::default();
+        let expected = Ok(LispExpr::Call(vec![
+            LispExpr::Macro(LispMacro::Quote),
+            LispExpr::Call(vec![
+                LispExpr::OpVar(state.intern("lambda")),
+                LispExpr::Call(vec![LispExpr::OpVar(state.intern("add1"))]),
+                LispExpr::Value(LispValue::Integer(1)),
+                LispExpr::Call(vec![
+                    LispExpr::OpVar(state.intern("quote")),
+                    LispExpr::OpVar(state.intern("y")),
+                ]),
+            ]),
+        ]));
+
+        assert_eq!(
+            expected,
+            parse_lisp_string("'(lambda (add1) 1 'y)", &mut state)
+        );
+        assert_eq!(
+            expected,
+            parse_lisp_string("(quote (lambda (add1) 1 (quote y)))", &mut state)
+        );
+    }
+
+    #[test]
+    fn parse_quote_inside_name() {
+        let mut state = State::default();
+        let expected = Ok(LispExpr::Call(vec![
+            LispExpr::OpVar(state.intern("sort'")),
+            LispExpr::Call(vec![
+                LispExpr::Macro(LispMacro::Quote),
+                LispExpr::OpVar(state.intern("a")),
+            ]),
+        ]));
+
+        assert_eq!(expected, parse_lisp_string("(sort' 'a)", &mut state));
+    }
+
+    #[test]
+    fn parse_quasiquote_shorthand() {
+        let mut state = State::default();
+        let expected = Ok(LispExpr::Call(vec![
+            LispExpr::Macro(LispMacro::Quasiquote),
+            LispExpr::Call(vec![
+                LispExpr::OpVar(state.intern("car")),
+                LispExpr::Call(vec![
+                    LispExpr::Macro(LispMacro::Unquote),
+                    LispExpr::Value(LispValue::Function(LispFunc::BuiltIn(BuiltIn::Car))),
+                ]),
+                LispExpr::Call(vec![
+                    LispExpr::Macro(LispMacro::UnquoteSplicing),
+                    LispExpr::OpVar(state.intern("l")),
+                ]),
+                LispExpr::Call(vec![
+                    LispExpr::OpVar(state.intern("quote")),
+                    LispExpr::Call(vec![
+                        LispExpr::Macro(LispMacro::Unquote),
+                        LispExpr::OpVar(state.intern("x")),
+                    ]),
+                ]),
+            ]),
+        ]));
+
+        assert_eq!(
+            expected,
+            parse_lisp_string("`(car ,car ,@l ',x)", &mut state)
+        );
+        assert_eq!(
+            expected,
+            parse_lisp_string(
+                "(quasiquote (car (unquote car) (unquote-splicing l) (quote (unquote x))))",
+                &mut state
+            )
+        );
+    }
+
+    #[test]
+    fn parse_quoted_unquote() {
+        let mut state = State::default();
+        let expected = Ok(LispExpr::Call(vec![
+            LispExpr::Macro(LispMacro::Quote),
+            LispExpr::Call(vec![
+                LispExpr::OpVar(state.intern("unquote-splicing")),
+                LispExpr::OpVar(state.intern("x")),
+            ]),
+        ]));
+
+        assert_eq!(expected, parse_lisp_string("',@x", &mut state));
+    }
+
+    #[test]
+    fn read_datum_as_code() {
+        let mut state = State::default();
+        let datum = LispValue::List(vec![
+            LispValue::Symbol(state.intern("cond")),
+            LispValue::Symbol(state.intern("x")),
+            LispValue::List(vec![
+                LispValue::Symbol(state.intern("quote")),
+                LispValue::Symbol(state.intern("add1")),
+            ]),
+            LispValue::List(vec![
+                LispValue::Symbol(state.intern("quasiquote")),
+                LispValue::List(vec![
+                    LispValue::Symbol(state.intern("add1")),
+                    LispValue::List(vec![
+                        LispValue::Symbol(state.intern("unquote")),
+                        LispValue::Symbol(state.intern("add1")),
+                    ]),
+                ]),
+            ]),
+        ]);
+        let expected = parse_lisp_string("(cond x 'add1 `(add1 ,add1))", &mut state).unwrap();
+
+        assert_eq!(expected, read_datum(datum, &state));
+    }
+
+    #[test]
+    fn parse_quote_without_datum() {
+        assert_eq!(
+            Err(ParseError::UnexpectedEof),
+            parse_lisp_string("'", &mut State::default())
+        );
+        assert_eq!(
+            Err(ParseError::UnexpectedCloseParen(Position {
+                offset: 2,
+                line: 1,
+                column: 3,
+            })),
+            parse_lisp_string("(')", &mut State::default())
+        );
+    }
+
+    #[test]
+    fn parse_quote_spans() {
+        let (_expr, spans) =
+            parse_lisp_string_with_spans(" '(a b)", &mut State::default()).unwrap();
+        let start = Position {
+            offset: 1,
+            line: 1,
+            column: 2,
+        };
+
+        assert_eq!(start, spans.span.start);
+        assert_eq!(7, spans.span.end.offset);
+        assert_eq!(2, spans.children.len());
+        assert_eq!(start, spans.children[0].span.start);
+        assert_eq!(2, spans.children[0].span.end.offset);
+        assert_eq!(2, spans.children[1].span.start.offset);
+        assert_eq!(2, spans.children[1].children.len());
+    }
+
+    #[test]
+    fn parse_big_integer() {
+        let cases = [
+            ("9223372036854775808", "9223372036854775808"),
+            ("-9223372036854775809", "-9223372036854775809"),
+            ("#x1_0000_0000_0000_0000", "18446744073709551616"),
+            (
+                "123_456_789_012_345_678_901_234_567_890",
+                "123456789012345678901234567890",
+            ),
+        ];
+
+        for &(lit, expected) in &cases {
+            assert_eq!(
+                Ok(LispExpr::Value(LispValue::BigInteger(
+                    expected.parse().unwrap()
+                ))),
+                parse_lisp_string(lit, &mut State::default())
+            );
+        }
+    }
+
+    #[test]
+    fn parse_radix_integers() {
+        let cases = [
+            ("#xff", 255),
+            ("#xFF", 255),
+            ("#b101", 5),
+            ("#o17", 15),
+            ("#x7fff_ffff_ffff_ffff", i64::MAX),
+            ("#x-8000_0000_0000_0000", i64::MIN),
+            ("-12", -12),
+            ("+12", 12),
+            ("#b-101", -5),
+            ("1_000_000", 1_000_000),
+            ("#b1111_0000", 240),
+        ];
+
+        for &(lit, expected) in &cases {
+            assert_eq!(
+                Ok(LispExpr::Value(LispValue::Integer(expected))),
+                parse_lisp_string(lit, &mut State::default())
+            );
+        }
+    }
+
+    #[test]
+    fn parse_invalid_radix_integers() {
+        for &lit in &[
+            "#x", "#b102", "#o8", "1_", "1__", "1__2", "#b1__0", "#x_f", "12e3", "-1-", "#x-",
+        ] {
+            assert_eq!(
+                Err(ParseError::InvalidNumberLiteral(
+                    lit.into(),
+                    Position::default()
+                )),
+                parse_lisp_string(lit, &mut State::default())
+            );
+        }
+    }
 }
@@ -1,11 +1,14 @@
-use std::iter::repeat;
-
-use super::{CustomFunc, FinalizedExpr, LispFunc, LispValue, Scope, State};
+use super::{Arity, BuiltIn, CustomFunc, FinalizedExpr, LispFunc, LispValue, Scope, State};
+use num_bigint::{BigInt, Sign};
 
 pub fn print_value(val: &LispValue, state: &State, indent: usize) -> String {
     match *val {
         LispValue::Function(ref func) => print_lisp_func(func, state, indent),
         LispValue::Integer(i) => i.to_string(),
+        LispValue::BigInteger(ref i) => i.to_string(),
+        LispValue::String(ref s) => print_string(s),
+        LispValue::Char(c) => print_char(c),
+        LispValue::Symbol(s) => state.resolve_intern(s).into(),
         LispValue::Boolean(true) => "#t".into(),
         LispValue::Boolean(false) => "#f".into(),
         LispValue::List(ref vec) => {
@@ -22,11 +25,75 @@ pub fn print_value(val: &LispValue, state: &State, indent: usize) -> String {
             result.push(')');
             result
         }
+        LispValue::Record(name, ref fields) => {
+            let mut result = format!("#<{}", state.resolve_intern(name));
+
+            for val in fields {
+                result.push(' ');
+                result.push_str(&print_value(val, state, indent));
+            }
+
+            result.push('>');
+            result
+        }
+    }
+}
+
+/// Prints a string as a literal, escaping quotes, backslashes and
+/// control characters.
+fn print_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\t' => result.push_str("\\t"),
+            '\r' => result.push_str("\\r"),
+            '\0' => result.push_str("\\0"),
+            c if c.is_control() => result.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => result.push(c),
+        }
+    }
+
+    result.push('"');
+    result
+}
+
+/// Prints a character as a literal. Whitespace and control characters
+/// are printed by name or code point.
+fn print_char(c: char) -> String {
+    match c {
+        ' ' => "#\\space".into(),
+        '\n' => "#\\newline".into(),
+        '\t' => "#\\tab".into(),
+        '\r' => "#\\return".into(),
+        '\0' => "#\\nul".into(),
+        c if c.is_whitespace() || c.is_control() => format!("#\\x{:x}", c as u32),
+        c => format!("#\\{}", c),
+    }
+}
+
+/// Prints an integer as a literal in the given radix, which the parser
+/// reads back as the same value. Radixes other than 2, 8 and 16 are
+/// printed in decimal.
+pub fn print_integer<I: Into<BigInt>>(i: I, radix: u32) -> String {
+    let i = i.into();
+    let sign = if i.sign() == Sign::Minus { "-" } else { "" };
+    let magnitude = i.magnitude();
+
+    match radix {
+        2 => format!("#b{}{:b}", sign, magnitude),
+        8 => format!("#o{}{:o}", sign, magnitude),
+        16 => format!("#x{}{:x}", sign, magnitude),
+        _ => i.to_string(),
     }
 }
 
 fn indent_to_string(indent: usize) -> String {
-    repeat(' ').take(indent * 4).collect()
+    " ".repeat(indent * 4)
 }
 
 fn format_list<'a, I: Iterator<Item = &'a FinalizedExpr>>(
@@ -57,11 +124,11 @@ fn format_list<'a, I: Iterator<Item = &'a FinalizedExpr>>(
 }
 
 fn print_custom_func(f: &CustomFunc, state: &State, indent: usize) -> String {
-    print_lambda(f.0.arg_count, Scope(0), &f.0.body, state, indent)
+    print_lambda(f.0.arity, Scope(0), &f.0.body, state, indent)
 }
 
 fn print_lambda(
-    arg_count: usize,
+    arity: Arity,
     scope: Scope,
     body: &FinalizedExpr,
     state: &State,
@@ -69,19 +136,38 @@ fn print_lambda(
 ) -> String {
     let mut result = "(".to_owned();
 
-    for i in 0..arg_count {
+    for i in 0..arity.slots() {
         if i > 0 {
             result.push(' ');
         }
+        if i == arity.required && arity.optional > 0 {
+            result.push_str("#!optional ");
+        }
+        if i == arity.required + arity.optional && arity.rest {
+            result.push_str(". ");
+        }
         result.push_str(&format!("$[{}:{}]", scope.0, i));
     }
 
-    result.push_str(&" -> ");
+    result.push_str(" -> ");
     result + &print_finalized_expr(body, state, indent) + ")"
 }
 
 fn print_lisp_func(f: &LispFunc, state: &State, indent: usize) -> String {
     match *f {
+        // The functions of a record type are printed by the names they
+        // were defined with
+        LispFunc::BuiltIn(BuiltIn::MakeRecord(name, _)) => {
+            format!("make-{}", state.resolve_intern(name))
+        }
+        LispFunc::BuiltIn(BuiltIn::CheckRecord(name)) => {
+            format!("{}?", state.resolve_intern(name))
+        }
+        LispFunc::BuiltIn(BuiltIn::RecordField(name, field, _)) => format!(
+            "{}-{}",
+            state.resolve_intern(name),
+            state.resolve_intern(field)
+        ),
         LispFunc::BuiltIn(name) => format!("{:?}", name),
         LispFunc::Custom(ref c) => print_custom_func(c, state, indent),
     }
@@ -97,13 +183,13 @@ fn print_finalized_expr(expr: &FinalizedExpr, state: &State, indent: usize) -> S
         FinalizedExpr::Cond(ref triple, ..) => {
             let (ref test_expr, ref true_expr, ref false_expr) = **triple;
             // FIXME: this is just too messy - how to better do this?
-            let expr_iter = Some(&*test_expr)
+            let expr_iter = Some(test_expr)
                 .into_iter()
-                .chain(Some(&*true_expr).into_iter().chain(Some(&*false_expr)));
-            format_list(state, indent, "cond", expr_iter)
+                .chain(Some(true_expr).into_iter().chain(Some(false_expr)));
+            format_list(state, indent, "if", expr_iter)
         }
-        FinalizedExpr::Lambda(arg_c, scope, ref body, _) => {
-            print_lambda(arg_c, scope, body, state, indent)
+        FinalizedExpr::Lambda(arity, scope, ref body, ..) => {
+            print_lambda(arity, scope, body, state, indent)
         }
         FinalizedExpr::FunctionCall(ref funk, ref args, _is_tail_call, _is_self_call) => {
             format_list(
@@ -113,5 +199,27 @@ fn print_finalized_expr(expr: &FinalizedExpr, state: &State, indent: usize) -> S
                 args.iter(),
             )
         }
+        FinalizedExpr::Let(_, _, ref values, ref body) => {
+            format_list(state, indent, "let", values.iter().chain(Some(&**body)))
+        }
+        FinalizedExpr::Begin(ref exprs) => format_list(state, indent, "begin", exprs.iter()),
+        FinalizedExpr::TestPart(offset, ref path, ref test) => {
+            format!(
+                "(test-part $[{}] {:?} {:?})",
+                usize::from(offset),
+                path,
+                test
+            )
+        }
+        FinalizedExpr::Part(offset, ref path, _) => {
+            format!("(part $[{}] {:?})", usize::from(offset), path)
+        }
+        FinalizedExpr::NoMatch => "(no-match)".into(),
+        FinalizedExpr::Try(ref handler, ref body) => format_list(
+            state,
+            indent,
+            "try",
+            Some(&**body).into_iter().chain(Some(&**handler)),
+        ),
     }
 }
@@ -0,0 +1,65 @@
+;;; Convenience functions for the REPL, defined in terms of the built-ins.
+;;; Every form is evaluated in order at start-up unless the REPL is started
+;;; with `--no-prelude`.
+
+(define closure (lambda (x) (lambda (y) (+ x y))))
+;; Binary aliases of + and *, kept for older programs
+(define add (lambda (x y) (+ x y)))
+(define mult (lambda (x y) (* x y)))
+
+;; List processing
+(define filter
+  (lambda (f xs)
+    (cond ((null? xs) xs)
+          ((f (car xs)) (cons (car xs) (filter f (cdr xs))))
+          (else (filter f (cdr xs))))))
+(define map (lambda (f xs) (if (null? xs) xs (cons (f (car xs)) (map f (cdr xs))))))
+
+;; Boolean logic
+(define not (lambda (t) (if t #f #t)))
+
+(define append (lambda (l1 l2) (if (null? l2) l1 (cons (car l2) (append l1 (cdr l2))))))
+;; The list of all numbers from start up to and including end
+(define range
+  (lambda (start end)
+    (if (> end start)
+        (cons end (range start (sub1 end)))
+        (list start))))
+;; Quicksort, using the head of the list as pivot
+(define sort
+  (lambda (l)
+    (if (null? l)
+        l
+        (append (cons (car l) (sort (filter (lambda (x) (not (> x (car l)))) (cdr l))))
+                (sort (filter (lambda (x) (> x (car l))) l))))))
+(define zip
+  (lambda (x y)
+    (if (or (null? x) (null? y))
+        (list)
+        (cons (list (car x) (car y)) (zip (cdr x) (cdr y))))))
+;; Applies a binary function to every pair in a zipped list
+(define map2
+  (lambda (f l)
+    (match l
+      (() (list))
+      (((y x) . rest) (cons (f x y) (map2 f rest))))))
+(define reverse (lambda (l) (if (null? l) l (append (list (car l)) (reverse (cdr l))))))
+;; Element at index i, counting from the head of the list
+(define !! (lambda (l i) (if (zero? i) (car l) (!! (cdr l) (sub1 i)))))
+(define foldr (lambda (f xs init) (if (null? xs) init (foldr f (cdr xs) (f init (car xs))))))
+
+;; Church numerals: the number n is represented by a function that applies
+;; its first argument n times to its second.
+(define n0 (lambda (f x) x))
+(define incr (lambda (n f x) (f (n f x))))
+(define itoc (lambda (i) (if (zero? i) n0 (incr (itoc (sub1 i))))))
+(define ctoi (lambda (n) (n add1 0)))
+
+;; Church pairs
+(define cpair (lambda (x y f) (f x y)))
+(define cfst (lambda (x y) x))
+(define csnd (lambda (x y) y))
+
+;; Predecessor of a church numeral, by counting up pairs (n - 1, n)
+(define cup (lambda (p) (cpair (p csnd) (incr (p csnd)))))
+(define decr (lambda (n) ((n cup (cpair n0 n0)) cfst)))